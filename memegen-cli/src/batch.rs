@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use memegen_lib::{Background, FontRegistry, Meme, TemplateLibrary};
+use rayon::prelude::*;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::{load_fonts, render_styled, ExportArgs, StyleOptions};
+
+/// The prefix of CSV columns that contain the text of a template slot, e.g. `slot.top`
+const SLOT_COLUMN_PREFIX: &str = "slot.";
+
+/**
+    Renders a meme for every row of a CSV or JSON Lines file.
+
+    CSV files have a header with the columns image, top, bottom, template and output,
+    the text of template slots is in columns named `slot.<name>`.
+    In JSON Lines files every line is an object with the same keys and the slots in a `slots` object.
+    A row either has an image or a template, top and bottom text of template rows fill the slots of these names.
+*/
+#[derive(StructOpt)]
+pub struct BatchOptions {
+    /// The rows, a .csv file or a .jsonl file
+    #[structopt(parse(from_os_str))]
+    rows: PathBuf,
+
+    /// The directory of the templates the rows can use
+    #[structopt(long, parse(from_os_str))]
+    templates: Option<PathBuf>,
+
+    /// The directory the memes are written to
+    #[structopt(short, long, default_value = ".", parse(from_os_str))]
+    output_dir: PathBuf,
+
+    /// The style of the captions on images, templates have their own styles
+    #[structopt(flatten)]
+    style: StyleOptions,
+
+    /// The format of every meme is chosen by the extension of its output
+    #[structopt(flatten)]
+    export: ExportArgs,
+}
+
+impl BatchOptions {
+    /**
+        Checks the options that the argument parser cannot check on its own
+    */
+    pub fn validate(&self) -> Result<(), String> {
+        self.style.validate()
+    }
+}
+
+/**
+    A single meme of the batch
+*/
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BatchRow {
+    /// The background image, relative paths are resolved against the directory of the rows file
+    image: Option<PathBuf>,
+    top: Option<String>,
+    bottom: Option<String>,
+    /// The id of a template
+    template: Option<String>,
+    slots: HashMap<String, String>,
+    /// The name of the written meme, by default it is numbered after the row
+    output: Option<String>,
+}
+
+/// A row with its number, or the reason it could not be parsed
+type NumberedRow = (usize, Result<BatchRow, String>);
+
+/// A row with its number and the path it is written to, or the reason it cannot be rendered
+type PlannedRow = (usize, Result<(BatchRow, PathBuf), String>);
+
+/**
+    Everything the rows are rendered with
+*/
+struct BatchContext<'a> {
+    options: &'a BatchOptions,
+    base_dir: PathBuf,
+    templates: TemplateLibrary,
+    fonts: FontRegistry,
+    font_family: String,
+}
+
+/**
+    Renders all rows in parallel, failing rows are reported and skipped.
+    Returns the number of failed rows, errors that affect all rows are returned right away.
+*/
+pub fn run_batch(options: &BatchOptions) -> Result<usize, String> {
+    let rows = read_rows(&options.rows)?;
+    let (fonts, font_family) = load_fonts(&options.style.font)
+        .map_err(|err| format!("could not load font {}: {}", options.style.font, err))?;
+    let mut templates = TemplateLibrary::new();
+    if let Some(dir) = &options.templates {
+        if templates.load_dir(dir) == 0 {
+            return Err(format!("there are no templates in {}", dir.display()));
+        }
+    }
+    fs::create_dir_all(&options.output_dir)
+        .map_err(|err| format!("could not create {}: {}", options.output_dir.display(), err))?;
+
+    let context = BatchContext {
+        options,
+        base_dir: options
+            .rows
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        templates,
+        fonts,
+        font_family,
+    };
+    let rows = plan_outputs(rows, &options.output_dir);
+    // the rows are rendered on all cores and reported in their order afterwards
+    let results: Vec<(usize, Result<PathBuf, String>)> = rows
+        .par_iter()
+        .map(|(number, row)| {
+            let result = row
+                .as_ref()
+                .map_err(String::clone)
+                .and_then(|(row, path)| render_row(row, path, &context).map(|()| path.clone()));
+            (*number, result)
+        })
+        .collect();
+    let mut failed = 0;
+    for (number, result) in results {
+        match result {
+            Ok(path) => println!("row {}: wrote {}", number, path.display()),
+            Err(err) => {
+                eprintln!("row {}: {}", number, err);
+                failed += 1;
+            }
+        }
+    }
+    println!("rendered {} of {} memes", rows.len() - failed, rows.len());
+    Ok(failed)
+}
+
+/**
+    Reads the rows of the file with their numbers, which start at 1 for the first row of data.
+    Rows that cannot be parsed are kept as errors, so they are reported like rendering errors.
+*/
+fn read_rows(path: &Path) -> Result<Vec<NumberedRow>, String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    let data = fs::read_to_string(path)
+        .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+    match extension.as_deref() {
+        Some("csv") => read_csv_rows(&data),
+        Some("jsonl") | Some("ndjson") => Ok(data
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| {
+                let row = serde_json::from_str(line).map_err(|err| err.to_string());
+                (idx + 1, row)
+            })
+            .collect()),
+        _ => Err(format!(
+            "unknown file extension of {}, expected .csv or .jsonl",
+            path.display()
+        )),
+    }
+}
+
+fn read_csv_rows(data: &str) -> Result<Vec<NumberedRow>, String> {
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|err| format!("invalid CSV header: {}", err))?
+        .clone();
+    let known = ["image", "top", "bottom", "template", "output"];
+    if let Some(unknown) = headers
+        .iter()
+        .find(|header| !known.contains(header) && !header.starts_with(SLOT_COLUMN_PREFIX))
+    {
+        return Err(format!("unknown column {}", unknown));
+    }
+
+    let rows = reader
+        .records()
+        .enumerate()
+        .map(|(idx, record)| {
+            let row = record.map_err(|err| err.to_string()).map(|record| {
+                let mut row = BatchRow::default();
+                // empty cells are treated like missing ones
+                for (header, value) in headers.iter().zip(record.iter()) {
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let value = value.to_string();
+                    match header {
+                        "image" => row.image = Some(PathBuf::from(value)),
+                        "top" => row.top = Some(value),
+                        "bottom" => row.bottom = Some(value),
+                        "template" => row.template = Some(value),
+                        "output" => row.output = Some(value),
+                        slot => {
+                            let slot = slot.trim_start_matches(SLOT_COLUMN_PREFIX).to_string();
+                            row.slots.insert(slot, value);
+                        }
+                    }
+                }
+                row
+            });
+            (idx + 1, row)
+        })
+        .collect();
+    Ok(rows)
+}
+
+/**
+    Finds the path every row is written to in the output directory.
+    Rows whose output would be outside of the directory or is written by an earlier row as well fail,
+    so no meme is overwritten by another one while they are rendered in parallel.
+*/
+fn plan_outputs(rows: Vec<NumberedRow>, output_dir: &Path) -> Vec<PlannedRow> {
+    let mut outputs: HashMap<PathBuf, usize> = HashMap::new();
+    rows.into_iter()
+        .map(|(number, row)| {
+            let planned = row.and_then(|row| {
+                let output = row
+                    .output
+                    .clone()
+                    .unwrap_or_else(|| format!("meme-{:04}.png", number));
+                let inside = Path::new(&output)
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+                if output.is_empty() || !inside {
+                    return Err(format!(
+                        "the output {} is not a file name inside of the output directory",
+                        output
+                    ));
+                }
+                let path = output_dir.join(&output);
+                if let Some(first) = outputs.get(&path) {
+                    return Err(format!("row {} is written to {} as well", first, output));
+                }
+                outputs.insert(path.clone(), number);
+                Ok((row, path))
+            });
+            (number, planned)
+        })
+        .collect()
+}
+
+/**
+    Renders the meme of a row and writes it to the path
+*/
+fn render_row(row: &BatchRow, path: &Path, context: &BatchContext) -> Result<(), String> {
+    let (image, exif) = match (&row.template, &row.image) {
+        (Some(_), Some(_)) => return Err("a row has either an image or a template".to_string()),
+        (Some(id), None) => {
+            let template = context
+                .templates
+                .get(id)
+                .ok_or_else(|| format!("unknown template {}", id))?;
+            let mut captions = row.slots.clone();
+            for (slot, text) in [("top", &row.top), ("bottom", &row.bottom)].iter() {
+                if let Some(text) = text {
+                    captions
+                        .entry(slot.to_string())
+                        .or_insert_with(|| text.clone());
+                }
+            }
+            let image = template
+                .render(&captions, &context.fonts)
+                .map_err(|err| err.to_string())?;
+            (image, None)
+        }
+        (None, Some(image)) => {
+            if !row.slots.is_empty() {
+                return Err("slots can only be filled with a template".to_string());
+            }
+            let texts_top: Vec<&str> = row.top.iter().map(String::as_str).collect();
+            let texts_bottom: Vec<&str> = row.bottom.iter().map(String::as_str).collect();
+            if texts_top.is_empty() && texts_bottom.is_empty() {
+                return Err("there is no text".to_string());
+            }
+            let meme = Meme::top_bottom(
+                Background::File {
+                    path: context.base_dir.join(image),
+                },
+                &texts_top,
+                &texts_bottom,
+            );
+            let exif = context.options.export.load_exif(&meme)?;
+            let image = render_styled(
+                meme,
+                &context.options.style,
+                &context.font_family,
+                &context.fonts,
+            )?;
+            (image, exif)
+        }
+        (None, None) => return Err("a row needs an image or a template".to_string()),
+    };
+
+    context
+        .options
+        .export
+        .export_options(path, None)?
+        .save(&image, exif.as_deref(), path)
+        .map_err(|err| format!("could not write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE_DIR: &str = "../memegen-lib/res/test/templates";
+
+    fn context(options: &BatchOptions) -> BatchContext<'_> {
+        let mut templates = TemplateLibrary::new();
+        templates.load_dir(TEMPLATE_DIR);
+        let (fonts, font_family) = load_fonts("Impact").unwrap();
+        BatchContext {
+            options,
+            base_dir: PathBuf::from("../memegen-lib/res/images"),
+            templates,
+            fonts,
+            font_family,
+        }
+    }
+
+    fn row(output: Option<&str>) -> BatchRow {
+        BatchRow {
+            output: output.map(str::to_string),
+            ..BatchRow::default()
+        }
+    }
+
+    #[test]
+    fn test_read_csv_rows() {
+        let data = "image,top,bottom,template,output,slot.top,slot.bottom\n\
+                    puffin.jpg,Top,,,first.jpg,,\n\
+                    ,,Bottom,two_panels,,Left,\n\
+                    too,few\n";
+        let rows = read_csv_rows(data).unwrap();
+        assert_eq!(3, rows.len());
+
+        let (number, first) = &rows[0];
+        let first = first.as_ref().unwrap();
+        assert_eq!(1, *number);
+        assert_eq!(Some(PathBuf::from("puffin.jpg")), first.image);
+        assert_eq!(Some("Top".to_string()), first.top);
+        // empty cells are missing values, not empty text
+        assert_eq!(None, first.bottom);
+        assert_eq!(None, first.template);
+        assert_eq!(Some("first.jpg".to_string()), first.output);
+        assert!(first.slots.is_empty());
+
+        let second = rows[1].1.as_ref().unwrap();
+        assert_eq!(None, second.image);
+        assert_eq!(Some("two_panels".to_string()), second.template);
+        assert_eq!(Some(&"Left".to_string()), second.slots.get("top"));
+        assert_eq!(1, second.slots.len());
+
+        // a broken row is an error of its own, the other rows are still read
+        assert_eq!(3, rows[2].0);
+        assert!(rows[2].1.is_err());
+
+        assert!(read_csv_rows("image,middle\npuffin.jpg,x\n")
+            .unwrap_err()
+            .contains("middle"));
+    }
+
+    #[test]
+    fn test_plan_outputs() {
+        let rows = vec![
+            (1, Ok(row(None))),
+            (2, Ok(row(Some("meme.jpg")))),
+            (3, Ok(row(Some("meme.jpg")))),
+            (4, Ok(row(Some("../meme.png")))),
+            (5, Ok(row(Some("/tmp/meme.png")))),
+            (6, Err("invalid row".to_string())),
+            (7, Ok(row(Some("")))),
+        ];
+        let planned = plan_outputs(rows, Path::new("out"));
+        let paths: Vec<_> = planned
+            .iter()
+            .map(|(_, row)| {
+                row.as_ref()
+                    .map(|(_, path)| path.clone())
+                    .map_err(String::clone)
+            })
+            .collect();
+        assert_eq!(Ok(PathBuf::from("out/meme-0001.png")), paths[0]);
+        assert_eq!(Ok(PathBuf::from("out/meme.jpg")), paths[1]);
+        // the first row keeps its output, later ones with the same output fail
+        assert!(paths[2].as_ref().unwrap_err().contains("row 2"));
+        // rows cannot write outside of the output directory
+        assert!(paths[3].as_ref().unwrap_err().contains("../meme.png"));
+        assert!(paths[4].as_ref().unwrap_err().contains("/tmp/meme.png"));
+        assert_eq!(Err("invalid row".to_string()), paths[5]);
+        assert!(paths[6].is_err());
+    }
+
+    #[test]
+    fn test_render_row() {
+        let options = BatchOptions::from_iter_safe(&["batch", "rows.csv"]).unwrap();
+        let context = context(&options);
+        fs::create_dir_all("test_output/batch").unwrap();
+        let path = |name: &str| PathBuf::from("test_output/batch").join(name);
+
+        let image_row = BatchRow {
+            image: Some(PathBuf::from("puffin.jpg")),
+            top: Some("Top".to_string()),
+            ..BatchRow::default()
+        };
+        render_row(&image_row, &path("image.jpg"), &context).unwrap();
+        assert!(path("image.jpg").is_file());
+
+        // top and bottom text fill the slots of these names unless the slots are given
+        let mut slots = HashMap::new();
+        slots.insert("top".to_string(), "Left".to_string());
+        let template_row = BatchRow {
+            template: Some("two_panels".to_string()),
+            top: Some("ignored".to_string()),
+            bottom: Some("Bottom".to_string()),
+            slots: slots.clone(),
+            ..BatchRow::default()
+        };
+        render_row(&template_row, &path("template.png"), &context).unwrap();
+        assert!(path("template.png").is_file());
+
+        let failing = |row: &BatchRow| render_row(row, &path("failed.png"), &context).unwrap_err();
+        let both = BatchRow {
+            image: Some(PathBuf::from("puffin.jpg")),
+            ..template_row
+        };
+        assert!(failing(&both).contains("either"));
+        let no_text = BatchRow {
+            top: None,
+            ..image_row
+        };
+        assert!(failing(&no_text).contains("no text"));
+        let slots_without_template = BatchRow {
+            top: Some("Top".to_string()),
+            slots,
+            ..no_text
+        };
+        assert!(failing(&slots_without_template).contains("template"));
+        let unknown_template = BatchRow {
+            template: Some("nope".to_string()),
+            ..BatchRow::default()
+        };
+        assert!(failing(&unknown_template).contains("nope"));
+        let missing_image = BatchRow {
+            image: Some(PathBuf::from("missing.jpg")),
+            top: Some("Top".to_string()),
+            ..BatchRow::default()
+        };
+        assert!(failing(&missing_image).contains("missing.jpg"));
+        assert!(failing(&BatchRow::default()).contains("image or a template"));
+        assert!(!path("failed.png").exists());
+    }
+}
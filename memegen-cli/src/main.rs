@@ -1,20 +1,552 @@
 extern crate memegen_lib;
-use image::{DynamicImage, Rgba, ImageBuffer,GenericImage};
-use rusttype::{point, Font, Scale, PositionedGlyph, VMetrics};
 
-use memegen_lib::{Line, Orientation, draw_lines_top_bottom};
-use memegen_lib::draw_line;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use image::RgbaImage;
+use memegen_lib::{
+    animate_lines, draw_layers, parse_colour, Animation, Background, Effect, EffectSettings,
+    ExportFormat, ExportOptions, FontRegistry, Line, Meme, MemegenError, Placement, PngCompression,
+    TextStyle,
+};
+use structopt::clap::{AppSettings, ErrorKind};
+use structopt::StructOpt;
+
+mod batch;
+
+use crate::batch::{run_batch, BatchOptions};
+
+/// The exit code for failures while reading, rendering or writing the meme
+const EXIT_FAILURE: i32 = 1;
+/// The exit code for invalid arguments
+const EXIT_USAGE: i32 = 2;
+
+/**
+    Why the command failed, which decides its exit code
+*/
+#[derive(Debug, PartialEq)]
+enum CliError {
+    /// The arguments are invalid, nothing was read or rendered
+    Usage(String),
+    /// Reading, rendering or writing the meme failed
+    Failure(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Failure(_) => EXIT_FAILURE,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::Usage(message) | CliError::Failure(message) => message,
+        }
+    }
+}
+
+/**
+    Draws classic top and bottom captions onto an image
+*/
+#[derive(StructOpt)]
+#[structopt(name = "memegen", setting = AppSettings::ArgsNegateSubcommands)]
+struct Options {
+    /// The background image, required unless a subcommand is used
+    #[structopt(parse(from_os_str))]
+    input: Option<PathBuf>,
+
+    /// A row of text at the top of the image, can be repeated
+    #[structopt(short, long, number_of_values = 1)]
+    top: Vec<String>,
+
+    /// A row of text at the bottom of the image, can be repeated
+    #[structopt(short, long, number_of_values = 1)]
+    bottom: Vec<String>,
+
+    #[structopt(flatten)]
+    style: StyleOptions,
+
+    /// The file the meme is written to, GIFs keep all frames of an animated GIF background
+    #[structopt(short, long, default_value = "meme.png", parse(from_os_str))]
+    output: PathBuf,
+
+    /// The image format of the output, by default it is chosen by the file extension
+    #[structopt(long, possible_values = &["png", "apng", "jpeg", "jpg", "webp", "gif", "bmp"])]
+    format: Option<String>,
+
+    #[structopt(flatten)]
+    export: ExportArgs,
+
+    /// Animates the captions into a GIF or an animated PNG
+    #[structopt(long, possible_values = &["typewriter", "fade", "bounce"])]
+    effect: Option<Effect>,
+
+    /// The frame rate of the caption animation
+    #[structopt(long, default_value = "15")]
+    fps: f32,
+
+    /// The length of the caption animation in seconds
+    #[structopt(long, default_value = "3")]
+    duration: f32,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Renders a meme for every row of a CSV or JSON Lines file
+    Batch(BatchOptions),
+}
+
+/**
+    The looks of the captions
+*/
+#[derive(StructOpt)]
+pub struct StyleOptions {
+    /// The font family, a baked or installed font, or the path of a font file
+    #[structopt(short, long, default_value = "Impact")]
+    font: String,
+
+    /// The largest font size in pixels, long text is shrunk to fit [default: a tenth of the image height]
+    #[structopt(short, long)]
+    size: Option<f32>,
+
+    /// The colour of the text, as hex code like #ffffff or a colour name
+    #[structopt(long, default_value = "white", parse(try_from_str = parse_colour_arg))]
+    fill: (u8, u8, u8),
+
+    /// The colour of the outline around the text
+    #[structopt(long, default_value = "black", parse(try_from_str = parse_colour_arg))]
+    outline: (u8, u8, u8),
+}
+
+impl StyleOptions {
+    /**
+        Checks the options that the argument parser cannot check on its own
+    */
+    pub fn validate(&self) -> Result<(), String> {
+        match self.size {
+            Some(size) if !(size.is_finite() && size > 0.0) => Err(format!(
+                "the font size must be a positive number of pixels, not {}",
+                size
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+        The style of the captions on an image of the given height
+    */
+    pub fn text_style(&self, font_family: &str, image_height: u32) -> TextStyle {
+        TextStyle {
+            font_family: font_family.to_string(),
+            size: self
+                .size
+                .map(|size| size / image_height as f32)
+                .unwrap_or_else(|| TextStyle::default().size),
+            fill: self.fill,
+            outline: self.outline,
+            ..TextStyle::default()
+        }
+    }
+}
+
+/**
+    How the memes are written
+*/
+#[derive(StructOpt)]
+pub struct ExportArgs {
+    /// The quality of JPEG images, from 1 to 100
+    #[structopt(long, default_value = "90")]
+    quality: u8,
+
+    /// How hard PNG images are compressed
+    #[structopt(long, default_value = "default", possible_values = &["fast", "default", "best"])]
+    compression: PngCompression,
+
+    /// Puts transparent areas on white instead of keeping them transparent
+    #[structopt(long)]
+    no_alpha: bool,
+
+    /// Keeps the EXIF data of the background image in PNG, JPEG and WebP images
+    #[structopt(long)]
+    keep_exif: bool,
+}
+
+impl ExportArgs {
+    /**
+        The export options of a file, the format is chosen by its extension unless it is given.
+        The settings of the format are checked as well.
+    */
+    pub fn export_options(
+        &self,
+        path: &Path,
+        format: Option<&str>,
+    ) -> Result<ExportOptions, String> {
+        let format = match format {
+            // an animated PNG without an effect is a still PNG
+            Some("apng") => ExportFormat::Png,
+            Some(format) => format.parse()?,
+            None => ExportFormat::from_path(path).ok_or_else(|| {
+                format!(
+                    "the extension of {} is not png, jpg, webp, gif or bmp",
+                    path.display()
+                )
+            })?,
+        };
+        let options = ExportOptions {
+            format,
+            jpeg_quality: self.quality,
+            png_compression: self.compression,
+            preserve_alpha: !self.no_alpha,
+            keep_exif: self.keep_exif,
+        };
+        options.validate().map_err(|err| err.to_string())?;
+        Ok(options)
+    }
+
+    /**
+        The EXIF data of the background of the meme, if it is kept
+    */
+    pub fn load_exif(&self, meme: &Meme) -> Result<Option<Vec<u8>>, String> {
+        if !self.keep_exif {
+            return Ok(None);
+        }
+        meme.load_exif().map_err(|err| read_error(meme, err))
+    }
+}
+
+fn parse_colour_arg(text: &str) -> Result<(u8, u8, u8), String> {
+    parse_colour(text).ok_or_else(|| format!("not a colour: {}", text))
+}
 
 fn main() {
-    let texts_top = vec!["One does not simply".to_string()];
-    let texts_bottom = vec!["create a meme generator".to_string(),];
+    let options = match Options::from_args_safe() {
+        Ok(options) => options,
+        Err(err) => match err.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => err.exit(),
+            _ => {
+                eprintln!("{}", err.message);
+                process::exit(EXIT_USAGE);
+            }
+        },
+    };
+
+    if let Some(Command::Batch(batch)) = &options.command {
+        if let Err(err) = batch.validate() {
+            eprintln!("error: {}", err);
+            process::exit(EXIT_USAGE);
+        }
+        match run_batch(batch) {
+            Ok(0) => {}
+            // the failed rows were already reported
+            Ok(_) => process::exit(EXIT_FAILURE),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                process::exit(EXIT_FAILURE);
+            }
+        }
+        return;
+    }
+
+    let result = validate(&options).and_then(|()| run(&options).map_err(CliError::Failure));
+    if let Err(err) = result {
+        eprintln!("error: {}", err.message());
+        process::exit(err.exit_code());
+    }
+}
+
+/**
+    Checks the arguments before anything is read or rendered
+*/
+fn validate(options: &Options) -> Result<(), CliError> {
+    if options.input.is_none() {
+        return Err(CliError::Usage(
+            "there is no background image, see --help for the usage".to_string(),
+        ));
+    }
+    if options.top.is_empty() && options.bottom.is_empty() {
+        return Err(CliError::Usage(
+            "there is no text, use --top or --bottom to add some".to_string(),
+        ));
+    }
+    options.style.validate().map_err(CliError::Usage)?;
+    if options.effect.is_some() {
+        if animation_format(options).is_none() {
+            return Err(CliError::Usage(format!(
+                "caption animations are written as GIF or APNG, not as {}",
+                options.output.display()
+            )));
+        }
+    } else if !is_gif_output(&options.output, options.format.as_deref()) {
+        options
+            .export
+            .export_options(&options.output, options.format.as_deref())
+            .map_err(CliError::Usage)?;
+    }
+    Ok(())
+}
+
+/**
+    Renders the meme, errors are described with the file they occurred on
+*/
+fn run(options: &Options) -> Result<(), String> {
+    let input = options.input.clone().unwrap_or_default();
+    let (fonts, font_family) = load_fonts(&options.style.font)
+        .map_err(|err| format!("could not load font {}: {}", options.style.font, err))?;
+
+    let texts_top: Vec<&str> = options.top.iter().map(String::as_str).collect();
+    let texts_bottom: Vec<&str> = options.bottom.iter().map(String::as_str).collect();
+    let meme = Meme::top_bottom(Background::File { path: input }, &texts_top, &texts_bottom);
+    let write_error =
+        |err: MemegenError| format!("could not write {}: {}", options.output.display(), err);
+    if let Some(effect) = options.effect {
+        let settings = EffectSettings {
+            effect,
+            fps: options.fps,
+            // negative and invalid durations become 0 and are rejected with the settings
+            duration: Duration::from_millis((options.duration * 1000.0) as u64),
+        };
+        let animation =
+            render_styled_effect(meme, &options.style, &font_family, &fonts, &settings)?;
+        return match animation_format(options) {
+            Some("apng") => animation.save_apng(&options.output),
+            _ => animation.save_gif(&options.output),
+        }
+        .map_err(write_error);
+    }
+    if is_gif_output(&options.output, options.format.as_deref()) {
+        let animation = render_styled_animation(meme, &options.style, &font_family, &fonts)?;
+        return animation.save_gif(&options.output).map_err(write_error);
+    }
+    let export = options
+        .export
+        .export_options(&options.output, options.format.as_deref())?;
+    let exif = options.export.load_exif(&meme)?;
+    let image = render_styled(meme, &options.style, &font_family, &fonts)?;
+
+    export
+        .save(&image, exif.as_deref(), &options.output)
+        .map_err(write_error)
+}
+
+/**
+    Whether the output is a GIF, by the format or else by the file extension
+*/
+fn is_gif_output(path: &Path, format: Option<&str>) -> bool {
+    match format {
+        Some(format) => format == "gif",
+        None => path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.eq_ignore_ascii_case("gif"))
+            .unwrap_or(false),
+    }
+}
+
+/**
+    The format of a caption animation, "gif" or "apng", by the format or else by the file extension
+*/
+fn animation_format(options: &Options) -> Option<&'static str> {
+    let format = match options.format.as_deref() {
+        Some(format) => format.to_string(),
+        None => options
+            .output
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase(),
+    };
+    match format.as_str() {
+        "gif" => Some("gif"),
+        "apng" | "png" => Some("apng"),
+        _ => None,
+    }
+}
 
-    let mut image = image::open("simply.jpg").unwrap().to_rgba();
-    draw_lines_top_bottom(texts_top,texts_bottom, &mut image);
+/**
+    Renders the meme with all captions in the style of the options
+*/
+pub fn render_styled(
+    mut meme: Meme,
+    style: &StyleOptions,
+    font_family: &str,
+    fonts: &FontRegistry,
+) -> Result<RgbaImage, String> {
+    let mut image = meme
+        .load_background()
+        .map_err(|err| read_error(&meme, err))?;
+    apply_style(&mut meme, style, font_family, image.height());
+    draw_layers(&meme.layers, fonts, &mut image).map_err(|err| err.to_string())?;
+    Ok(image)
+}
+
+/**
+    Renders the meme onto every frame of an animated GIF background, see `render_styled`
+*/
+pub fn render_styled_animation(
+    mut meme: Meme,
+    style: &StyleOptions,
+    font_family: &str,
+    fonts: &FontRegistry,
+) -> Result<Animation, String> {
+    let mut animation = meme
+        .load_animation()
+        .map_err(|err| read_error(&meme, err))?;
+    apply_style(&mut meme, style, font_family, animation.dimensions().1);
+    animation
+        .draw_layers(&meme.layers, fonts)
+        .map_err(|err| err.to_string())?;
+    Ok(animation)
+}
+
+/**
+    Animates the captions of the meme on its still background, see `render_styled`
+*/
+pub fn render_styled_effect(
+    mut meme: Meme,
+    style: &StyleOptions,
+    font_family: &str,
+    fonts: &FontRegistry,
+    settings: &EffectSettings,
+) -> Result<Animation, String> {
+    let image = meme
+        .load_background()
+        .map_err(|err| read_error(&meme, err))?;
+    apply_style(&mut meme, style, font_family, image.height());
+    let mut lines = Vec::with_capacity(meme.layers.len());
+    for layer in meme.layers.iter() {
+        if let Placement::Anchor { orientation } = &layer.placement {
+            lines.push(Line {
+                text: layer.text.clone(),
+                orientation: orientation.clone(),
+                alignment: layer.style.alignment.clone(),
+                fontspec: layer
+                    .style
+                    .fontspec(fonts, image.height())
+                    .map_err(|err| err.to_string())?,
+                max_rows: layer.style.max_rows,
+                ..Line::default()
+            });
+        }
+    }
+    animate_lines(&lines, &image, settings).map_err(|err| err.to_string())
+}
+
+fn apply_style(meme: &mut Meme, style: &StyleOptions, font_family: &str, image_height: u32) {
+    let text_style = style.text_style(font_family, image_height);
+    for layer in meme.layers.iter_mut() {
+        layer.style = text_style.clone();
+    }
+}
+
+fn read_error(meme: &Meme, err: MemegenError) -> String {
+    match &meme.background {
+        Background::File { path } => format!("could not read {}: {}", path.display(), err),
+        _ => err.to_string(),
+    }
+}
 
-    image.save("test_output/test.jpg").unwrap();
+/**
+    The baked fonts, and the font file or the installed fonts if the font is not baked.
+    Returns the fonts and the family to draw the text with.
+*/
+pub fn load_fonts(font: &str) -> Result<(FontRegistry, String), MemegenError> {
+    let mut fonts = FontRegistry::with_baked_fonts();
+    if Path::new(font).is_file() {
+        let baked = fonts.faces().len();
+        fonts.load_file(font)?;
+        // the first face of the file, which is the only one for anything but a collection
+        let family = fonts.faces()[baked].family.clone();
+        return Ok((fonts, family));
+    }
+    if fonts.find(font, None).is_none() {
+        fonts.load_system_fonts();
+    }
+    Ok((fonts, font.to_string()))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    fn options(args: &[&str]) -> Options {
+        let args = ["memegen"].iter().chain(args.iter());
+        Options::from_iter_safe(args).unwrap()
+    }
 
+    fn usage_error(args: &[&str]) -> String {
+        match validate(&options(args)) {
+            Err(CliError::Usage(message)) => message,
+            result => panic!("{:?} are no usage error: {:?}", args, result),
+        }
+    }
 
+    #[test]
+    fn test_export_options() {
+        let export = options(&["in.png", "--quality", "70", "--no-alpha"]).export;
+        let jpeg = export.export_options(Path::new("meme.JPG"), None).unwrap();
+        assert_eq!(ExportFormat::Jpeg, jpeg.format);
+        assert_eq!(70, jpeg.jpeg_quality);
+        assert!(!jpeg.preserve_alpha);
+
+        // the format wins over the extension, animated PNGs without effect are still PNGs
+        let png = export.export_options(Path::new("meme.jpg"), Some("apng"));
+        assert_eq!(ExportFormat::Png, png.unwrap().format);
+        let webp = export.export_options(Path::new("meme"), Some("webp"));
+        assert_eq!(ExportFormat::WebP, webp.unwrap().format);
+
+        let tiff = export.export_options(Path::new("meme.tiff"), None);
+        assert!(tiff.unwrap_err().contains("meme.tiff"));
+        assert!(export.export_options(Path::new("meme"), None).is_err());
+
+        // the quality only matters for JPEG images
+        let export = options(&["in.png", "--quality", "0"]).export;
+        assert!(export.export_options(Path::new("meme.png"), None).is_ok());
+        assert!(export.export_options(Path::new("meme.jpg"), None).is_err());
+    }
+
+    #[test]
+    fn test_animation_format() {
+        assert_eq!(Some("gif"), animation_format(&options(&["in.png", "-o", "a.GIF"])));
+        assert_eq!(Some("apng"), animation_format(&options(&["in.png", "-o", "a.png"])));
+        let apng = options(&["in.png", "-o", "a.gif", "--format", "apng"]);
+        assert_eq!(Some("apng"), animation_format(&apng));
+        assert_eq!(None, animation_format(&options(&["in.png", "-o", "a.jpg"])));
+        assert_eq!(None, animation_format(&options(&["in.png", "-o", "a"])));
+    }
+
+    #[test]
+    fn test_is_gif_output() {
+        assert!(is_gif_output(Path::new("meme.gif"), None));
+        assert!(is_gif_output(Path::new("meme.GIF"), None));
+        assert!(is_gif_output(Path::new("meme.png"), Some("gif")));
+        assert!(!is_gif_output(Path::new("meme.gif"), Some("png")));
+        assert!(!is_gif_output(Path::new("gif"), None));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(EXIT_USAGE, CliError::Usage(String::new()).exit_code());
+        assert_eq!(EXIT_FAILURE, CliError::Failure(String::new()).exit_code());
+
+        assert!(usage_error(&["-t", "hi"]).contains("background"));
+        assert!(usage_error(&["in.png"]).contains("text"));
+        assert!(usage_error(&["in.png", "-t", "hi", "-o", "x.tiff"]).contains("x.tiff"));
+        assert!(usage_error(&["in.png", "-t", "hi", "--size", "0"]).contains("font size"));
+        let quality = ["in.png", "-t", "hi", "-o", "out.jpg", "--quality", "0"];
+        assert!(usage_error(&quality).contains("JPEG quality"));
+        let effect = ["in.png", "-t", "hi", "--effect", "fade", "-o", "x.jpg"];
+        assert!(usage_error(&effect).contains("GIF or APNG"));
+
+        // the arguments are valid, so a missing background is a failure of the rendering
+        let valid = options(&["does-not-exist.png", "-t", "hi", "-o", "x.gif"]);
+        assert_eq!(Ok(()), validate(&valid));
+        let valid = options(&["in.png", "-t", "hi", "-o", "x.tiff", "--format", "png"]);
+        assert_eq!(Ok(()), validate(&valid));
+    }
+}
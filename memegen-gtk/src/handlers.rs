@@ -1,10 +1,14 @@
 use gtk::prelude::*;
 
 use gdk::{EventScroll,EventButton,EventMotion};
-use gtk::{ Fixed, Image, Window };
+use gtk::{ FileChooserAction, Fixed, Image, Window };
 
 use gdk::ScrollDirection;
-use memegen_lib::{draw_line_at, Line, Orientation, PreviewService};
+use memegen_lib::{
+    load_project, place_line, render_animation, save_project, Background, ExportFormat, ExportOptions, FontRegistry,
+    Line, Meme, MemegenError, Placement, TextLayer, TextStyle,
+};
+use image::RgbaImage;
 use std::cell::RefMut;
 
 use std::cmp::max;
@@ -23,6 +27,21 @@ pub struct TextArea {
     pub click_offset_y: i32,
     pub position_in_text_field: i32,
     pub line: Line,
+    /// The layer of a project the text area was opened from, new text areas have none
+    pub opened: Option<OpenedLayer>,
+}
+
+/**
+    A layer of an opened project and where its text area was shown.
+    The layer keeps everything the text areas cannot show, like anchors, boxes and visibility,
+    only what is changed in the editor is replaced when the project is saved again.
+*/
+#[derive(Clone)]
+pub struct OpenedLayer {
+    pub layer: TextLayer,
+    pub pos_x: i32,
+    pub pos_y: i32,
+    pub scale: f32,
 }
 
 /**
@@ -188,7 +207,13 @@ pub fn handle_new_text(
         // reuse the text areas, if they are contained in the tuple
         let area = match area {
             Some(mut ta) => {
-                let img_data = memegen_lib::generate_font_rendering_with_transparency(&mut ta.line);
+                let img_data = match memegen_lib::generate_font_rendering_with_transparency(&mut ta.line) {
+                    Ok(img_data) => img_data,
+                    Err(err) => {
+                        eprintln!("Could not render text area: {}", err);
+                        continue
+                    }
+                };
                 let (img,background_dimensions) = add_image_at_target_position(&fixed_container, img_data,(ta.pos_x,ta.pos_y));
                 ta.image = img;
                 ta.size_x=  background_dimensions.0 as i32;
@@ -197,7 +222,13 @@ pub fn handle_new_text(
             }
             None => {
                 let mut line = Line{text:img_text.to_string(),..Line::default()};
-                let img_data = memegen_lib::generate_font_rendering_with_transparency(&mut line);
+                let img_data = match memegen_lib::generate_font_rendering_with_transparency(&mut line) {
+                    Ok(img_data) => img_data,
+                    Err(err) => {
+                        eprintln!("Could not render text area: {}", err);
+                        continue
+                    }
+                };
                 let (img,background_dimensions) = add_image_at_target_position(&fixed_container, img_data,(10,10));
                 TextArea{
                     pos_x: 10,
@@ -209,6 +240,7 @@ pub fn handle_new_text(
                     click_offset_y: 0,
                     line: line,
                     position_in_text_field: 0,
+                    opened: None,
                 }
             }
         };
@@ -248,9 +280,18 @@ pub fn handle_scroll_event(
                 element.line.fontspec.scale.x += scale_modifier as f32;
                 element.line.fontspec.scale.y += scale_modifier as f32;
 
-                fixed_container.remove(&element.image);
+                // keep the old rendering, if the new size cannot be rendered
+                let img_data = match memegen_lib::generate_font_rendering_with_transparency(&mut element.line) {
+                    Ok(img_data) => img_data,
+                    Err(err) => {
+                        eprintln!("Could not resize text area: {}", err);
+                        element.line.fontspec.scale.x -= scale_modifier as f32;
+                        element.line.fontspec.scale.y -= scale_modifier as f32;
+                        return
+                    }
+                };
 
-                let img_data = memegen_lib::generate_font_rendering_with_transparency(&mut element.line);
+                fixed_container.remove(&element.image);
                 let (img,background_dimensions) = add_image_at_target_position(&fixed_container, img_data,(element.pos_x,element.pos_y));
                 element.image = img;
                 element.size_x = background_dimensions.0 as i32;
@@ -287,34 +328,242 @@ pub fn handle_button_press(
     }
 }
 
+/**
+    Creates the meme document of the text areas on the background
+    The text areas are positioned on the preview, as fractions of the preview they match the original image.
+    Text areas opened from a project keep their layer, only the text, position and size that were edited change.
+*/
+fn build_meme(
+    background: &Background,
+    background_dimensions: &(u32, u32),
+    lines: &[TextArea],
+) -> Meme {
+    let (preview_width, preview_height) = *background_dimensions;
+    let preview_width = preview_width as f32;
+    let preview_height = preview_height as f32;
+
+    let mut meme = Meme::new(background.clone());
+    for area in lines.iter() {
+        let line = &area.line;
+        let fontspec = &line.fontspec;
+        let x = area.pos_x as f32 / preview_width;
+        let y = area.pos_y as f32 / preview_height;
+        let size = fontspec.scale.y / preview_height;
+
+        let layer = match &area.opened {
+            Some(opened) => {
+                let mut layer = opened.layer.clone();
+                // the text view shows the text trimmed, untouched text keeps its whitespace
+                if line.text != layer.text.trim() {
+                    layer.text = line.text.clone();
+                }
+                if fontspec.scale.y != opened.scale {
+                    layer.style.size = size;
+                }
+                if (area.pos_x, area.pos_y) != (opened.pos_x, opened.pos_y) {
+                    layer.placement = match layer.placement {
+                        Placement::Box{ width, height, vertical_alignment, .. } => {
+                            Placement::Box{ x, y, width, height, vertical_alignment }
+                        }
+                        // moved anchored text is placed where it was dropped
+                        _ => Placement::Point{ x, y },
+                    };
+                }
+                layer
+            }
+            // the rows of new text areas are separated by newlines like on their rendering
+            None => TextLayer{
+                text: line.text.clone(),
+                style: TextStyle{
+                    font_family: fontspec.family.clone(),
+                    font_style: Some(fontspec.style.clone()),
+                    size,
+                    fill: fontspec.colour_main,
+                    outline: fontspec.colour_outline,
+                    outline_width: fontspec.outline_width.map(|width| width / preview_height),
+                    alignment: line.alignment.clone(),
+                    max_rows: line.max_rows,
+                    ..TextStyle::default()
+                },
+                placement: Placement::Point{ x, y },
+                visible: None,
+            },
+        };
+        meme.layers.push(layer);
+    }
+    meme
+}
+
+/**
+    The baked fonts and, if the meme uses a family that is not baked, the fonts installed on the system
+*/
+fn fonts_for(meme: &Meme) -> FontRegistry {
+    let mut fonts = FontRegistry::with_baked_fonts();
+    let missing = meme.layers.iter().any(|layer| {
+        fonts.find(&layer.style.font_family, layer.style.font_style.as_deref()).is_none()
+    });
+    if missing {
+        fonts.load_system_fonts();
+    }
+    fonts
+}
+
 /**
     Handle activation of the save button
 */
 pub fn handle_save(
-    background_location: RefMut<String>,
-    mut lines: RefMut<Vec<TextArea>>,
+    window: &Window,
+    background: RefMut<Background>,
+    background_dimensions: RefMut<(u32, u32)>,
+    lines: RefMut<Vec<TextArea>>,
 ){
-    let mut image = image::load_from_memory(include_bytes!("../morpheus.jpg")).unwrap().to_rgba();
-    if *background_location != ""{
-        image = image::open(&*background_location).unwrap().to_rgba();
+    let path = match choose_export_file(window) {
+        Some(path) => path,
+        None => return,
+    };
+    // files without the extension of a known format are saved as PNG
+    let (path, format) = match ExportFormat::from_path(&path) {
+        Some(format) => (path, format),
+        None => (path.with_extension("png"), ExportFormat::Png),
+    };
+    let meme = build_meme(&background, &background_dimensions, &lines);
+    // animated GIFs keep their frames when saved as GIF, everything else is saved as a still
+    let saved = render_animation(&meme, &fonts_for(&meme))
+        .and_then(|animation| {
+            if format == ExportFormat::Gif && animation.is_animated() {
+                animation.save_gif(&path)
+            } else {
+                let image = animation.frames.into_iter().next().map(|frame| frame.image);
+                ExportOptions::new(format).save(&image.ok_or(MemegenError::EmptyImage)?, None, &path)
+            }
+        });
+    if let Err(err) = saved {
+        eprintln!("Could not save the meme: {}", err);
     }
+}
 
-    let mut image = PreviewService::generate_preview(&image);
-    for area in lines.iter_mut() {
-        let line = &area.line;
-        for (i,split) in line.text.split("\n").enumerate() {
-
-            let spacing = line.fontspec.font.v_metrics(line.fontspec.scale).ascent + 6.0;
-            let offset = spacing * i as f32;
-            let mut single_line = Line{
-                text: split.to_string(),
-                fontspec: line.fontspec.clone(),
-                orientation: Orientation::Top,
-                number_from_layout_anchor: 0,
-            };
-            draw_line_at(&mut single_line, &mut image, area.pos_x as f32,area.pos_y as f32 + offset);
+/**
+    Handle activation of the save project button
+    The project keeps the text areas editable, unlike the rendered image
+*/
+pub fn handle_save_project(
+    window: &Window,
+    background: RefMut<Background>,
+    background_dimensions: RefMut<(u32, u32)>,
+    lines: RefMut<Vec<TextArea>>,
+){
+    if let Some(path) = choose_project_file(window, FileChooserAction::Save) {
+        let meme = build_meme(&background, &background_dimensions, &lines);
+        if let Err(err) = save_project(&meme, &path) {
+            eprintln!("Could not save the project: {}", err);
         }
     }
-    image.save("./output.jpg").unwrap();
 }
 
+/**
+    Handle activation of the open project button
+    The background and the text areas of the project replace the current ones.
+    Returns the text of the text areas, separated like in the text view.
+*/
+pub fn handle_open_project(
+    window: &Window,
+    img: &Image,
+    fixed_container: &Fixed,
+    mut background: RefMut<Background>,
+    mut background_dimensions: RefMut<(u32, u32)>,
+    mut lines: RefMut<Vec<TextArea>>,
+    mut last_text: RefMut<String>,
+) -> Option<String> {
+    let path = choose_project_file(window, FileChooserAction::Open)?;
+    let meme = match load_project(&path) {
+        Ok(meme) => meme,
+        Err(err) => {
+            eprintln!("Could not open the project: {}", err);
+            return None
+        }
+    };
+    let background_image = match meme.load_background() {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("Could not load the background of the project: {}", err);
+            return None
+        }
+    };
+    *background_dimensions = show_background(img, background_image);
+    *background = meme.background.clone();
+
+    lines.iter().for_each(|area| fixed_container.remove(&area.image));
+    lines.clear();
+
+    let fonts = fonts_for(&meme);
+    let (preview_width, preview_height) = *background_dimensions;
+    // the preview of the background, anchored text is laid out on it to find its position
+    let preview = RgbaImage::new(preview_width, preview_height);
+    for layer in meme.layers.iter().filter(|layer| layer.text.trim() != "") {
+        // text in a font that is not installed is shown in Impact, the project keeps its font
+        let fontspec = layer.style.fontspec(&fonts, preview_height).or_else(|_| {
+            TextStyle{ font_family: "Impact".to_string(), font_style: None, ..layer.style.clone() }
+                .fontspec(&fonts, preview_height)
+        });
+        let fontspec = match fontspec {
+            Ok(fontspec) => fontspec,
+            Err(err) => {
+                eprintln!("Could not load the font of \"{}\": {}", layer.text, err);
+                continue
+            }
+        };
+        let mut line = Line{
+            text: layer.text.trim().to_string(),
+            alignment: layer.style.alignment.clone(),
+            max_rows: layer.style.max_rows,
+            fontspec,
+            ..Line::default()
+        };
+        let scale = line.fontspec.scale.y;
+        let (pos_x, pos_y) = match &layer.placement {
+            Placement::Point{ x, y } | Placement::Box{ x, y, .. } => {
+                ((x * preview_width as f32) as i32, (y * preview_height as f32) as i32)
+            }
+            Placement::Anchor{ orientation } => {
+                // the text area starts where the automatic layout puts the first row
+                line.orientation = orientation.clone();
+                let rows = place_line(&mut line, &preview).unwrap_or_default();
+                let x = rows.iter().map(|row| row.x).fold(f32::INFINITY, f32::min);
+                match rows.first() {
+                    Some(row) => (x as i32, row.y as i32),
+                    None => (10, 10),
+                }
+            }
+        };
+        let img_data = match memegen_lib::generate_font_rendering_with_transparency(&mut line) {
+            Ok(img_data) => img_data,
+            Err(err) => {
+                eprintln!("Could not render text area: {}", err);
+                continue
+            }
+        };
+        let (image,dimensions) = add_image_at_target_position(fixed_container, img_data, (pos_x,pos_y));
+        lines.push(TextArea{
+            pos_x,
+            pos_y,
+            size_x: dimensions.0 as i32,
+            size_y: dimensions.1 as i32,
+            image,
+            click_offset_x: 0,
+            click_offset_y: 0,
+            line,
+            position_in_text_field: 0,
+            opened: Some(OpenedLayer{
+                layer: layer.clone(),
+                pos_x,
+                pos_y,
+                scale,
+            }),
+        });
+    }
+
+    // with the text already known, updating the text view reuses the text areas
+    let text = lines.iter().map(|area| area.line.text.clone()).collect::<Vec<_>>().join("\n\n");
+    *last_text = text.clone();
+    Some(text)
+}
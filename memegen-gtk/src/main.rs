@@ -12,7 +12,7 @@ use gtk::prelude::*;
 use gtk::{ Builder, Button, EventBox, Fixed, TextView, Window, Application };
 use std::env::args;
 
-use memegen_lib::{PreviewService};
+use memegen_lib::{Background, PreviewService};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -86,7 +86,7 @@ fn setup_gui(app: &Application){
     let lines = Rc::new(RefCell::new(text_images));
 
     let background_dimensions = Rc::new(RefCell::new(background_dimensions));
-    let background_location = Rc::new(RefCell::new(String::new()));
+    let background = Rc::new(RefCell::new(Background::Encoded { data: background_data.to_vec() }));
 
 
 
@@ -95,20 +95,24 @@ fn setup_gui(app: &Application){
     // the file button, opens the file chooser dialog
     let btn_file: Button = builder.get_object("btn_load").expect("Couldn't get btn_load");
     btn_file.connect_clicked( clone!(
-            window, background_dimensions, background_location => move |_| {
+            window, img, background_dimensions, background => move |_| {
             update_image_from_file(
                 &window,
                 &img,
                 background_dimensions.borrow_mut(),
-                background_location.borrow_mut())
+                background.borrow_mut())
         }));
 
 
-    // the save button, saves the resulting image as output.jpg
+    // the save button, saves the resulting image in the format of the chosen file
     let btn_save: Button = builder.get_object("btn_save").expect("Couldn't get btn_save");
     btn_save.connect_clicked( clone!(
-            lines, background_location => move |_| {
-                handle_save(background_location.borrow_mut(),lines.borrow_mut())
+            window, lines, background, background_dimensions => move |_| {
+                handle_save(
+                    &window,
+                    background.borrow_mut(),
+                    background_dimensions.borrow_mut(),
+                    lines.borrow_mut())
         }));
 
 
@@ -129,6 +133,37 @@ fn setup_gui(app: &Application){
         }));
 
 
+    // the project buttons save and restore the background and all text areas
+
+    let btn_save_project: Button = builder.get_object("btn_save_project").expect("Couldn't get btn_save_project");
+    btn_save_project.connect_clicked( clone!(
+            window, lines, background, background_dimensions => move |_| {
+                handle_save_project(
+                    &window,
+                    background.borrow_mut(),
+                    background_dimensions.borrow_mut(),
+                    lines.borrow_mut())
+        }));
+
+    let btn_open_project: Button = builder.get_object("btn_open_project").expect("Couldn't get btn_open_project");
+    btn_open_project.connect_clicked( clone!(
+            window, img, fixed_container, lines, background, background_dimensions, last_text, text_buffer => move |_| {
+                let text = handle_open_project(
+                    &window,
+                    &img,
+                    &fixed_container,
+                    background.borrow_mut(),
+                    background_dimensions.borrow_mut(),
+                    lines.borrow_mut(),
+                    last_text.borrow_mut());
+                // the text view is only updated after all borrows are released, its change handler needs them
+                if let Some(text) = text {
+                    text_buffer.set_text(&text);
+                }
+                window.show_all();
+        }));
+
+
     // we now connect to mouse events: click, move and scroll
 
     evt_box.connect_button_press_event( clone!(
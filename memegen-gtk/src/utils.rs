@@ -1,11 +1,11 @@
 use gtk::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use gdk_pixbuf::{Colorspace, Pixbuf};
 use gtk::{ FileChooserAction, FileChooserDialog, FileFilter, Fixed, Image, ResponseType, Window};
 use image::{ImageBuffer, Rgba};
 
-use memegen_lib::PreviewService;
+use memegen_lib::{open_image, Background, PreviewService};
 use std::cell::RefMut;
 
 use crate::handlers::TextArea;
@@ -37,7 +37,7 @@ pub fn update_image_from_file(
     window: &Window,
     img: &Image,
     mut background_dimensions: RefMut<(u32, u32)>,
-    mut background_location: RefMut<String>,
+    mut background: RefMut<Background>,
 ) {
     let dialog =
         FileChooserDialog::new(Some("Choose a file"), Some(window), FileChooserAction::Open);
@@ -52,6 +52,11 @@ pub fn update_image_from_file(
     filter.add_pattern("*.jpg");
     filter.add_pattern("*.jpeg");
     filter.add_pattern("*.JPG");
+    filter.add_pattern("*.png");
+    filter.add_pattern("*.PNG");
+    // animated GIFs are saved with the text on every frame
+    filter.add_pattern("*.gif");
+    filter.add_pattern("*.GIF");
     dialog.set_filter(&filter);
     dialog.set_select_multiple(true);
     // this shows the dialog and blocks execution
@@ -61,32 +66,107 @@ pub fn update_image_from_file(
     let filename = dialog.get_filename();
     dialog.destroy();
     if let Some(file) = filename {
-        let image_file = image::open(&file);
+        // the image is shown upright and in sRGB, like the text is placed on it when saving
+        let image_file = open_image(&file);
         if let Ok(image_file) = image_file {
             let filename_str = file.to_str().unwrap();
             println!("Loading: {}", filename_str);
 
-            let background = image_file.to_rgba();
-            let background = PreviewService::generate_preview(&background);
-            let (w, h) = background.dimensions();
-            *background_dimensions = background.dimensions();
-            *background_location = String::from(filename_str);
-
-            let data = background.into_raw();
-            let pixbuf = Pixbuf::new_from_mut_slice(
-                data,
-                Colorspace::Rgb,
-                true,
-                8,
-                w as i32,
-                h as i32,
-                (w * 4) as i32,
-            );
-            img.set_from_pixbuf(&pixbuf);
+            *background_dimensions = show_background(img, image_file);
+            *background = Background::File { path: file.clone() };
         }
     }
 }
 
+/**
+    Lets the user pick a project file, to open or to save to depending on the action
+*/
+pub fn choose_project_file(window: &Window, action: FileChooserAction) -> Option<PathBuf> {
+    let dialog = FileChooserDialog::new(Some("Choose a project file"), Some(window), action);
+    let accept = match action {
+        FileChooserAction::Save => "Save",
+        _ => "Open",
+    };
+    dialog.add_buttons(&[
+        ("Cancel", ResponseType::Cancel.into()),
+        (accept, ResponseType::Ok.into()),
+    ]);
+
+    dialog.set_current_folder(Path::new("."));
+    if action == FileChooserAction::Save {
+        dialog.set_current_name("meme.json");
+        dialog.set_do_overwrite_confirmation(true);
+    }
+
+    let filter = FileFilter::new();
+    filter.add_pattern("*.json");
+    filter.add_pattern("*.toml");
+    dialog.set_filter(&filter);
+
+    // this shows the dialog and blocks execution
+    let response = dialog.run();
+    let filename = dialog.get_filename();
+    dialog.destroy();
+    if response == ResponseType::Ok.into() {
+        filename
+    } else {
+        None
+    }
+}
+
+/**
+    Lets the user pick the file the meme is saved to, its extension chooses the image format
+*/
+pub fn choose_export_file(window: &Window) -> Option<PathBuf> {
+    let dialog = FileChooserDialog::new(Some("Save the meme"), Some(window), FileChooserAction::Save);
+    dialog.add_buttons(&[
+        ("Cancel", ResponseType::Cancel.into()),
+        ("Save", ResponseType::Ok.into()),
+    ]);
+
+    dialog.set_current_folder(Path::new("."));
+    dialog.set_current_name("meme.png");
+    dialog.set_do_overwrite_confirmation(true);
+
+    let filter = FileFilter::new();
+    filter.set_name(Some("Images"));
+    for extension in &["png", "jpg", "jpeg", "webp", "gif", "bmp"] {
+        filter.add_pattern(&format!("*.{}", extension));
+    }
+    dialog.set_filter(&filter);
+
+    // this shows the dialog and blocks execution
+    let response = dialog.run();
+    let filename = dialog.get_filename();
+    dialog.destroy();
+    if response == ResponseType::Ok.into() {
+        filename
+    } else {
+        None
+    }
+}
+
+/**
+    Shows the preview of the background image, returns the dimensions of the preview
+*/
+pub fn show_background(img: &Image, background: ImageBuffer<Rgba<u8>, Vec<u8>>) -> (u32, u32) {
+    let background = PreviewService::generate_preview(&background);
+    let (w, h) = background.dimensions();
+
+    let data = background.into_raw();
+    let pixbuf = Pixbuf::new_from_mut_slice(
+        data,
+        Colorspace::Rgb,
+        true,
+        8,
+        w as i32,
+        h as i32,
+        (w * 4) as i32,
+    );
+    img.set_from_pixbuf(&pixbuf);
+    (w, h)
+}
+
 pub fn add_image_at_target_position(
     fixed_container: &Fixed,
     source: ImageBuffer<Rgba<u8>, Vec<u8>>,
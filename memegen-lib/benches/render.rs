@@ -0,0 +1,108 @@
+/*!
+    Compares rendering a batch of memes without the glyph cache, with the glyph cache
+    and with the glyph cache on all cores, and drawing captions with the default fontspec
+    with and without its glyph cache. Run with `cargo bench`.
+*/
+
+use std::time::{Duration, Instant};
+
+use image::{Rgba, RgbaImage};
+use memegen_lib::{
+    draw_line, render_all, render_with_fonts, Background, FontRegistry, Line, Meme, Orientation,
+};
+
+/// The number of memes in the batch
+const BATCH_SIZE: usize = 48;
+
+fn batch() -> Vec<Meme> {
+    (0..BATCH_SIZE)
+        .map(|idx| {
+            Meme::top_bottom(
+                Background::Solid {
+                    width: 800,
+                    height: 600,
+                    colour: (40, 80, 120),
+                },
+                &[
+                    "One does not simply",
+                    &format!("render meme number {}", idx),
+                ],
+                &["without caching the glyphs"],
+            )
+        })
+        .collect()
+}
+
+fn measure<F: FnMut()>(name: &str, mut run: F) -> Duration {
+    // the first run warms up the caches and is not measured
+    run();
+    let start = Instant::now();
+    run();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<32} {:>8.1} ms, {:>6.2} ms per meme",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        elapsed.as_secs_f64() * 1000.0 / BATCH_SIZE as f64
+    );
+    elapsed
+}
+
+fn main() {
+    let memes = batch();
+    let fonts = FontRegistry::with_baked_fonts();
+    let mut uncached_fonts = fonts.clone();
+    uncached_fonts.set_glyph_cache(None);
+
+    let baseline = measure("sequential, no glyph cache", || {
+        for meme in memes.iter() {
+            render_with_fonts(meme, &uncached_fonts).expect("The meme cannot be rendered");
+        }
+    });
+    let cached = measure("sequential, glyph cache", || {
+        for meme in memes.iter() {
+            render_with_fonts(meme, &fonts).expect("The meme cannot be rendered");
+        }
+    });
+    let parallel = measure("parallel, glyph cache", || {
+        for image in render_all(&memes, &fonts) {
+            image.expect("The meme cannot be rendered");
+        }
+    });
+
+    let speed_up = |elapsed: Duration| baseline.as_secs_f64() / elapsed.as_secs_f64();
+    println!("speed-up of the glyph cache: {:.2}x", speed_up(cached));
+    println!(
+        "speed-up of the glyph cache on {} cores: {:.2}x",
+        rayon::current_num_threads(),
+        speed_up(parallel)
+    );
+
+    // captions drawn like by `draw_lines_top_bottom`, with the shared cache of the baked fonts
+    let background = &RgbaImage::from_pixel(800, 600, Rgba([40, 80, 120, 255]));
+    let captions = |cached: bool| {
+        move || {
+            for meme in batch().iter() {
+                let mut image = background.clone();
+                for (idx, layer) in meme.layers.iter().enumerate() {
+                    let mut line = Line {
+                        text: layer.text.clone(),
+                        orientation: Orientation::Top,
+                        number_from_layout_anchor: idx as u32,
+                        ..Line::default()
+                    };
+                    if !cached {
+                        line.fontspec.glyph_cache = None;
+                    }
+                    draw_line(&mut line, &mut image).expect("The line cannot be drawn");
+                }
+            }
+        }
+    };
+    let uncached_lines = measure("default fontspec, no glyph cache", captions(false));
+    let cached_lines = measure("default fontspec, glyph cache", captions(true));
+    println!(
+        "speed-up of the glyph cache of the baked fonts: {:.2}x",
+        uncached_lines.as_secs_f64() / cached_lines.as_secs_f64()
+    );
+}
@@ -0,0 +1,184 @@
+#!/usr/bin/env python3
+"""
+Writes the tiny colour fonts used by the emoji tests of memegen-lib.
+
+emoji-colr.ttf has 😀 (U+1F600) as a COLR glyph: a yellow square with a dark square on top.
+emoji-cbdt.ttf has ⭐ (U+2B50) as a red 20x20 PNG bitmap in CBDT and no outlines at all.
+
+Run it from this directory, it only needs the python standard library.
+"""
+import struct
+import zlib
+
+UNITS_PER_EM = 1000
+ASCENDER = 800
+DESCENDER = -200
+ADVANCE = 1000
+
+
+def pad4(data):
+    return data + b"\0" * (-len(data) % 4)
+
+
+def checksum(data):
+    data = pad4(data)
+    return sum(struct.unpack(">%dI" % (len(data) // 4), data)) & 0xFFFFFFFF
+
+
+def font(tables):
+    tags = sorted(tables)
+    count = len(tags)
+    entry_selector = count.bit_length() - 1
+    search_range = (1 << entry_selector) * 16
+    out = struct.pack(">IHHHH", 0x00010000, count, search_range, entry_selector,
+                      count * 16 - search_range)
+    offset = 12 + 16 * count
+    body = b""
+    for tag in tags:
+        data = tables[tag]
+        out += struct.pack(">4sIII", tag.encode(), checksum(data), offset, len(data))
+        body += pad4(data)
+        offset += len(pad4(data))
+    return out + body
+
+
+def head(long_offsets=False):
+    return struct.pack(">IIIIHHqqhhhhHHhhh", 0x00010000, 0x00010000, 0, 0x5F0F3CF5, 0,
+                       UNITS_PER_EM, 0, 0, 0, DESCENDER, ADVANCE, ASCENDER, 0, 8, 2,
+                       1 if long_offsets else 0, 0)
+
+
+def hhea(glyph_count):
+    return struct.pack(">IhhhHhhhhhhhhhhhH", 0x00010000, ASCENDER, DESCENDER, 0, ADVANCE,
+                       0, 0, ADVANCE, 1, 0, 0, 0, 0, 0, 0, 0, glyph_count)
+
+
+def hmtx(glyph_count):
+    return struct.pack(">" + "Hh" * glyph_count, *([ADVANCE, 0] * glyph_count))
+
+
+def maxp(glyph_count, with_outlines):
+    if not with_outlines:
+        return struct.pack(">IH", 0x00005000, glyph_count)
+    return struct.pack(">IHHHHHHHHHHHHHH", 0x00010000, glyph_count, 4, 1, 0, 0, 2, 0, 0, 0,
+                       0, 0, 0, 0, 0)
+
+
+def cmap(mapping):
+    groups = b"".join(struct.pack(">III", code, code, gid) for code, gid in sorted(mapping.items()))
+    subtable = struct.pack(">HHIII", 12, 0, 16 + len(groups), 0, len(mapping)) + groups
+    return struct.pack(">HHHHI", 0, 1, 3, 10, 12) + subtable
+
+
+def name(family):
+    records = [(1, family), (2, "Regular")]
+    strings = b""
+    entries = b""
+    for name_id, value in records:
+        encoded = value.encode("utf-16-be")
+        entries += struct.pack(">HHHHHH", 3, 1, 0x409, name_id, len(encoded), len(strings))
+        strings += encoded
+    return struct.pack(">HHH", 0, len(records), 6 + len(entries)) + entries + strings
+
+
+def square(x_min, y_min, x_max, y_max):
+    """A simple glyph with a single clockwise contour"""
+    points = [(x_min, y_min), (x_min, y_max), (x_max, y_max), (x_max, y_min)]
+    data = struct.pack(">hhhhh", 1, x_min, y_min, x_max, y_max)
+    data += struct.pack(">HH", 3, 0)
+    data += bytes([0x01] * 4)
+    last = (0, 0)
+    xs = b""
+    ys = b""
+    for x, y in points:
+        xs += struct.pack(">h", x - last[0])
+        ys += struct.pack(">h", y - last[1])
+        last = (x, y)
+    return data + xs + ys
+
+
+def colr_font():
+    glyphs = [b"", square(0, DESCENDER, ADVANCE, ASCENDER), square(50, -150, 950, 750),
+              square(300, 200, 700, 500)]
+    glyf = b""
+    loca = []
+    for glyph in glyphs:
+        loca.append(len(glyf))
+        glyf += pad4(glyph)
+    loca.append(len(glyf))
+
+    base_glyphs = struct.pack(">HHH", 1, 0, 2)
+    layers = struct.pack(">HHHH", 2, 0, 3, 1)
+    colr = struct.pack(">HHIIH", 0, 1, 14, 14 + len(base_glyphs), 2) + base_glyphs + layers
+    # the colours are stored as BGRA
+    colours = struct.pack(">BBBB", 0, 220, 255, 255) + struct.pack(">BBBB", 40, 40, 40, 255)
+    cpal = struct.pack(">HHHHI", 0, 2, 1, 2, 14) + struct.pack(">H", 0) + colours
+
+    return font({
+        "head": head(long_offsets=True),
+        "hhea": hhea(len(glyphs)),
+        "maxp": maxp(len(glyphs), True),
+        "hmtx": hmtx(len(glyphs)),
+        "cmap": cmap({0x1F600: 1}),
+        "loca": struct.pack(">%dI" % len(loca), *loca),
+        "glyf": glyf,
+        "name": name("Test Emoji COLR"),
+        "COLR": colr,
+        "CPAL": cpal,
+    })
+
+
+def png(width, height, pixel):
+    def chunk(tag, data):
+        return struct.pack(">I", len(data)) + tag + data + struct.pack(
+            ">I", zlib.crc32(tag + data) & 0xFFFFFFFF)
+
+    rows = b""
+    for y in range(height):
+        rows += b"\0" + b"".join(bytes(pixel(x, y)) for x in range(width))
+    return (b"\x89PNG\r\n\x1a\n"
+            + chunk(b"IHDR", struct.pack(">IIBBBBB", width, height, 8, 6, 0, 0, 0))
+            + chunk(b"IDAT", zlib.compress(rows))
+            + chunk(b"IEND", b""))
+
+
+def cbdt_font():
+    size = 20
+    image = png(size, size, lambda x, y: (255, 0, 0, 255) if 2 <= x < 18 and 2 <= y < 18
+                else (0, 0, 0, 0))
+    # small glyph metrics: height, width, bearing x, bearing y (top), advance
+    glyph_data = struct.pack(">BBbbB", size, size, 0, 16, size)
+    glyph_data += struct.pack(">I", len(image)) + image
+    cbdt = struct.pack(">I", 0x00030000) + glyph_data
+    image_data_offset = 4
+
+    # a single strike for glyph 1 with an index subtable of format 1 and image format 17
+    index_subtable = struct.pack(">HHI", 1, 17, image_data_offset)
+    index_subtable += struct.pack(">II", 0, len(glyph_data))
+    subtable_array = struct.pack(">HHI", 1, 1, 8)
+    line_metrics = struct.pack(">bbBbbbbbbbbb", 16, -4, size, 1, 0, 0, 0, 0, 0, 0, 0, 0)
+    bitmap_size_offset = 8
+    subtable_array_offset = bitmap_size_offset + 48
+    bitmap_size = struct.pack(">IIII", subtable_array_offset,
+                              len(subtable_array) + len(index_subtable), 1, 0)
+    bitmap_size += line_metrics + line_metrics
+    bitmap_size += struct.pack(">HHBBBb", 1, 1, size, size, 32, 1)
+    cblc = struct.pack(">II", 0x00030000, 1) + bitmap_size + subtable_array + index_subtable
+
+    return font({
+        "head": head(),
+        "hhea": hhea(2),
+        "maxp": maxp(2, False),
+        "hmtx": hmtx(2),
+        "cmap": cmap({0x2B50: 1}),
+        "name": name("Test Emoji CBDT"),
+        "CBDT": cbdt,
+        "CBLC": cblc,
+    })
+
+
+if __name__ == "__main__":
+    with open("emoji-colr.ttf", "wb") as f:
+        f.write(colr_font())
+    with open("emoji-cbdt.ttf", "wb") as f:
+        f.write(cbdt_font())
@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""
+Writes the tiny font used by the bidirectional text tests of memegen-lib.
+
+rtl.ttf has the latin letters a and b, a space, a comma, the hebrew letters of the tests
+and the arabic letter beh (U+0628) with its initial, medial and final forms,
+which are chosen by the init, medi and fina features of its GSUB table.
+Every glyph is a box of its own width, so glyphs can be told apart by their outlines.
+
+Run it from this directory, it only needs the python standard library.
+"""
+import struct
+
+from make_emoji_fonts import font, head, hhea, maxp, cmap, name, pad4, square, ASCENDER, ADVANCE
+
+HEBREW = "שלוםעזהמארךד"
+BEH = 0x0628
+# the forms of beh follow its isolated glyph
+BEH_FORMS = ["init", "medi", "fina"]
+
+
+def glyphs():
+    """The outlines and the mapped characters of all glyphs"""
+    characters = [None, " ", ",", "a", "b"] + list(HEBREW) + [chr(BEH)] + [None] * len(BEH_FORMS)
+    outlines = []
+    for gid, character in enumerate(characters):
+        if gid == 0 or character == " ":
+            outlines.append(b"")
+        else:
+            width = 100 + 20 * gid
+            outlines.append(square(50, 0, 50 + width, ASCENDER if character != "," else 150))
+    return outlines, characters
+
+
+def coverage(gid):
+    return struct.pack(">HHH", 1, 1, gid)
+
+
+def single_substitution(source, target):
+    """A lookup of type 1 with a single subtable of format 2"""
+    subtable = struct.pack(">HHHH", 2, 8, 1, target) + coverage(source)
+    return struct.pack(">HHHH", 1, 0, 1, 8) + subtable
+
+
+def gsub(beh):
+    # the features are sorted by their tags, each one uses the lookup of the same index
+    features = sorted(BEH_FORMS)
+    # the default language of the arabic and the default script uses all features
+    lang_sys = struct.pack(">HHH", 0, 0xFFFF, len(features)) + struct.pack(
+        ">%dH" % len(features), *range(len(features)))
+    script = struct.pack(">HH", 4, 0) + lang_sys
+    scripts = ["DFLT", "arab"]
+    script_list = struct.pack(">H", len(scripts))
+    offset = 2 + 6 * len(scripts)
+    for tag in scripts:
+        script_list += struct.pack(">4sH", tag.encode(), offset)
+        offset += len(script)
+    script_list += script * len(scripts)
+
+    feature_list = struct.pack(">H", len(features))
+    offset = 2 + 6 * len(features)
+    for feature in features:
+        feature_list += struct.pack(">4sH", feature.encode(), offset)
+        offset += 6
+    for idx in range(len(features)):
+        feature_list += struct.pack(">HHH", 0, 1, idx)
+
+    lookups = [single_substitution(beh, beh + 1 + BEH_FORMS.index(feature))
+               for feature in features]
+    lookup_list = struct.pack(">H", len(lookups))
+    offset = 2 + 2 * len(lookups)
+    for lookup in lookups:
+        lookup_list += struct.pack(">H", offset)
+        offset += len(lookup)
+    lookup_list += b"".join(lookups)
+
+    script_offset = 10
+    feature_offset = script_offset + len(script_list)
+    lookup_offset = feature_offset + len(feature_list)
+    return (struct.pack(">HHHHH", 1, 0, script_offset, feature_offset, lookup_offset)
+            + script_list + feature_list + lookup_list)
+
+
+def rtl_font():
+    outlines, characters = glyphs()
+    glyf = b""
+    loca = []
+    for outline in outlines:
+        loca.append(len(glyf))
+        glyf += pad4(outline)
+    loca.append(len(glyf))
+    mapping = {ord(character): gid for gid, character in enumerate(characters) if character}
+    # glyphs advance by their width and a gap of 50 units on both sides, empty ones by half an em
+    advances = [ADVANCE // 2 if not outline else 100 + 20 * gid + 100
+                for gid, outline in enumerate(outlines)]
+    hmtx = b"".join(struct.pack(">Hh", advance, 50) for advance in advances)
+
+    return font({
+        "head": head(long_offsets=True),
+        "hhea": hhea(len(outlines)),
+        "maxp": maxp(len(outlines), True),
+        "hmtx": hmtx,
+        "cmap": cmap(mapping),
+        "loca": struct.pack(">%dI" % len(loca), *loca),
+        "glyf": glyf,
+        "name": name("Test RTL"),
+        "GSUB": gsub(mapping[BEH]),
+    })
+
+
+if __name__ == "__main__":
+    with open("rtl.ttf", "wb") as f:
+        f.write(rtl_font())
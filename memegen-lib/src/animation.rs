@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use color_quant::NeuQuant;
+use gif::SetParameter;
+use image::{ImageError, Rgba};
+use rayon::prelude::*;
+
+use crate::error::MemegenError;
+use crate::fonts::FontRegistry;
+use crate::layout::RgbaImage;
+use crate::meme::{draw_layers, TextLayer};
+use crate::rendering::blend_pixel;
+
+/**
+    How much of each frame is sampled to find its palette, from 1 (every pixel) to 30.
+    10 is the compromise between quality and speed the GIF encoder recommends.
+*/
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+/// The palette index of transparent pixels, the other 255 colours are picked per frame
+const GIF_TRANSPARENT_INDEX: u8 = 255;
+
+/**
+    A frame of an animation, the full image as it is shown and how long it is shown
+*/
+#[derive(Clone, Debug)]
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    /// GIF delays are in hundredths of a second, a delay of 0 is usually shown as 100ms
+    pub delay: Duration,
+}
+
+/**
+    The frames of an animated image, e.g. a reaction GIF.
+    Partial frames and their disposal are resolved while decoding,
+    so every frame is a complete image in the dimensions of the animation.
+*/
+#[derive(Clone, Debug)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+}
+
+impl Animation {
+    /**
+        An animation of a single frame, still images are shown without any delay
+    */
+    pub fn still(image: RgbaImage) -> Animation {
+        Animation {
+            frames: vec![AnimationFrame {
+                image,
+                delay: Duration::from_millis(0),
+            }],
+        }
+    }
+
+    /**
+        Decodes all frames of a GIF, animated or not
+    */
+    pub fn decode_gif<R: Read>(reader: R) -> Result<Animation, MemegenError> {
+        let mut decoder = gif::Decoder::new(reader);
+        decoder.set(gif::ColorOutput::RGBA);
+        let mut reader = decoder.read_info().map_err(ImageError::from)?;
+        let (width, height) = (u32::from(reader.width()), u32::from(reader.height()));
+
+        // transparent pixels show the canvas, which is what the previous frames left after their disposal
+        let mut canvas = RgbaImage::new(width, height);
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.read_next_frame().map_err(ImageError::from)? {
+            let restore = match frame.dispose {
+                gif::DisposalMethod::Previous => Some(canvas.clone()),
+                _ => None,
+            };
+            let (left, top) = (u32::from(frame.left), u32::from(frame.top));
+            let frame_width = u32::from(frame.width);
+            for (idx, pixel) in frame.buffer.chunks(4).enumerate() {
+                let (x, y) = (
+                    left + idx as u32 % frame_width,
+                    top + idx as u32 / frame_width,
+                );
+                // frames may reach out of the canvas, which is cut off
+                if pixel[3] != 0 && x < width && y < height {
+                    canvas.put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+                }
+            }
+            frames.push(AnimationFrame {
+                image: canvas.clone(),
+                delay: Duration::from_millis(u64::from(frame.delay) * 10),
+            });
+
+            match frame.dispose {
+                gif::DisposalMethod::Background => {
+                    let right = (left + frame_width).min(width);
+                    let bottom = (top + u32::from(frame.height)).min(height);
+                    for y in top..bottom {
+                        for x in left..right {
+                            canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                        }
+                    }
+                }
+                gif::DisposalMethod::Previous => {
+                    if let Some(previous) = restore {
+                        canvas = previous;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if frames.is_empty() {
+            return Err(MemegenError::EmptyImage);
+        }
+        Ok(Animation { frames })
+    }
+
+    /**
+        Opens a GIF file with all its frames
+    */
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Animation, MemegenError> {
+        Animation::decode_gif(File::open(path)?)
+    }
+
+    /// The width and height of every frame
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map(|frame| frame.image.dimensions())
+            .unwrap_or((0, 0))
+    }
+
+    /// Whether there is more than one frame
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    /**
+        Draws the text layers that are visible on each frame onto it, scaled to the dimensions of the animation.
+        The layers are laid out and rasterized once for every combination of visible layers,
+        then they are composited onto the frames. Anchored layers are stacked among the visible layers only,
+        so a caption that replaces another one takes its place.
+    */
+    pub fn draw_layers(
+        &mut self,
+        layers: &[TextLayer],
+        fonts: &FontRegistry,
+    ) -> Result<(), MemegenError> {
+        let mut time = Duration::from_millis(0);
+        let mut visible_layers = Vec::with_capacity(self.frames.len());
+        for (idx, frame) in self.frames.iter().enumerate() {
+            let visible: Vec<usize> = (0..layers.len())
+                .filter(|layer| layers[*layer].is_visible_at(idx, time))
+                .collect();
+            visible_layers.push(visible);
+            time += frame.delay;
+        }
+
+        let (width, height) = self.dimensions();
+        let mut overlays: HashMap<&[usize], RgbaImage> = HashMap::new();
+        for visible in visible_layers.iter() {
+            if visible.is_empty() || overlays.contains_key(&visible[..]) {
+                continue;
+            }
+            let shown: Vec<TextLayer> = visible.iter().map(|idx| layers[*idx].clone()).collect();
+            let mut overlay = RgbaImage::new(width, height);
+            draw_layers(&shown, fonts, &mut overlay)?;
+            overlays.insert(visible, overlay);
+        }
+
+        self.frames
+            .par_iter_mut()
+            .zip(visible_layers.par_iter())
+            .for_each(|(frame, visible)| {
+                if let Some(overlay) = overlays.get(&visible[..]) {
+                    for (pixel, text) in frame.image.pixels_mut().zip(overlay.pixels()) {
+                        if text.data[3] != 0 {
+                            let [r, g, b, a] = text.data;
+                            blend_pixel(pixel, (r, g, b), f32::from(a) / 255.0);
+                        }
+                    }
+                }
+            });
+        Ok(())
+    }
+
+    /**
+        Encodes the frames as a GIF, animations loop forever.
+        Every frame gets a palette of its own colours, pixels that are mostly transparent become transparent.
+    */
+    pub fn encode_gif<W: Write>(&self, writer: W) -> Result<(), MemegenError> {
+        let (width, height) = self.dimensions();
+        if width == 0 || height == 0 {
+            return Err(MemegenError::EmptyImage);
+        }
+        if width > u32::from(u16::MAX) || height > u32::from(u16::MAX) {
+            return Err(MemegenError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "GIFs can be at most 65535 pixels wide, not {}x{}",
+                    width, height
+                ),
+            )));
+        }
+        let frames: Vec<gif::Frame> = self.frames.par_iter().map(quantize_frame).collect();
+
+        let mut encoder = gif::Encoder::new(writer, width as u16, height as u16, &[])?;
+        if self.is_animated() {
+            encoder.set(gif::Repeat::Infinite)?;
+        }
+        for frame in frames.iter() {
+            encoder.write_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    /**
+        Saves the frames as a GIF file, see `encode_gif`
+    */
+    pub fn save_gif<P: AsRef<Path>>(&self, path: P) -> Result<(), MemegenError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.encode_gif(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /**
+        Encodes the frames as an animated PNG that loops forever, with the full colours and transparency.
+        Viewers without APNG support show the first frame.
+    */
+    pub fn encode_apng<W: Write>(&self, writer: W) -> Result<(), MemegenError> {
+        let (width, height) = self.dimensions();
+        if width == 0 || height == 0 {
+            return Err(MemegenError::EmptyImage);
+        }
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(self.frames.len() as u32, 0)
+            .map_err(png_error)?;
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        for frame in self.frames.iter() {
+            // milliseconds, longer delays are cut off
+            let delay = frame.delay.as_millis().min(u128::from(u16::MAX)) as u16;
+            writer.set_frame_delay(delay, 1000).map_err(png_error)?;
+            writer.write_image_data(&frame.image).map_err(png_error)?;
+        }
+        writer.finish().map_err(png_error)
+    }
+
+    /**
+        Saves the frames as an animated PNG file, see `encode_apng`
+    */
+    pub fn save_apng<P: AsRef<Path>>(&self, path: P) -> Result<(), MemegenError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.encode_apng(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) fn png_error(err: png::EncodingError) -> MemegenError {
+    MemegenError::Image(ImageError::FormatError(err.to_string()))
+}
+
+/**
+    Reduces the frame to a palette of at most 255 colours and the transparent index.
+    Frames with few enough colours, like the frames of a decoded GIF, keep their exact colours.
+    Otherwise the palette is found among the visible pixels only, so no colour is mistaken for transparency.
+*/
+fn quantize_frame(frame: &AnimationFrame) -> gif::Frame<'static> {
+    let (width, height) = frame.image.dimensions();
+    let visible = |pixel: &Rgba<u8>| pixel.data[3] >= 128;
+    let rgb = |pixel: &Rgba<u8>| [pixel.data[0], pixel.data[1], pixel.data[2]];
+
+    let mut exact: HashMap<[u8; 3], u8> = HashMap::new();
+    for pixel in frame.image.pixels().filter(|pixel| visible(pixel)) {
+        let next = exact.len();
+        if next == usize::from(GIF_TRANSPARENT_INDEX) {
+            exact.clear();
+            break;
+        }
+        exact.entry(rgb(pixel)).or_insert(next as u8);
+    }
+
+    let (mut palette, indices): (Vec<u8>, Vec<u8>) = if !exact.is_empty() {
+        let mut palette = vec![0; exact.len() * 3];
+        for (colour, idx) in exact.iter() {
+            let idx = usize::from(*idx) * 3;
+            palette[idx..idx + 3].copy_from_slice(colour);
+        }
+        let indices = frame
+            .image
+            .pixels()
+            .map(|pixel| {
+                if visible(pixel) {
+                    exact[&rgb(pixel)]
+                } else {
+                    GIF_TRANSPARENT_INDEX
+                }
+            })
+            .collect();
+        (palette, indices)
+    } else {
+        let mut samples: Vec<u8> = frame
+            .image
+            .pixels()
+            .filter(|pixel| visible(pixel))
+            .flat_map(|pixel| vec![pixel.data[0], pixel.data[1], pixel.data[2], 255])
+            .collect();
+        if samples.is_empty() {
+            samples.extend_from_slice(&[0, 0, 0, 255]);
+        }
+        let quantizer = NeuQuant::new(
+            GIF_QUANTIZATION_SPEED,
+            usize::from(GIF_TRANSPARENT_INDEX),
+            &samples,
+        );
+        let indices = frame
+            .image
+            .pixels()
+            .map(|pixel| {
+                if visible(pixel) {
+                    let [r, g, b] = rgb(pixel);
+                    quantizer.index_of(&[r, g, b, 255]) as u8
+                } else {
+                    GIF_TRANSPARENT_INDEX
+                }
+            })
+            .collect();
+        (quantizer.color_map_rgb(), indices)
+    };
+    palette.resize(256 * 3, 0);
+
+    let mut gif_frame = gif::Frame::from_palette_pixels(
+        width as u16,
+        height as u16,
+        &indices,
+        &palette,
+        Some(GIF_TRANSPARENT_INDEX),
+    );
+    // hundredths of a second, longer delays are cut off
+    gif_frame.delay = (frame.delay.as_millis() / 10).min(u128::from(u16::MAX)) as u16;
+    // every frame is complete, so transparent pixels must not show the previous one
+    gif_frame.dispose = gif::DisposalMethod::Background;
+    gif_frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Visibility;
+    use crate::meme::{Placement, TextLayer};
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    /**
+        A 4x4 GIF: a red background, a blue pixel that is disposed to the background,
+        and a blue pixel that is restored to the previous frame
+    */
+    fn partial_frames_gif() -> Vec<u8> {
+        let palette = [255, 0, 0, 0, 0, 255];
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 4, 4, &palette).unwrap();
+            let red = |dispose| gif::Frame {
+                width: 4,
+                height: 4,
+                dispose,
+                delay: 5,
+                buffer: Cow::Owned(vec![0; 16]),
+                ..gif::Frame::default()
+            };
+            let blue = |position, dispose| gif::Frame {
+                left: position,
+                top: position,
+                width: 1,
+                height: 1,
+                dispose,
+                buffer: Cow::Owned(vec![1]),
+                ..gif::Frame::default()
+            };
+            let frames = [
+                red(gif::DisposalMethod::Keep),
+                blue(1, gif::DisposalMethod::Background),
+                blue(2, gif::DisposalMethod::Previous),
+                blue(3, gif::DisposalMethod::Keep),
+            ];
+            for frame in frames.iter() {
+                encoder.write_frame(frame).unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_decode_gif_disposal() -> Result<(), MemegenError> {
+        let animation = Animation::decode_gif(Cursor::new(partial_frames_gif()))?;
+        assert_eq!(4, animation.frames.len());
+        assert_eq!((4, 4), animation.dimensions());
+        assert!(animation.is_animated());
+        let pixel = |frame: usize, x, y| animation.frames[frame].image.get_pixel(x, y).data;
+
+        assert_eq!(RED, pixel(0, 1, 1));
+        assert_eq!(Duration::from_millis(50), animation.frames[0].delay);
+        assert_eq!(BLUE, pixel(1, 1, 1));
+        assert_eq!(RED, pixel(1, 0, 0));
+        // the second frame was cleared to transparency
+        assert_eq!([0, 0, 0, 0], pixel(2, 1, 1)[..]);
+        assert_eq!(BLUE, pixel(2, 2, 2));
+        // the third frame was restored to what was there before it
+        assert_eq!(RED, pixel(3, 2, 2));
+        assert_eq!(BLUE, pixel(3, 3, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gif_round_trip() -> Result<(), MemegenError> {
+        let mut animation = Animation::decode_gif(Cursor::new(partial_frames_gif()))?;
+        animation.frames[3].delay = Duration::from_millis(120);
+        let mut data = Vec::new();
+        animation.encode_gif(&mut data)?;
+        let decoded = Animation::decode_gif(Cursor::new(data))?;
+        assert_eq!(4, decoded.frames.len());
+        assert_eq!(Duration::from_millis(120), decoded.frames[3].delay);
+        // frames of few colours keep them exactly
+        for (original, decoded) in animation.frames.iter().zip(decoded.frames.iter()) {
+            assert_eq!(
+                original.image.clone().into_raw(),
+                decoded.image.clone().into_raw()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_gif_quantization() -> Result<(), MemegenError> {
+        let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+            let alpha = if x < 4 { 0 } else { 255 };
+            Rgba([(x * 4) as u8, (y * 4) as u8, 128, alpha])
+        });
+        let mut data = Vec::new();
+        Animation::still(gradient.clone()).encode_gif(&mut data)?;
+        let decoded = Animation::decode_gif(Cursor::new(data))?;
+        // the 4096 colours are reduced to a palette of similar ones
+        let mut error = 0;
+        for (a, b) in gradient.pixels().zip(decoded.frames[0].image.pixels()) {
+            if a.data[3] == 0 {
+                assert_eq!(0, b.data[3]);
+            } else {
+                assert_eq!(255, b.data[3]);
+                for (a, b) in a.data.iter().zip(b.data.iter()) {
+                    error += (i32::from(*a) - i32::from(*b)).abs();
+                }
+            }
+        }
+        let mean_error = error as f32 / (60 * 64 * 3) as f32;
+        assert!(mean_error < 8.0, "{}", mean_error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_ranged_layers() -> Result<(), MemegenError> {
+        let black = RgbaImage::from_pixel(240, 160, Rgba([0, 0, 0, 255]));
+        let frame = AnimationFrame {
+            image: black.clone(),
+            delay: Duration::from_millis(500),
+        };
+        let mut animation = Animation {
+            frames: vec![frame; 5],
+        };
+        let top = || Placement::Anchor {
+            orientation: crate::Orientation::Top,
+        };
+        let me = TextLayer {
+            visible: Some(Visibility::Time {
+                start: 0.0,
+                end: Some(1.0),
+            }),
+            ..TextLayer::new("ME", top())
+        };
+        let also_me = TextLayer {
+            visible: Some(Visibility::Time {
+                start: 1.0,
+                end: None,
+            }),
+            ..TextLayer::new("ALSO ME", top())
+        };
+        let last_frame = TextLayer {
+            visible: Some(Visibility::Frames {
+                start: 4,
+                end: None,
+            }),
+            ..TextLayer::new("THE END", Placement::Point { x: 0.1, y: 0.6 })
+        };
+        let layers = vec![me.clone(), also_me.clone(), last_frame];
+        let fonts = FontRegistry::with_baked_fonts();
+        animation.draw_layers(&layers, &fonts)?;
+
+        let drawn = |layers: &[TextLayer]| -> Result<Vec<u8>, MemegenError> {
+            let mut image = black.clone();
+            draw_layers(layers, &fonts, &mut image)?;
+            Ok(image.into_raw())
+        };
+        let frame = |idx: usize| animation.frames[idx].image.clone().into_raw();
+        assert_eq!(frame(0), frame(1));
+        assert_eq!(frame(2), frame(3));
+        assert_ne!(frame(1), frame(2));
+        assert_ne!(frame(3), frame(4));
+        // the later caption takes the place of the earlier one at the top
+        let close = |a: Vec<u8>, b: Vec<u8>| {
+            a.iter()
+                .zip(b.iter())
+                .all(|(a, b)| (i32::from(*a) - i32::from(*b)).abs() <= 1)
+        };
+        assert!(close(frame(0), drawn(std::slice::from_ref(&me))?));
+        assert!(close(frame(2), drawn(std::slice::from_ref(&also_me))?));
+        animation.save_gif("test_output/test_time_ranged_layers.gif")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_layers_on_every_frame() -> Result<(), MemegenError> {
+        let frame = |colour: [u8; 4]| AnimationFrame {
+            image: RgbaImage::from_pixel(200, 100, Rgba(colour)),
+            delay: Duration::from_millis(100),
+        };
+        let mut animation = Animation {
+            frames: vec![frame(RED), frame(BLUE), frame([0, 0, 0, 0])],
+        };
+        let layers = vec![TextLayer::new(
+            "EVERY FRAME",
+            Placement::Point { x: 0.1, y: 0.1 },
+        )];
+        let fonts = FontRegistry::with_baked_fonts();
+        animation.draw_layers(&layers, &fonts)?;
+
+        // the overlay matches drawing onto the frame directly
+        let mut direct = RgbaImage::from_pixel(200, 100, Rgba(RED));
+        draw_layers(&layers, &fonts, &mut direct)?;
+        for (a, b) in animation.frames[0].image.pixels().zip(direct.pixels()) {
+            for (a, b) in a.data.iter().zip(b.data.iter()) {
+                assert!((i32::from(*a) - i32::from(*b)).abs() <= 1);
+            }
+        }
+        for frame in animation.frames.iter() {
+            assert!(frame
+                .image
+                .pixels()
+                .any(|pixel| pixel.data == [255, 255, 255, 255]));
+        }
+        animation.save_gif("test_output/test_draw_layers_on_every_frame.gif")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_still() -> Result<(), MemegenError> {
+        let animation = Animation::still(RgbaImage::new(3, 2));
+        assert!(!animation.is_animated());
+        assert_eq!((3, 2), animation.dimensions());
+        let mut data = Vec::new();
+        Animation { frames: vec![] }
+            .encode_gif(&mut data)
+            .expect_err("There are no frames");
+        animation.encode_gif(&mut data)?;
+        assert_eq!(b"GIF89a", &data[..6]);
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::animation::{Animation, AnimationFrame};
+use crate::error::MemegenError;
+use crate::layout::{
+    draw_line_at, get_row_height, get_text_width, place_line, Line, PlacedRow, RgbaImage,
+    VerticalAlignment,
+};
+use crate::rendering::blend_pixel;
+use crate::shaping::is_rtl;
+
+/// The share of the duration text takes to type or fade in, the rest shows the finished captions
+const EFFECT_SHARE: f32 = 0.75;
+/// How high captions bounce, as a fraction of their row height
+const BOUNCE_HEIGHT: f32 = 0.4;
+/// How often captions bounce in one loop of the animation
+const BOUNCES: f32 = 2.0;
+/// How far each row lags behind the row before it while bouncing, as a fraction of a bounce
+const BOUNCE_STAGGER: f32 = 0.15;
+/// The most frames a caption animation may have
+pub const MAX_EFFECT_FRAMES: usize = 1000;
+
+/**
+    How captions are animated on a still background
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    /// The text appears character by character, in reading order of all captions
+    Typewriter,
+    /// The text fades in from transparent
+    Fade,
+    /// The rows of text jump up and down, one after the other
+    Bounce,
+}
+
+impl FromStr for Effect {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Effect, String> {
+        match name {
+            "typewriter" => Ok(Effect::Typewriter),
+            "fade" => Ok(Effect::Fade),
+            "bounce" => Ok(Effect::Bounce),
+            _ => Err(format!(
+                "unknown effect {}, expected typewriter, fade or bounce",
+                name
+            )),
+        }
+    }
+}
+
+/**
+    The effect and the timing of a caption animation.
+    Typing and fading take the first three quarters of the duration, bouncing loops over all of it.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectSettings {
+    pub effect: Effect,
+    /// Frames per second, GIFs round the frame delays to hundredths of a second
+    pub fps: f32,
+    /// The length of one loop of the animation
+    pub duration: Duration,
+}
+
+impl Default for EffectSettings {
+    fn default() -> EffectSettings {
+        EffectSettings {
+            effect: Effect::Typewriter,
+            fps: 15.0,
+            duration: Duration::from_secs(3),
+        }
+    }
+}
+
+impl EffectSettings {
+    /**
+        The number of frames of the animation, at least one
+    */
+    pub fn frame_count(&self) -> Result<usize, MemegenError> {
+        if !(self.fps.is_finite() && self.fps > 0.0) {
+            return Err(MemegenError::Effect(format!(
+                "the frame rate must be positive, not {}",
+                self.fps
+            )));
+        }
+        if self.duration == Duration::from_millis(0) {
+            return Err(MemegenError::Effect(
+                "the duration must not be 0".to_string(),
+            ));
+        }
+        let frames = (self.fps * self.duration.as_secs_f32()).round().max(1.0);
+        if frames > MAX_EFFECT_FRAMES as f32 {
+            return Err(MemegenError::Effect(format!(
+                "{} frames are too many, at most {} are allowed",
+                frames, MAX_EFFECT_FRAMES
+            )));
+        }
+        Ok(frames as usize)
+    }
+}
+
+/**
+    Animates the lines on the background with the effect of the settings.
+    The lines are laid out at their anchors like by `draw_line`, lines at the same anchor are stacked
+    like the layers of a meme. Every frame is drawn row by row with `draw_line_at`.
+*/
+pub fn animate_lines(
+    lines: &[Line],
+    background: &RgbaImage,
+    settings: &EffectSettings,
+) -> Result<Animation, MemegenError> {
+    let frame_count = settings.frame_count()?;
+    let placed = place_lines(lines, background)?;
+    let delay = settings.duration / frame_count as u32;
+
+    // fading draws the same text on every frame, only its opacity changes
+    let overlay = match settings.effect {
+        Effect::Fade => {
+            let (width, height) = background.dimensions();
+            let mut overlay = RgbaImage::new(width, height);
+            for (line, row) in placed.iter() {
+                draw_row(line, &row.text, &mut overlay, row.x, row.y)?;
+            }
+            Some(overlay)
+        }
+        _ => None,
+    };
+    let total_graphemes: usize = placed
+        .iter()
+        .map(|(_, row)| row.text.graphemes(true).count())
+        .sum();
+
+    let frames = (0..frame_count)
+        .into_par_iter()
+        .map(|idx| {
+            let time = idx as f32 / frame_count as f32;
+            let progress = (time / EFFECT_SHARE).min(1.0);
+            let mut image = background.clone();
+            match settings.effect {
+                Effect::Typewriter => {
+                    let mut typed = (progress * total_graphemes as f32).floor() as usize;
+                    for (line, row) in placed.iter() {
+                        let prefix: String = row.text.graphemes(true).take(typed).collect();
+                        typed = typed.saturating_sub(row.text.graphemes(true).count());
+                        // right to left rows grow leftwards from their right edge
+                        let x = if is_rtl(&row.text) {
+                            row.x + get_text_width(&line.fontspec, &row.text)
+                                - get_text_width(&line.fontspec, &prefix)
+                        } else {
+                            row.x
+                        };
+                        draw_row(line, &prefix, &mut image, x, row.y)?;
+                    }
+                }
+                Effect::Fade => {
+                    let overlay = overlay.as_ref().expect("The overlay is drawn for fading");
+                    for (pixel, text) in image.pixels_mut().zip(overlay.pixels()) {
+                        if text.data[3] != 0 {
+                            let [r, g, b, a] = text.data;
+                            blend_pixel(pixel, (r, g, b), progress * f32::from(a) / 255.0);
+                        }
+                    }
+                }
+                Effect::Bounce => {
+                    for (row_idx, (line, row)) in placed.iter().enumerate() {
+                        let phase = time * BOUNCES - row_idx as f32 * BOUNCE_STAGGER;
+                        let height = BOUNCE_HEIGHT * get_row_height(&line.fontspec);
+                        let y = row.y - height * (PI * phase).sin().abs();
+                        draw_row(line, &row.text, &mut image, row.x, y)?;
+                    }
+                }
+            }
+            Ok(AnimationFrame { image, delay })
+        })
+        .collect::<Result<Vec<_>, MemegenError>>()?;
+    Ok(Animation { frames })
+}
+
+/**
+    Lays out all lines, in reading order of their rows.
+    Lines at the same anchor are stacked away from it: later lines below earlier ones,
+    and at the bottom, earlier lines above later ones.
+*/
+fn place_lines(lines: &[Line], image: &RgbaImage) -> Result<Vec<(Line, PlacedRow)>, MemegenError> {
+    let at_bottom =
+        |line: &Line| line.orientation.vertical_alignment() == VerticalAlignment::Bottom;
+    let order = (0..lines.len())
+        .filter(|idx| !at_bottom(&lines[*idx]))
+        .chain((0..lines.len()).rev().filter(|idx| at_bottom(&lines[*idx])));
+
+    let mut offsets = Vec::new();
+    let mut placed = vec![Vec::new(); lines.len()];
+    for idx in order {
+        let mut line = lines[idx].clone();
+        let anchor = offsets
+            .iter()
+            .position(|(orientation, _)| *orientation == line.orientation)
+            .unwrap_or_else(|| {
+                offsets.push((line.orientation.clone(), 0));
+                offsets.len() - 1
+            });
+        line.number_from_layout_anchor = offsets[anchor].1;
+        let rows = place_line(&mut line, image)?;
+        offsets[anchor].1 += rows.len() as u32;
+        placed[idx] = rows.into_iter().map(|row| (line.clone(), row)).collect();
+    }
+    Ok(placed.into_iter().flatten().collect())
+}
+
+fn draw_row(
+    line: &Line,
+    text: &str,
+    image: &mut RgbaImage,
+    x: f32,
+    y: f32,
+) -> Result<(), MemegenError> {
+    if text.is_empty() {
+        return Ok(());
+    }
+    let mut row = Line {
+        text: text.to_string(),
+        ..line.clone()
+    };
+    draw_line_at(&mut row, image, x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Orientation;
+    use image::Rgba;
+
+    fn background() -> RgbaImage {
+        RgbaImage::from_pixel(320, 200, Rgba([30, 60, 90, 255]))
+    }
+
+    fn lines() -> Vec<Line> {
+        vec![
+            Line {
+                text: "ONE DOES NOT".to_string(),
+                ..Line::default()
+            },
+            Line {
+                text: "SIMPLY ANIMATE".to_string(),
+                orientation: Orientation::Bottom,
+                ..Line::default()
+            },
+        ]
+    }
+
+    fn settings(effect: Effect) -> EffectSettings {
+        EffectSettings {
+            effect,
+            fps: 10.0,
+            duration: Duration::from_secs(2),
+        }
+    }
+
+    fn drawn_pixels(image: &RgbaImage) -> usize {
+        image
+            .pixels()
+            .filter(|pixel| pixel.data != [30, 60, 90, 255])
+            .count()
+    }
+
+    #[test]
+    fn test_typewriter() -> Result<(), MemegenError> {
+        let animation = animate_lines(&lines(), &background(), &settings(Effect::Typewriter))?;
+        assert_eq!(20, animation.frames.len());
+        assert_eq!(Duration::from_millis(100), animation.frames[0].delay);
+        assert_eq!(0, drawn_pixels(&animation.frames[0].image));
+        let drawn: Vec<usize> = animation
+            .frames
+            .iter()
+            .map(|frame| drawn_pixels(&frame.image))
+            .collect();
+        assert!(drawn.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // the finished text is drawn where the layout puts it
+        let mut expected = background();
+        for mut line in lines() {
+            crate::layout::draw_line(&mut line, &mut expected)?;
+        }
+        let last = &animation.frames[19].image;
+        assert_eq!(expected.clone().into_raw(), last.clone().into_raw());
+        animation.save_gif("test_output/test_typewriter.gif")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fade() -> Result<(), MemegenError> {
+        let animation = animate_lines(&lines(), &background(), &settings(Effect::Fade))?;
+        let brightest = |image: &RgbaImage| image.pixels().map(|pixel| pixel.data[0]).max();
+        assert_eq!(Some(30), brightest(&animation.frames[0].image));
+        let middle = brightest(&animation.frames[7].image).unwrap();
+        assert!(middle > 30 && middle < 255, "{}", middle);
+        assert_eq!(Some(255), brightest(&animation.frames[19].image));
+        animation.save_apng("test_output/test_fade.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounce() -> Result<(), MemegenError> {
+        let animation = animate_lines(&lines(), &background(), &settings(Effect::Bounce))?;
+        let top_row = |image: &RgbaImage| {
+            (0..image.height())
+                .find(|y| (0..image.width()).any(|x| image.get_pixel(x, *y).data[0] == 255))
+        };
+        let tops: Vec<Option<u32>> = animation
+            .frames
+            .iter()
+            .map(|frame| top_row(&frame.image))
+            .collect();
+        // the text leaves its place and comes back to it
+        assert!(tops.iter().any(|top| *top < tops[0]));
+        assert!(tops[0].is_some());
+        animation.save_gif("test_output/test_bounce.gif")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let invalid = |fps: f32, duration: Duration| {
+            let settings = EffectSettings {
+                fps,
+                duration,
+                ..EffectSettings::default()
+            };
+            match animate_lines(&lines(), &background(), &settings) {
+                Err(MemegenError::Effect(_)) => {}
+                _ => panic!("{} fps for {:?} are invalid", fps, duration),
+            }
+        };
+        invalid(0.0, Duration::from_secs(1));
+        invalid(f32::NAN, Duration::from_secs(1));
+        invalid(10.0, Duration::from_secs(0));
+        invalid(100.0, Duration::from_secs(60));
+        assert_eq!(Ok(Effect::Fade), "fade".parse());
+        assert!("spin".parse::<Effect>().is_err());
+    }
+}
@@ -0,0 +1,1024 @@
+use image::{imageops, FilterType, ImageBuffer, ImageFormat, Rgba};
+use rusttype::{Point, Vector};
+use ttf_parser::colr::{ClipBox, CompositeMode, GradientExtend, Paint, Painter};
+use ttf_parser::{
+    GlyphId, OutlineBuilder, RasterGlyphImage, RasterImageFormat, RgbaColor, Transform,
+};
+
+use crate::layout::RgbaImage;
+use crate::rendering::FontSource;
+
+/**
+    A colour glyph like an emoji, rendered to an image with its top left corner at (`x`, `y`).
+    Unlike outline glyphs, it keeps its own colours instead of `Fontspec::colour_main`.
+*/
+#[derive(Clone)]
+pub struct ColourGlyph {
+    pub x: i32,
+    pub y: i32,
+    pub image: RgbaImage,
+}
+
+/**
+    A glyph the face defines in colour, placed within a row of text but not rendered yet.
+    Measuring text only needs the columns the glyph covers, it is rendered when the text is drawn.
+*/
+#[derive(Clone)]
+pub struct PlacedColourGlyph {
+    pub source: FontSource,
+    pub glyph_id: u16,
+    /// Converts font units to pixels
+    pub units_to_px: Vector<f32>,
+    /// The origin of the glyph on the baseline
+    pub position: Point<f32>,
+    /// The first pixel column covered by the glyph and the one behind its last
+    pub x_range: (i32, i32),
+}
+
+impl PlacedColourGlyph {
+    /**
+        Places the glyph if the face defines it in colour, either with layers of the `COLR` table
+        or as a bitmap from the `CBDT` or `sbix` tables.
+        The face is the one of `source`.
+    */
+    pub fn place(
+        face: &ttf_parser::Face,
+        source: &FontSource,
+        glyph_id: u16,
+        units_to_px: Vector<f32>,
+        position: Point<f32>,
+    ) -> Option<PlacedColourGlyph> {
+        let id = GlyphId(glyph_id);
+        let x_range = if face.is_color_glyph(id) {
+            let (min_x, max_x) = colr_bounds_x(face, id)?;
+            (
+                (position.x + min_x * units_to_px.x).floor() as i32,
+                (position.x + max_x * units_to_px.x).ceil() as i32,
+            )
+        } else {
+            let (raster, pixels_per_em) = colour_raster(face, id, units_to_px)?;
+            let placement = RasterPlacement::new(&raster, pixels_per_em, units_to_px, position);
+            (placement.x, placement.x + placement.width as i32)
+        };
+        Some(PlacedColourGlyph {
+            source: source.clone(),
+            glyph_id,
+            units_to_px,
+            position,
+            x_range,
+        })
+    }
+
+    /**
+        Renders the glyph in its colours, `None` if nothing of it is visible
+    */
+    pub fn render(&self) -> Option<ColourGlyph> {
+        let face = ttf_parser::Face::parse(&self.source.data, self.source.index).ok()?;
+        render_colour_glyph(&face, self.glyph_id, self.units_to_px, self.position)
+    }
+}
+
+/**
+    Renders the glyph if the face defines it in colour, either with layers of the `COLR` table
+    or as a bitmap from the `CBDT` or `sbix` tables.
+    `units_to_px` converts font units to pixels, `position` is the origin of the glyph on the baseline.
+*/
+pub fn render_colour_glyph(
+    face: &ttf_parser::Face,
+    glyph_id: u16,
+    units_to_px: Vector<f32>,
+    position: Point<f32>,
+) -> Option<ColourGlyph> {
+    let glyph_id = GlyphId(glyph_id);
+    if face.is_color_glyph(glyph_id) {
+        render_colr_glyph(face, glyph_id, units_to_px, position)
+    } else {
+        render_raster_glyph(face, glyph_id, units_to_px, position)
+    }
+}
+
+/**
+    Whether the face has bitmap glyphs but no outlines, like most emoji fonts with `CBDT` tables
+*/
+pub fn is_bitmap_only(face: &ttf_parser::Face) -> bool {
+    let tables = face.tables();
+    tables.glyf.is_none()
+        && tables.cff.is_none()
+        && tables.cff2.is_none()
+        && (tables.cbdt.is_some() || tables.sbix.is_some())
+}
+
+/**
+    Adds empty `glyf` and `loca` tables to a font without outlines,
+    so it can be loaded by rusttype and its glyphs are all empty.
+    Only single fonts are supported, not font collections.
+*/
+pub fn with_empty_outlines(data: &[u8]) -> Option<Vec<u8>> {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+
+    let table_count = read_u16(4)? as usize;
+    let mut tables = Vec::new();
+    for idx in 0..table_count {
+        let record = 12 + idx * 16;
+        let tag = data.get(record..record + 4)?;
+        let offset = read_u32(record + 8)? as usize;
+        let length = read_u32(record + 12)? as usize;
+        tables.push((tag.to_vec(), data.get(offset..offset + length)?.to_vec()));
+    }
+    let find = |tag: &[u8]| tables.iter().find(|(t, _)| t.as_slice() == tag);
+    let glyph_count = find(b"maxp").and_then(|(_, maxp)| maxp.get(4..6))?;
+    let glyph_count = u16::from_be_bytes([glyph_count[0], glyph_count[1]]) as usize;
+    let long_offsets = find(b"head").and_then(|(_, head)| head.get(50..52))? != [0, 0];
+
+    // every glyph starts and ends at offset 0 of the glyf table
+    let offset_size = if long_offsets { 4 } else { 2 };
+    tables.push((b"loca".to_vec(), vec![0; (glyph_count + 1) * offset_size]));
+    tables.push((b"glyf".to_vec(), vec![0; 4]));
+    tables.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut font = data[0..4].to_vec();
+    let table_count = tables.len() as u16;
+    let entry_selector = 15 - table_count.leading_zeros() as u16;
+    let search_range = (1 << entry_selector) * 16;
+    for value in [
+        table_count,
+        search_range,
+        entry_selector,
+        table_count * 16 - search_range,
+    ]
+    .iter()
+    {
+        font.extend_from_slice(&value.to_be_bytes());
+    }
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, table) in tables.iter() {
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&checksum(table).to_be_bytes());
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        offset += (table.len() + 3) & !3;
+    }
+    for (_, table) in tables.iter() {
+        font.extend_from_slice(table);
+        font.resize((font.len() + 3) & !3, 0);
+    }
+    Some(font)
+}
+
+fn checksum(table: &[u8]) -> u32 {
+    table.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+/**
+    The coloured bitmap of the strike closest to the font size, with the size of the font in pixels.
+    Monochrome and grayscale bitmaps are drawn from the outlines like any other glyph.
+*/
+fn colour_raster<'a>(
+    face: &'a ttf_parser::Face,
+    glyph_id: GlyphId,
+    units_to_px: Vector<f32>,
+) -> Option<(RasterGlyphImage<'a>, f32)> {
+    let pixels_per_em = units_to_px.y * f32::from(face.units_per_em());
+    let raster = face.glyph_raster_image(glyph_id, pixels_per_em.round().max(1.0) as u16)?;
+    match raster.format {
+        RasterImageFormat::PNG | RasterImageFormat::BitmapPremulBgra32 => {
+            Some((raster, pixels_per_em))
+        }
+        _ => None,
+    }
+}
+
+/**
+    Where the bitmap of a strike is drawn once it is scaled to the font size
+*/
+struct RasterPlacement {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+}
+
+impl RasterPlacement {
+    fn new(
+        raster: &RasterGlyphImage,
+        pixels_per_em: f32,
+        units_to_px: Vector<f32>,
+        position: Point<f32>,
+    ) -> RasterPlacement {
+        let strike_scale_y = pixels_per_em / f32::from(raster.pixels_per_em);
+        let strike_scale_x = strike_scale_y * units_to_px.x / units_to_px.y;
+        let (raster_width, raster_height) = (f32::from(raster.width), f32::from(raster.height));
+        // the offset of the bitmap is its bottom left corner relative to the glyph origin
+        let x = position.x + f32::from(raster.x) * strike_scale_x;
+        let y = position.y - (f32::from(raster.y) + raster_height) * strike_scale_y;
+        RasterPlacement {
+            x: x.round() as i32,
+            y: y.round() as i32,
+            width: (raster_width * strike_scale_x).round().max(1.0) as u32,
+            height: (raster_height * strike_scale_y).round().max(1.0) as u32,
+        }
+    }
+}
+
+/**
+    Scales the bitmap of the strike closest to the font size to the font size
+*/
+fn render_raster_glyph(
+    face: &ttf_parser::Face,
+    glyph_id: GlyphId,
+    units_to_px: Vector<f32>,
+    position: Point<f32>,
+) -> Option<ColourGlyph> {
+    let (raster, pixels_per_em) = colour_raster(face, glyph_id, units_to_px)?;
+    let bitmap = match raster.format {
+        RasterImageFormat::PNG => {
+            image::load_from_memory_with_format(raster.data, ImageFormat::PNG)
+                .ok()?
+                .to_rgba()
+        }
+        _ => {
+            let (width, height) = (u32::from(raster.width), u32::from(raster.height));
+            let mut bitmap = RgbaImage::new(width, height);
+            for (pixel, bgra) in bitmap.pixels_mut().zip(raster.data.chunks(4)) {
+                let alpha = f32::from(bgra[3]) / 255.0;
+                let unpremultiply = |c: u8| {
+                    if alpha > 0.0 {
+                        (f32::from(c) / alpha).min(255.0) as u8
+                    } else {
+                        0
+                    }
+                };
+                *pixel = Rgba([
+                    unpremultiply(bgra[2]),
+                    unpremultiply(bgra[1]),
+                    unpremultiply(bgra[0]),
+                    bgra[3],
+                ]);
+            }
+            bitmap
+        }
+    };
+
+    let placement = RasterPlacement::new(&raster, pixels_per_em, units_to_px, position);
+    Some(ColourGlyph {
+        x: placement.x,
+        y: placement.y,
+        image: imageops::resize(&bitmap, placement.width, placement.height, FilterType::Triangle),
+    })
+}
+
+/**
+    The horizontal extent of the layers of a `COLR` glyph in font units, without painting them
+*/
+fn colr_bounds_x(face: &ttf_parser::Face, glyph_id: GlyphId) -> Option<(f32, f32)> {
+    let mut painter = BoundsPainter {
+        face,
+        transforms: vec![Transform::default()],
+        bounds: None,
+    };
+    face.paint_color_glyph(glyph_id, 0, RgbaColor::new(0, 0, 0, 255), &mut painter)?;
+    painter.bounds
+}
+
+/**
+    Collects the horizontal extent of the outlines of the layers a `COLR` glyph is painted with
+*/
+struct BoundsPainter<'f, 'a> {
+    face: &'f ttf_parser::Face<'a>,
+    transforms: Vec<Transform>,
+    bounds: Option<(f32, f32)>,
+}
+
+impl<'f, 'a> Painter<'a> for BoundsPainter<'f, 'a> {
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        let rect = match self.face.glyph_bounding_box(glyph_id) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let transform = *self.transforms.last().unwrap();
+        let corners = [
+            (rect.x_min, rect.y_min),
+            (rect.x_min, rect.y_max),
+            (rect.x_max, rect.y_min),
+            (rect.x_max, rect.y_max),
+        ];
+        for (x, y) in corners.iter() {
+            let (x, _) = apply(&transform, f32::from(*x), f32::from(*y));
+            self.bounds = Some(match self.bounds {
+                None => (x, x),
+                Some((min_x, max_x)) => (min_x.min(x), max_x.max(x)),
+            });
+        }
+    }
+
+    fn paint(&mut self, _paint: Paint<'a>) {}
+
+    fn push_clip(&mut self) {}
+
+    fn push_clip_box(&mut self, _clip_box: ClipBox) {}
+
+    fn pop_clip(&mut self) {}
+
+    fn push_layer(&mut self, _mode: CompositeMode) {}
+
+    fn pop_layer(&mut self) {}
+
+    fn push_transform(&mut self, transform: Transform) {
+        let combined = Transform::combine(*self.transforms.last().unwrap(), transform);
+        self.transforms.push(combined);
+    }
+
+    fn pop_transform(&mut self) {
+        if self.transforms.len() > 1 {
+            self.transforms.pop();
+        }
+    }
+}
+
+fn render_colr_glyph(
+    face: &ttf_parser::Face,
+    glyph_id: GlyphId,
+    units_to_px: Vector<f32>,
+    position: Point<f32>,
+) -> Option<ColourGlyph> {
+    // the layers may reach beyond the advance, so half an em of space is added around it
+    let margin = f32::from(face.units_per_em()) / 2.0;
+    let advance = f32::from(face.glyph_hor_advance(glyph_id).unwrap_or(0));
+    let min_x = -margin;
+    let max_y = f32::from(face.ascender()) + margin;
+    let width = ((advance + 2.0 * margin) * units_to_px.x).ceil() as u32;
+    let height = ((max_y - f32::from(face.descender()) + margin) * units_to_px.y).ceil() as u32;
+
+    let origin_x = (position.x + min_x * units_to_px.x).floor();
+    let origin_y = (position.y - max_y * units_to_px.y).floor();
+    // font units, y up, to pixels of the canvas, y down
+    let to_canvas = Transform::new(
+        units_to_px.x,
+        0.0,
+        0.0,
+        -units_to_px.y,
+        position.x - origin_x,
+        position.y - origin_y,
+    );
+
+    let mut painter = ColrPainter::new(face, width, height, to_canvas);
+    let foreground = RgbaColor::new(0, 0, 0, 255);
+    face.paint_color_glyph(glyph_id, 0, foreground, &mut painter)?;
+    let mut image = painter.finish();
+
+    // the canvas is cropped to the painted pixels, so the glyph is measured like outline glyphs
+    let painted: Vec<(u32, u32)> = image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel.data[3] > 0)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+    let min_x = painted.iter().map(|p| p.0).min()?;
+    let min_y = painted.iter().map(|p| p.1).min()?;
+    let max_x = painted.iter().map(|p| p.0).max()?;
+    let max_y = painted.iter().map(|p| p.1).max()?;
+    let image = imageops::crop(
+        &mut image,
+        min_x,
+        min_y,
+        max_x - min_x + 1,
+        max_y - min_y + 1,
+    )
+    .to_image();
+    Some(ColourGlyph {
+        x: origin_x as i32 + min_x as i32,
+        y: origin_y as i32 + min_y as i32,
+        image,
+    })
+}
+
+/**
+    Paints the layers of a `COLR` glyph onto a canvas of premultiplied colours.
+    Composite modes other than source-over are not supported and are painted like it.
+*/
+struct ColrPainter<'f, 'a> {
+    face: &'f ttf_parser::Face<'a>,
+    width: u32,
+    height: u32,
+    transforms: Vec<Transform>,
+    outline: Option<Vec<f32>>,
+    clips: Vec<Vec<f32>>,
+    layers: Vec<Vec<[f32; 4]>>,
+}
+
+impl<'f, 'a> ColrPainter<'f, 'a> {
+    fn new(
+        face: &'f ttf_parser::Face<'a>,
+        width: u32,
+        height: u32,
+        to_canvas: Transform,
+    ) -> ColrPainter<'f, 'a> {
+        ColrPainter {
+            face,
+            width,
+            height,
+            transforms: vec![to_canvas],
+            outline: None,
+            clips: Vec::new(),
+            layers: vec![vec![[0.0; 4]; (width * height) as usize]],
+        }
+    }
+
+    fn transform(&self) -> Transform {
+        *self.transforms.last().unwrap()
+    }
+
+    /**
+        The coverage of the current outline within all clips, one value per pixel
+    */
+    fn coverage(&self) -> Vec<f32> {
+        let mut coverage = self
+            .outline
+            .clone()
+            .unwrap_or_else(|| vec![1.0; (self.width * self.height) as usize]);
+        for clip in self.clips.iter() {
+            for (value, clip) in coverage.iter_mut().zip(clip) {
+                *value *= clip;
+            }
+        }
+        coverage
+    }
+
+    fn rasterize<F: FnOnce(&mut OutlineRasterizer)>(&self, build: F) -> Vec<f32> {
+        let mut rasterizer = OutlineRasterizer::new(self.width, self.height, self.transform());
+        build(&mut rasterizer);
+        rasterizer.finish()
+    }
+
+    /**
+        Converts the canvas to an image with straight alpha
+    */
+    fn finish(mut self) -> RgbaImage {
+        let layer = self.layers.swap_remove(0);
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let [r, g, b, a] = layer[(y * self.width + x) as usize];
+            if a <= 0.0 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            let channel = |c: f32| (c / a * 255.0).round().min(255.0) as u8;
+            Rgba([
+                channel(r),
+                channel(g),
+                channel(b),
+                (a * 255.0).round() as u8,
+            ])
+        })
+    }
+}
+
+impl<'f, 'a> Painter<'a> for ColrPainter<'f, 'a> {
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        let face = self.face;
+        self.outline = Some(self.rasterize(|rasterizer| {
+            face.outline_glyph(glyph_id, rasterizer);
+        }));
+    }
+
+    fn paint(&mut self, paint: Paint<'a>) {
+        let coverage = self.coverage();
+        let to_font = invert(self.transform());
+        let gradient = Gradient::new(&paint);
+        let width = self.width;
+        let layer = self.layers.last_mut().unwrap();
+        for (idx, (pixel, coverage)) in layer.iter_mut().zip(coverage).enumerate() {
+            if coverage <= 0.0 {
+                continue;
+            }
+            let colour = match &paint {
+                Paint::Solid(colour) => *colour,
+                _ => {
+                    let x = (idx as u32 % width) as f32 + 0.5;
+                    let y = (idx as u32 / width) as f32 + 0.5;
+                    let (fx, fy) = apply(&to_font, x, y);
+                    gradient.colour_at(&paint, fx, fy)
+                }
+            };
+            let alpha = f32::from(colour.alpha) / 255.0 * coverage.min(1.0);
+            let src = [
+                f32::from(colour.red) / 255.0 * alpha,
+                f32::from(colour.green) / 255.0 * alpha,
+                f32::from(colour.blue) / 255.0 * alpha,
+                alpha,
+            ];
+            for (dst, src) in pixel.iter_mut().zip(src.iter()) {
+                *dst = src + *dst * (1.0 - alpha);
+            }
+        }
+    }
+
+    fn push_clip(&mut self) {
+        // the outline becomes a clip, paints within it fill the whole clip
+        let clip = self
+            .outline
+            .take()
+            .unwrap_or_else(|| vec![1.0; (self.width * self.height) as usize]);
+        self.clips.push(clip);
+    }
+
+    fn push_clip_box(&mut self, clip_box: ClipBox) {
+        let clip = self.rasterize(|rasterizer| {
+            rasterizer.move_to(clip_box.x_min, clip_box.y_min);
+            rasterizer.line_to(clip_box.x_max, clip_box.y_min);
+            rasterizer.line_to(clip_box.x_max, clip_box.y_max);
+            rasterizer.line_to(clip_box.x_min, clip_box.y_max);
+            rasterizer.close();
+        });
+        self.clips.push(clip);
+    }
+
+    fn pop_clip(&mut self) {
+        self.clips.pop();
+    }
+
+    fn push_layer(&mut self, _mode: CompositeMode) {
+        self.layers
+            .push(vec![[0.0; 4]; (self.width * self.height) as usize]);
+    }
+
+    fn pop_layer(&mut self) {
+        if self.layers.len() < 2 {
+            return;
+        }
+        let layer = self.layers.pop().unwrap();
+        let below = self.layers.last_mut().unwrap();
+        for (dst, src) in below.iter_mut().zip(layer) {
+            let alpha = src[3];
+            for (dst, src) in dst.iter_mut().zip(src.iter()) {
+                *dst = src + *dst * (1.0 - alpha);
+            }
+        }
+    }
+
+    fn push_transform(&mut self, transform: Transform) {
+        let combined = Transform::combine(self.transform(), transform);
+        self.transforms.push(combined);
+    }
+
+    fn pop_transform(&mut self) {
+        if self.transforms.len() > 1 {
+            self.transforms.pop();
+        }
+    }
+}
+
+/**
+    The colour stops of a gradient paint, sorted by their offset
+*/
+struct Gradient {
+    stops: Vec<(f32, RgbaColor)>,
+}
+
+impl Gradient {
+    fn new(paint: &Paint) -> Gradient {
+        let coords = &[];
+        let mut stops: Vec<_> = match paint {
+            Paint::Solid(_) => Vec::new(),
+            Paint::LinearGradient(gradient) => gradient
+                .stops(0, coords)
+                .map(|stop| (stop.stop_offset, stop.color))
+                .collect(),
+            Paint::RadialGradient(gradient) => gradient
+                .stops(0, coords)
+                .map(|stop| (stop.stop_offset, stop.color))
+                .collect(),
+            Paint::SweepGradient(gradient) => gradient
+                .stops(0, coords)
+                .map(|stop| (stop.stop_offset, stop.color))
+                .collect(),
+        };
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Gradient { stops }
+    }
+
+    /**
+        The colour of the gradient at the point in font units
+    */
+    fn colour_at(&self, paint: &Paint, x: f32, y: f32) -> RgbaColor {
+        let (t, extend) = match paint {
+            Paint::Solid(colour) => return *colour,
+            Paint::LinearGradient(g) => {
+                // the gradient runs from p0 towards p1, perpendicular to the line from p0 to p2
+                let (dx, dy) = (g.x2 - g.x0, g.y2 - g.y0);
+                let (px, py) = (g.x1 - g.x0, g.y1 - g.y0);
+                let (nx, ny) = (dy, -dx);
+                let n_len = nx * nx + ny * ny;
+                let (vx, vy) = if n_len > 0.0 {
+                    let dot = (px * nx + py * ny) / n_len;
+                    (nx * dot, ny * dot)
+                } else {
+                    (px, py)
+                };
+                let len = vx * vx + vy * vy;
+                let t = if len > 0.0 {
+                    ((x - g.x0) * vx + (y - g.y0) * vy) / len
+                } else {
+                    0.0
+                };
+                (t, g.extend)
+            }
+            Paint::RadialGradient(g) => {
+                // the t for which the point lies on the interpolated circle
+                let (qx, qy) = (x - g.x0, y - g.y0);
+                let (cx, cy) = (g.x1 - g.x0, g.y1 - g.y0);
+                let dr = g.r1 - g.r0;
+                let a = cx * cx + cy * cy - dr * dr;
+                let b = qx * cx + qy * cy + g.r0 * dr;
+                let c = qx * qx + qy * qy - g.r0 * g.r0;
+                let t = if a.abs() < 1e-6 {
+                    if b.abs() < 1e-6 {
+                        0.0
+                    } else {
+                        c / (2.0 * b)
+                    }
+                } else {
+                    let discriminant = b * b - a * c;
+                    if discriminant < 0.0 {
+                        0.0
+                    } else {
+                        (b + discriminant.sqrt()) / a
+                    }
+                };
+                (t, g.extend)
+            }
+            Paint::SweepGradient(g) => {
+                let angle = (y - g.center_y).atan2(x - g.center_x).to_degrees();
+                let angle = if angle < 0.0 { angle + 360.0 } else { angle };
+                let (start, end) = (g.start_angle * 180.0, g.end_angle * 180.0);
+                let t = if (end - start).abs() > 0.0 {
+                    (angle - start) / (end - start)
+                } else {
+                    0.0
+                };
+                (t, g.extend)
+            }
+        };
+        self.colour_at_offset(extend_offset(t, extend))
+    }
+
+    fn colour_at_offset(&self, t: f32) -> RgbaColor {
+        let (first, last) = match (self.stops.first(), self.stops.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return RgbaColor::new(0, 0, 0, 0),
+        };
+        if t <= first.0 {
+            return first.1;
+        }
+        if t >= last.0 {
+            return last.1;
+        }
+        for pair in self.stops.windows(2) {
+            let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+            if t >= t0 && t <= t1 {
+                let f = if t1 > t0 { (t - t0) / (t1 - t0) } else { 0.0 };
+                let mix = |a: u8, b: u8| (f32::from(a) + (f32::from(b) - f32::from(a)) * f) as u8;
+                return RgbaColor::new(
+                    mix(c0.red, c1.red),
+                    mix(c0.green, c1.green),
+                    mix(c0.blue, c1.blue),
+                    mix(c0.alpha, c1.alpha),
+                );
+            }
+        }
+        last.1
+    }
+}
+
+fn extend_offset(t: f32, extend: GradientExtend) -> f32 {
+    match extend {
+        GradientExtend::Pad => t.clamp(0.0, 1.0),
+        GradientExtend::Repeat => t - t.floor(),
+        GradientExtend::Reflect => {
+            let t = t.abs() % 2.0;
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        }
+    }
+}
+
+fn apply(transform: &Transform, x: f32, y: f32) -> (f32, f32) {
+    (
+        transform.a * x + transform.c * y + transform.e,
+        transform.b * x + transform.d * y + transform.f,
+    )
+}
+
+fn invert(t: Transform) -> Transform {
+    let det = t.a * t.d - t.b * t.c;
+    if det.abs() < 1e-12 {
+        return Transform::default();
+    }
+    let a = t.d / det;
+    let b = -t.b / det;
+    let c = -t.c / det;
+    let d = t.a / det;
+    Transform::new(a, b, c, d, -(a * t.e + c * t.f), -(b * t.e + d * t.f))
+}
+
+/**
+    An antialiasing rasterizer for outlines in font units.
+    Every line adds its signed area to an accumulation buffer,
+    the running sum of the buffer is the coverage of each pixel.
+*/
+struct OutlineRasterizer {
+    width: u32,
+    height: u32,
+    transform: Transform,
+    accumulation: Vec<f32>,
+    start: (f32, f32),
+    last: (f32, f32),
+}
+
+impl OutlineRasterizer {
+    fn new(width: u32, height: u32, transform: Transform) -> OutlineRasterizer {
+        OutlineRasterizer {
+            width,
+            height,
+            transform,
+            accumulation: vec![0.0; (width * height) as usize + 2],
+            start: (0.0, 0.0),
+            last: (0.0, 0.0),
+        }
+    }
+
+    fn to_canvas(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x, y) = apply(&self.transform, x, y);
+        // clamping keeps every line within the buffer, the margin of the canvas makes it rare
+        (x.max(0.0).min(self.width as f32 - 1.001), y)
+    }
+
+    fn draw_line(&mut self, from: (f32, f32), to: (f32, f32)) {
+        if (from.1 - to.1).abs() < 1e-6 {
+            return;
+        }
+        let (dir, p0, p1) = if from.1 < to.1 {
+            (1.0, from, to)
+        } else {
+            (-1.0, to, from)
+        };
+        let dxdy = (p1.0 - p0.0) / (p1.1 - p0.1);
+        let mut x = p0.0;
+        if p0.1 < 0.0 {
+            x -= p0.1 * dxdy;
+        }
+        let width = self.width as usize;
+        let row_start = p0.1.max(0.0) as usize;
+        let row_end = (self.height as usize).min(p1.1.ceil().max(0.0) as usize);
+        for row in row_start..row_end {
+            let line_start = row * width;
+            let dy = ((row + 1) as f32).min(p1.1) - (row as f32).max(p0.1);
+            let x_next = x + dxdy * dy;
+            let d = dy * dir;
+            let (x0, x1) = if x < x_next { (x, x_next) } else { (x_next, x) };
+            let x0_floor = x0.floor();
+            let x0i = x0_floor as usize;
+            let x1_ceil = x1.ceil();
+            let x1i = x1_ceil as usize;
+            let acc = &mut self.accumulation;
+            if x1i <= x0i + 1 {
+                let xmf = 0.5 * (x + x_next) - x0_floor;
+                acc[line_start + x0i] += d - d * xmf;
+                acc[line_start + x0i + 1] += d * xmf;
+            } else {
+                let s = (x1 - x0).recip();
+                let x0f = x0 - x0_floor;
+                let a0 = 0.5 * s * (1.0 - x0f) * (1.0 - x0f);
+                let x1f = x1 - x1_ceil + 1.0;
+                let am = 0.5 * s * x1f * x1f;
+                acc[line_start + x0i] += d * a0;
+                if x1i == x0i + 2 {
+                    acc[line_start + x0i + 1] += d * (1.0 - a0 - am);
+                } else {
+                    let a1 = s * (1.5 - x0f);
+                    acc[line_start + x0i + 1] += d * (a1 - a0);
+                    for xi in x0i + 2..x1i - 1 {
+                        acc[line_start + xi] += d * s;
+                    }
+                    let a2 = a1 + (x1i - x0i - 3) as f32 * s;
+                    acc[line_start + x1i - 1] += d * (1.0 - a2 - am);
+                }
+                acc[line_start + x1i] += d * am;
+            }
+            x = x_next;
+        }
+    }
+
+    fn line_to_canvas(&mut self, to: (f32, f32)) {
+        let from = self.last;
+        self.draw_line(from, to);
+        self.last = to;
+    }
+
+    fn finish(mut self) -> Vec<f32> {
+        let mut sum = 0.0;
+        self.accumulation
+            .truncate((self.width * self.height) as usize);
+        for value in self.accumulation.iter_mut() {
+            sum += *value;
+            *value = sum.abs().min(1.0);
+        }
+        self.accumulation
+    }
+}
+
+impl OutlineBuilder for OutlineRasterizer {
+    fn move_to(&mut self, x: f32, y: f32) {
+        let point = self.to_canvas(x, y);
+        self.start = point;
+        self.last = point;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let to = self.to_canvas(x, y);
+        self.line_to_canvas(to);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (p0, p1, p2) = (self.last, self.to_canvas(x1, y1), self.to_canvas(x, y));
+        let steps = curve_steps(&[p0, p1, p2]);
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            let mt = 1.0 - t;
+            let point = (
+                mt * mt * p0.0 + 2.0 * mt * t * p1.0 + t * t * p2.0,
+                mt * mt * p0.1 + 2.0 * mt * t * p1.1 + t * t * p2.1,
+            );
+            self.line_to_canvas(point);
+        }
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let p0 = self.last;
+        let (p1, p2, p3) = (
+            self.to_canvas(x1, y1),
+            self.to_canvas(x2, y2),
+            self.to_canvas(x, y),
+        );
+        let steps = curve_steps(&[p0, p1, p2, p3]);
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            let mt = 1.0 - t;
+            let point = (
+                mt * mt * mt * p0.0
+                    + 3.0 * mt * mt * t * p1.0
+                    + 3.0 * mt * t * t * p2.0
+                    + t * t * t * p3.0,
+                mt * mt * mt * p0.1
+                    + 3.0 * mt * mt * t * p1.1
+                    + 3.0 * mt * t * t * p2.1
+                    + t * t * t * p3.1,
+            );
+            self.line_to_canvas(point);
+        }
+    }
+
+    fn close(&mut self) {
+        let start = self.start;
+        self.line_to_canvas(start);
+    }
+}
+
+/**
+    The number of lines a curve is flattened into, about one per two pixels of its control polygon
+*/
+fn curve_steps(points: &[(f32, f32)]) -> u32 {
+    let length: f32 = points
+        .windows(2)
+        .map(|pair| ((pair[1].0 - pair[0].0).powi(2) + (pair[1].1 - pair[0].1).powi(2)).sqrt())
+        .sum();
+    ((length / 2.0).ceil() as u32).clamp(1, 64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::FontRegistry;
+    use crate::rendering::Fontspec;
+    use crate::shaping::shape_text;
+    use crate::{draw_line, Line, MemegenError};
+    use image::DynamicImage;
+    use rusttype::point;
+
+    /**
+        The test fonts are written by `res/test/make_emoji_fonts.py`
+    */
+    fn emoji_fontspec() -> Fontspec {
+        let mut registry = FontRegistry::with_baked_fonts();
+        registry.load_file("res/test/emoji-colr.ttf").unwrap();
+        registry.load_file("res/test/emoji-cbdt.ttf").unwrap();
+        Fontspec {
+            fallbacks: registry.fallbacks(&["Test Emoji COLR", "Test Emoji CBDT"]),
+            ..Fontspec::impact()
+        }
+    }
+
+    #[test]
+    fn test_render_colr_glyph() {
+        let fontspec = emoji_fontspec();
+        let shaped = shape_text(&fontspec, "LOL 😀", point(0.0, 100.0));
+        assert_eq!(4, shaped.glyphs.len());
+        assert_eq!(1, shaped.colour_glyphs.len());
+
+        let placed = &shaped.colour_glyphs[0];
+        let glyph = placed.render().unwrap();
+        let (width, height) = glyph.image.dimensions();
+        // the glyph is measured by its layers without rendering it
+        assert!((placed.x_range.0 - glyph.x).abs() <= 1);
+        assert!((placed.x_range.1 - glyph.x - width as i32).abs() <= 1);
+        // the yellow layer is 90% of the em, which is scaled to the font size
+        let em = fontspec.scale.y;
+        assert!((width as f32 - 0.9 * em).abs() <= 2.0);
+        assert!((height as f32 - 0.9 * em).abs() <= 2.0);
+        assert_eq!([255, 220, 0, 255], glyph.image.get_pixel(2, 2).data);
+        assert_eq!(
+            [40, 40, 40, 255],
+            glyph.image.get_pixel(width / 2, height / 2).data
+        );
+    }
+
+    #[test]
+    fn test_render_cbdt_glyph() {
+        let fontspec = emoji_fontspec();
+        let shaped = shape_text(&fontspec, "⭐", point(10.0, 100.0));
+        assert!(shaped.glyphs.is_empty());
+        assert_eq!(1, shaped.colour_glyphs.len());
+
+        let placed = &shaped.colour_glyphs[0];
+        let glyph = placed.render().unwrap();
+        assert_eq!((glyph.x, glyph.x + glyph.image.width() as i32), placed.x_range);
+        // the 20 pixel strike is scaled to the em of the font size
+        let em = fontspec.scale.y;
+        assert_eq!(em as u32, glyph.image.width());
+        assert_eq!(10, glyph.x);
+        // the top of the bitmap is 16 of 20 pixels above the baseline
+        assert_eq!((100.0 - 0.8 * em).round() as i32, glyph.y);
+        let center = glyph
+            .image
+            .get_pixel(glyph.image.width() / 2, glyph.image.height() / 2);
+        assert_eq!([255, 0, 0, 255], center.data);
+    }
+
+    #[test]
+    fn test_with_empty_outlines() {
+        let data = std::fs::read("res/test/emoji-cbdt.ttf").unwrap();
+        assert!(rusttype::Font::from_bytes(data.clone()).is_err());
+        let face = ttf_parser::Face::parse(&data, 0).unwrap();
+        assert!(is_bitmap_only(&face));
+
+        let patched = with_empty_outlines(&data).unwrap();
+        let font = rusttype::Font::from_bytes(patched.clone()).unwrap();
+        assert_eq!(1, font.glyph('⭐').id().0);
+        let face = ttf_parser::Face::parse(&patched, 0).unwrap();
+        assert!(!is_bitmap_only(&face));
+        assert!(face.glyph_raster_image(GlyphId(1), 20).is_some());
+    }
+
+    #[test]
+    fn test_emoji_are_outlined() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(400, 150).to_rgba();
+        image
+            .pixels_mut()
+            .for_each(|pixel| pixel.data = [0, 0, 255, 255]);
+        let mut line = Line {
+            text: "⭐ WOW 😀".to_string(),
+            fontspec: emoji_fontspec(),
+            ..Line::default()
+        };
+        draw_line(&mut line, &mut image)?;
+        image.save("test_output/test_emoji_are_outlined.png")?;
+
+        // the star is drawn first, its red square is surrounded by the dark outline
+        let red = image
+            .enumerate_pixels()
+            .find(|(_, _, pixel)| pixel.data == [255, 0, 0, 255])
+            .map(|(x, y, _)| (x, y))
+            .unwrap();
+        assert!((1..6).any(|d| {
+            let pixel = image.get_pixel(red.0 - d, red.1 + 5);
+            pixel.data[0] < 32 && pixel.data[2] < 32
+        }));
+        assert_eq!(
+            [0, 0, 255, 255],
+            image.get_pixel(red.0 - 12, red.1 + 5).data
+        );
+        assert!(image.pixels().any(|pixel| pixel.data == [255, 220, 0, 255]));
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::fonts::FontError;
+
+/**
+    The errors of the library.
+    Bad input is reported with them instead of panicking, so an application can reject it.
+*/
+#[derive(Debug)]
+pub enum MemegenError {
+    /// An image could not be decoded or encoded
+    Image(image::ImageError),
+    Io(io::Error),
+    Font(FontError),
+    /// The image to draw on has no pixels
+    EmptyImage,
+    /// The font size is not a positive, finite number
+    InvalidScale(f32),
+    /// The outline width is negative, not a finite number or wider than the font size
+    InvalidOutline(f32),
+    /// A project file could not be read or written in its format
+    Project(String),
+    /// A template manifest could not be parsed
+    Template(String),
+    /// A caption was given for a slot the template does not have
+    UnknownSlot(String),
+    /// There is no template with the id
+    UnknownTemplate(String),
+    /// A meme link does not follow the URL scheme
+    Link(String),
+    /// The frame rate or duration of a caption animation is out of range
+    Effect(String),
+    /// An image cannot be exported with the options, e.g. a JPEG quality of 0
+    Export(String),
+}
+
+impl fmt::Display for MemegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemegenError::Image(err) => write!(f, "image error: {}", err),
+            MemegenError::Io(err) => write!(f, "io error: {}", err),
+            MemegenError::Font(err) => write!(f, "font error: {}", err),
+            MemegenError::EmptyImage => write!(f, "the image has no pixels"),
+            MemegenError::InvalidScale(scale) => write!(f, "invalid font size: {}", scale),
+            MemegenError::InvalidOutline(width) => write!(f, "invalid outline width: {}", width),
+            MemegenError::Project(msg) => write!(f, "invalid project file: {}", msg),
+            MemegenError::Template(msg) => write!(f, "invalid template: {}", msg),
+            MemegenError::UnknownSlot(slot) => write!(f, "the template has no slot {}", slot),
+            MemegenError::UnknownTemplate(id) => write!(f, "unknown template {}", id),
+            MemegenError::Link(msg) => write!(f, "invalid meme link: {}", msg),
+            MemegenError::Effect(msg) => write!(f, "invalid caption animation: {}", msg),
+            MemegenError::Export(msg) => write!(f, "could not export the image: {}", msg),
+        }
+    }
+}
+
+impl Error for MemegenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MemegenError::Image(err) => Some(err),
+            MemegenError::Io(err) => Some(err),
+            MemegenError::Font(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<image::ImageError> for MemegenError {
+    fn from(err: image::ImageError) -> MemegenError {
+        MemegenError::Image(err)
+    }
+}
+
+impl From<io::Error> for MemegenError {
+    fn from(err: io::Error) -> MemegenError {
+        MemegenError::Io(err)
+    }
+}
+
+impl From<FontError> for MemegenError {
+    fn from(err: FontError) -> MemegenError {
+        MemegenError::Font(err)
+    }
+}
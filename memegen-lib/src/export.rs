@@ -0,0 +1,509 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::jpeg::JPEGEncoder;
+use image::{ColorType, Rgba};
+use serde::{Deserialize, Serialize};
+
+use crate::animation::{png_error, Animation};
+use crate::error::MemegenError;
+use crate::layout::RgbaImage;
+use crate::metadata::{JPEG_APP1, JPEG_EXIF_HEADER};
+use crate::webp::encode_webp;
+
+/// The JPEG quality of the default export options
+pub const DEFAULT_JPEG_QUALITY: u8 = 90;
+/// The colour transparent pixels are put on for formats without an alpha channel
+const FLATTEN_COLOUR: [u8; 3] = [255, 255, 255];
+
+/**
+    The image formats memes can be exported as
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Png,
+    Jpeg,
+    /// Lossless WebP
+    WebP,
+    /// A still GIF of at most 255 colours
+    Gif,
+    Bmp,
+}
+
+impl ExportFormat {
+    /**
+        The format of a file extension like `jpg`, ignoring its case
+    */
+    pub fn from_extension(extension: &str) -> Option<ExportFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(ExportFormat::Png),
+            "jpg" | "jpeg" => Some(ExportFormat::Jpeg),
+            "webp" => Some(ExportFormat::WebP),
+            "gif" => Some(ExportFormat::Gif),
+            "bmp" => Some(ExportFormat::Bmp),
+            _ => None,
+        }
+    }
+
+    /**
+        The format of a file by its extension
+    */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ExportFormat> {
+        let extension = path.as_ref().extension()?.to_str()?;
+        ExportFormat::from_extension(extension)
+    }
+
+    /**
+        The usual file extension of the format
+    */
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::WebP => "webp",
+            ExportFormat::Gif => "gif",
+            ExportFormat::Bmp => "bmp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "image/png",
+            ExportFormat::Jpeg => "image/jpeg",
+            ExportFormat::WebP => "image/webp",
+            ExportFormat::Gif => "image/gif",
+            ExportFormat::Bmp => "image/bmp",
+        }
+    }
+
+    /**
+        Whether images of the format can be transparent
+    */
+    pub fn supports_alpha(self) -> bool {
+        self != ExportFormat::Jpeg
+    }
+
+    /**
+        Whether EXIF data can be stored in images of the format
+    */
+    pub fn supports_exif(self) -> bool {
+        match self {
+            ExportFormat::Png | ExportFormat::Jpeg | ExportFormat::WebP => true,
+            ExportFormat::Gif | ExportFormat::Bmp => false,
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<ExportFormat, String> {
+        ExportFormat::from_extension(name).ok_or_else(|| {
+            format!(
+                "unknown image format {}, expected png, jpeg, webp, gif or bmp",
+                name
+            )
+        })
+    }
+}
+
+/**
+    How hard PNG images are compressed, better compression takes longer
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    fn name(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Default => "default",
+            PngCompression::Best => "best",
+        }
+    }
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<PngCompression, String> {
+        match name {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            _ => Err(format!(
+                "unknown compression {}, expected fast, default or best",
+                name
+            )),
+        }
+    }
+}
+
+/**
+    How a rendered meme is written.
+    The settings of other formats than the chosen one are ignored.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// The JPEG quality from 1 to 100
+    pub jpeg_quality: u8,
+    pub png_compression: PngCompression,
+    /// Whether transparent pixels are kept, otherwise they are put on white like they always are for JPEG
+    pub preserve_alpha: bool,
+    /// Whether the EXIF data of the background is kept, for the formats that can store it
+    pub keep_exif: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions {
+            format: ExportFormat::Png,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            png_compression: PngCompression::Default,
+            preserve_alpha: true,
+            keep_exif: false,
+        }
+    }
+}
+
+impl ExportOptions {
+    /**
+        The default options for the format
+    */
+    pub fn new(format: ExportFormat) -> ExportOptions {
+        ExportOptions {
+            format,
+            ..ExportOptions::default()
+        }
+    }
+
+    /**
+        Identifies the format and the settings that change its images, e.g. in the keys of a `RenderCache`
+    */
+    pub fn cache_name(&self) -> String {
+        let mut name = self.format.extension().to_string();
+        match self.format {
+            ExportFormat::Jpeg => name.push_str(&format!(":{}", self.jpeg_quality)),
+            ExportFormat::Png => name.push_str(&format!(":{}", self.png_compression.name())),
+            _ => {}
+        }
+        if self.format.supports_alpha() && !self.preserve_alpha {
+            name.push_str(":opaque");
+        }
+        if self.format.supports_exif() && self.keep_exif {
+            name.push_str(":exif");
+        }
+        name
+    }
+
+    /**
+        Checks the settings of the chosen format, e.g. the JPEG quality is only checked for JPEG images
+    */
+    pub fn validate(&self) -> Result<(), MemegenError> {
+        if self.format == ExportFormat::Jpeg && !(1..=100).contains(&self.jpeg_quality) {
+            return Err(MemegenError::Export(format!(
+                "the JPEG quality must be between 1 and 100, not {}",
+                self.jpeg_quality
+            )));
+        }
+        Ok(())
+    }
+
+    /**
+        Encodes the image in the format of the options.
+        The EXIF data, e.g. from `Meme::load_exif`, is only written if it is to be kept.
+    */
+    pub fn encode(&self, image: &RgbaImage, exif: Option<&[u8]>) -> Result<Vec<u8>, MemegenError> {
+        self.validate()?;
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(MemegenError::EmptyImage);
+        }
+        let exif = exif.filter(|_| self.keep_exif);
+        let alpha = self.preserve_alpha && self.format.supports_alpha();
+        let flattened;
+        let image = if alpha {
+            image
+        } else {
+            flattened = flatten(image);
+            &flattened
+        };
+
+        let mut data = Vec::new();
+        match self.format {
+            ExportFormat::Png => self.encode_png(image, alpha, exif, &mut data)?,
+            ExportFormat::Jpeg => {
+                let rgb = rgb_pixels(image);
+                JPEGEncoder::new_with_quality(&mut data, self.jpeg_quality).encode(
+                    &rgb,
+                    width,
+                    height,
+                    ColorType::RGB(8),
+                )?;
+                if let Some(exif) = exif {
+                    data = insert_jpeg_exif(data, exif)?;
+                }
+            }
+            ExportFormat::WebP => data = encode_webp(image, exif)?,
+            ExportFormat::Gif => Animation::still(image.clone()).encode_gif(&mut data)?,
+            ExportFormat::Bmp => {
+                let mut encoder = image::bmp::BMPEncoder::new(&mut data);
+                if alpha {
+                    encoder.encode(image, width, height, ColorType::RGBA(8))?;
+                } else {
+                    encoder.encode(&rgb_pixels(image), width, height, ColorType::RGB(8))?;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /**
+        Encodes the image and writes it to the file, see `encode`
+    */
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        image: &RgbaImage,
+        exif: Option<&[u8]>,
+        path: P,
+    ) -> Result<(), MemegenError> {
+        let data = self.encode(image, exif)?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn encode_png(
+        &self,
+        image: &RgbaImage,
+        alpha: bool,
+        exif: Option<&[u8]>,
+        data: &mut Vec<u8>,
+    ) -> Result<(), MemegenError> {
+        let (width, height) = image.dimensions();
+        let mut encoder = png::Encoder::new(data, width, height);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(match self.png_compression {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Default => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        });
+        encoder.set_color(if alpha {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        });
+        let mut writer = encoder.write_header().map_err(png_error)?;
+        // the EXIF data has to come before the image data
+        if let Some(exif) = exif {
+            writer
+                .write_chunk(png::chunk::eXIf, exif)
+                .map_err(png_error)?;
+        }
+        if alpha {
+            writer.write_image_data(image).map_err(png_error)?;
+        } else {
+            writer
+                .write_image_data(&rgb_pixels(image))
+                .map_err(png_error)?;
+        }
+        writer.finish().map_err(png_error)
+    }
+}
+
+/**
+    Puts the EXIF data into an APP1 segment after the JFIF header the encoder starts the image with
+*/
+fn insert_jpeg_exif(data: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>, MemegenError> {
+    let length = 2 + JPEG_EXIF_HEADER.len() + exif.len();
+    if length > usize::from(u16::MAX) {
+        return Err(MemegenError::Export(format!(
+            "{} bytes of EXIF data do not fit into a JPEG segment",
+            exif.len()
+        )));
+    }
+    let mut pos = 2;
+    if data.get(2..4) == Some(&[0xff, 0xe0]) {
+        pos += 2 + usize::from(u16::from_be_bytes([data[4], data[5]]));
+    }
+    let mut exported = Vec::with_capacity(data.len() + length + 2);
+    exported.extend_from_slice(&data[..pos]);
+    exported.extend_from_slice(&[0xff, JPEG_APP1]);
+    exported.extend_from_slice(&(length as u16).to_be_bytes());
+    exported.extend_from_slice(JPEG_EXIF_HEADER);
+    exported.extend_from_slice(exif);
+    exported.extend_from_slice(&data[pos..]);
+    Ok(exported)
+}
+
+/**
+    Puts the image on a white background, so every pixel is opaque
+*/
+fn flatten(image: &RgbaImage) -> RgbaImage {
+    let mut flattened = image.clone();
+    for pixel in flattened.pixels_mut() {
+        let alpha = u32::from(pixel.data[3]);
+        let mut data = [0, 0, 0, 255];
+        for channel in 0..3 {
+            let value = u32::from(pixel.data[channel]) * alpha
+                + u32::from(FLATTEN_COLOUR[channel]) * (255 - alpha);
+            data[channel] = ((value + 127) / 255) as u8;
+        }
+        *pixel = Rgba(data);
+    }
+    flattened
+}
+
+fn rgb_pixels(image: &RgbaImage) -> Vec<u8> {
+    image
+        .pixels()
+        .flat_map(|pixel| pixel.data[0..3].to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::read_exif;
+
+    /// A minimal TIFF structure with no entries, as stored in EXIF data
+    const EXIF: &[u8] = b"II*\0\x08\0\0\0\0\0\0\0\0\0";
+
+    fn image() -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(40, 30, Rgba([200, 40, 10, 255]));
+        for x in 0..20 {
+            image.put_pixel(x, 0, Rgba([0, 0, 0, 0]));
+        }
+        image
+    }
+
+    #[test]
+    fn test_export_formats() -> Result<(), MemegenError> {
+        for format in [
+            ExportFormat::Png,
+            ExportFormat::Jpeg,
+            ExportFormat::Gif,
+            ExportFormat::Bmp,
+        ]
+        .iter()
+        {
+            let options = ExportOptions::new(*format);
+            let data = options.encode(&image(), None)?;
+            let decoded = image::load_from_memory(&data)?.to_rgba();
+            assert_eq!((40, 30), decoded.dimensions());
+            let transparent = decoded.get_pixel(0, 0).data[3];
+            assert_eq!(format.supports_alpha(), transparent == 0, "{:?}", format);
+            options.save(
+                &image(),
+                None,
+                format!("test_output/test_export.{}", format.extension()),
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_without_alpha() -> Result<(), MemegenError> {
+        let options = ExportOptions {
+            preserve_alpha: false,
+            ..ExportOptions::new(ExportFormat::Png)
+        };
+        let decoded = image::load_from_memory(&options.encode(&image(), None)?)?.to_rgba();
+        assert_eq!([255, 255, 255, 255], decoded.get_pixel(0, 0).data);
+        assert_eq!([200, 40, 10, 255], decoded.get_pixel(30, 20).data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jpeg_quality() -> Result<(), MemegenError> {
+        let mut image = image();
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Rgba([(x * 6) as u8, (y * 8) as u8, ((x + y) * 3) as u8, 255]);
+        }
+        let size = |quality: u8| {
+            ExportOptions {
+                jpeg_quality: quality,
+                ..ExportOptions::new(ExportFormat::Jpeg)
+            }
+            .encode(&image, None)
+            .map(|data| data.len())
+        };
+        assert!(size(20)? < size(95)?);
+        assert!(size(0).is_err());
+        assert!(size(101).is_err());
+        // the quality of other formats is ignored
+        let png = ExportOptions {
+            jpeg_quality: 0,
+            ..ExportOptions::new(ExportFormat::Png)
+        };
+        png.encode(&image, None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_exif() -> Result<(), MemegenError> {
+        for format in [ExportFormat::Png, ExportFormat::Jpeg, ExportFormat::WebP].iter() {
+            let kept = ExportOptions {
+                keep_exif: true,
+                ..ExportOptions::new(*format)
+            };
+            let data = kept.encode(&image(), Some(EXIF))?;
+            assert_eq!(Some(EXIF.to_vec()), read_exif(&data), "{:?}", format);
+            let stripped = ExportOptions::new(*format).encode(&image(), Some(EXIF))?;
+            assert_eq!(None, read_exif(&stripped), "{:?}", format);
+        }
+        // the segment is inserted into a JPEG that still decodes
+        let kept = ExportOptions {
+            keep_exif: true,
+            ..ExportOptions::new(ExportFormat::Jpeg)
+        };
+        image::load_from_memory(&kept.encode(&image(), Some(EXIF))?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_webp() -> Result<(), MemegenError> {
+        let data = ExportOptions::new(ExportFormat::WebP).encode(&image(), None)?;
+        assert_eq!(b"RIFF", &data[0..4]);
+        assert_eq!(
+            data.len() - 8,
+            u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize
+        );
+        assert_eq!(b"WEBPVP8L", &data[8..16]);
+        // the signature, then the width and height less one in 14 bits each, then the alpha flag
+        let header = u32::from_le_bytes([data[21], data[22], data[23], data[24]]);
+        assert_eq!(0x2f, data[20]);
+        assert_eq!(39, header & 0x3fff);
+        assert_eq!(29, (header >> 14) & 0x3fff);
+        assert_eq!(1, (header >> 28) & 1);
+        let (decoded, _) = crate::webp::tests::decode_webp(&data);
+        assert_eq!(image().into_raw(), decoded.into_raw());
+        fs::write("test_output/test_export.webp", data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_format_names() {
+        assert_eq!(
+            Some(ExportFormat::Jpeg),
+            ExportFormat::from_path("meme.JPEG")
+        );
+        assert_eq!(None, ExportFormat::from_path("meme"));
+        assert_eq!(Ok(ExportFormat::WebP), "webp".parse());
+        assert_eq!("png:default", ExportOptions::default().cache_name());
+        let options = ExportOptions {
+            preserve_alpha: false,
+            keep_exif: true,
+            ..ExportOptions::new(ExportFormat::Jpeg)
+        };
+        assert_eq!("jpg:90:exif", options.cache_name());
+    }
+}
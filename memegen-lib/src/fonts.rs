@@ -0,0 +1,549 @@
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use rusttype::{Font, FontCollection, Scale, SharedBytes};
+use ttf_parser::name_id;
+
+use crate::emoji::{is_bitmap_only, with_empty_outlines};
+use crate::glyph_cache::{next_font_id, GlyphCache};
+use crate::rendering::{FontDataSelector, FontSource, Fontspec};
+
+/// The file extensions that are considered when scanning font directories
+const FONT_EXTENSIONS: [&str; 4] = ["ttf", "otf", "ttc", "otc"];
+/// Parts of the file names of the usual emoji fonts, like `NotoColorEmoji.ttf` or `seguiemj.ttf` on Windows
+const EMOJI_FONT_NAMES: [&str; 2] = ["emoji", "seguiemj"];
+
+/// The colour emoji faces installed on the system, they are only looked up once
+static INSTALLED_EMOJI_FACES: OnceLock<Vec<FontFace>> = OnceLock::new();
+
+/**
+    A single font face, e.g. the bold style of a font family.
+*/
+#[derive(Clone)]
+pub struct FontFace {
+    pub family: String,
+    pub style: String,
+    pub font: Font<'static>,
+    pub source: FontSource,
+    /**
+        The file the face was loaded from, if it was not baked into the binary
+    */
+    pub path: Option<PathBuf>,
+}
+
+impl FontFace {
+    /**
+        Whether the face has colour glyphs like emoji in its `COLR`, `CBDT` or `sbix` tables
+    */
+    pub fn has_colour_glyphs(&self) -> bool {
+        ttf_parser::Face::parse(&self.source.data, self.source.index)
+            .map(|face| {
+                let tables = face.tables();
+                tables.colr.is_some() || tables.cbdt.is_some() || tables.sbix.is_some()
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+pub enum FontError {
+    Io(io::Error),
+    /// The data could not be parsed as a TrueType or OpenType font (collection)
+    InvalidFont,
+    /// There is no face of the family, or not in the requested style
+    NotFound(String),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Io(err) => write!(f, "could not read font: {}", err),
+            FontError::InvalidFont => write!(f, "not a valid font file"),
+            FontError::NotFound(family) => write!(f, "font not found: {}", family),
+        }
+    }
+}
+
+impl Error for FontError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FontError::Io(err) => Some(err),
+            FontError::InvalidFont | FontError::NotFound(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for FontError {
+    fn from(err: io::Error) -> FontError {
+        FontError::Io(err)
+    }
+}
+
+/**
+    A collection of font faces that can be looked up by their family and style names.
+    Fonts can be loaded from single files, directories or the fonts installed on the system.
+    The fontspecs of a registry share its glyph cache, clones of the registry share it as well.
+*/
+#[derive(Clone)]
+pub struct FontRegistry {
+    faces: Vec<FontFace>,
+    glyph_cache: Option<Arc<GlyphCache>>,
+}
+
+impl Default for FontRegistry {
+    fn default() -> FontRegistry {
+        FontRegistry {
+            faces: Vec::new(),
+            glyph_cache: Some(Arc::new(GlyphCache::new())),
+        }
+    }
+}
+
+impl FontRegistry {
+    pub fn new() -> FontRegistry {
+        FontRegistry::default()
+    }
+
+    pub fn glyph_cache(&self) -> Option<&Arc<GlyphCache>> {
+        self.glyph_cache.as_ref()
+    }
+
+    /**
+        Replaces the glyph cache the fontspecs are created with, `None` disables caching
+    */
+    pub fn set_glyph_cache(&mut self, glyph_cache: Option<Arc<GlyphCache>>) {
+        self.glyph_cache = glyph_cache;
+    }
+
+    /**
+        Creates a registry that contains the fonts baked into the library
+    */
+    pub fn with_baked_fonts() -> FontRegistry {
+        let mut registry = FontRegistry::new();
+        for selector in [
+            FontDataSelector::ImpactFontData,
+            FontDataSelector::RobotoFontData,
+        ]
+        .iter()
+        {
+            if let Ok(fontspec) = Fontspec::baked(selector.clone()) {
+                registry.faces.push(FontFace {
+                    family: fontspec.family,
+                    style: fontspec.style,
+                    font: fontspec.font,
+                    source: fontspec.source,
+                    path: None,
+                });
+            }
+        }
+        registry
+    }
+
+    /**
+        Loads all faces of a font file, returning the number of faces that were added
+    */
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, FontError> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let faces = parse_faces(data, Some(path.to_path_buf()))?;
+        let count = faces.len();
+        self.faces.extend(faces);
+        Ok(count)
+    }
+
+    /**
+        Loads all faces of the font data, returning the number of faces that were added
+    */
+    pub fn load_bytes(&mut self, data: Vec<u8>) -> Result<usize, FontError> {
+        let faces = parse_faces(data, None)?;
+        let count = faces.len();
+        self.faces.extend(faces);
+        Ok(count)
+    }
+
+    /**
+        Recursively loads all font files in the directory.
+        Files that cannot be read or parsed are skipped, the number of added faces is returned.
+        Linked directories are followed, but every directory is only loaded once.
+    */
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> usize {
+        self.load_dir_once(dir.as_ref(), is_font_file, &mut HashSet::new())
+    }
+
+    /**
+        Loads the font files in the directory and below it for which `matches` is true
+    */
+    fn load_dir_once(
+        &mut self,
+        dir: &Path,
+        matches: fn(&Path) -> bool,
+        visited: &mut HashSet<PathBuf>,
+    ) -> usize {
+        // symbolic links may lead back into a directory that is being loaded
+        let is_new = fs::canonicalize(dir).map(|canonical| visited.insert(canonical));
+        if !is_new.unwrap_or(false) {
+            return 0;
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+        let mut count = 0;
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                count += self.load_dir_once(&path, matches, visited);
+            } else if matches(&path) {
+                count += self.load_file(&path).unwrap_or(0);
+            }
+        }
+        count
+    }
+
+    /**
+        Loads the fonts from all directories returned by `system_font_dirs`
+    */
+    pub fn load_system_fonts(&mut self) -> usize {
+        system_font_dirs()
+            .iter()
+            .map(|dir| self.load_dir(dir))
+            .sum()
+    }
+
+    /**
+        Finds a face by its family name and optionally its style, ignoring case.
+        Without a style, the regular face is preferred over any other face of the family.
+    */
+    pub fn find(&self, family: &str, style: Option<&str>) -> Option<&FontFace> {
+        let mut candidates = self
+            .faces
+            .iter()
+            .filter(|face| face.family.eq_ignore_ascii_case(family));
+        match style {
+            Some(style) => candidates.find(|face| face.style.eq_ignore_ascii_case(style)),
+            None => {
+                let candidates: Vec<_> = candidates.collect();
+                candidates
+                    .iter()
+                    .find(|face| is_regular_style(&face.style))
+                    .or_else(|| candidates.first())
+                    .cloned()
+            }
+        }
+    }
+
+    /**
+        Creates a fontspec with the default size and colours for the face
+    */
+    pub fn fontspec(&self, family: &str, style: Option<&str>) -> Option<Fontspec> {
+        self.find(family, style).map(|face| Fontspec {
+            font: face.font.clone(),
+            source: face.source.clone(),
+            family: face.family.clone(),
+            style: face.style.clone(),
+            scale: Scale::uniform(64.0),
+            colour_main: (255, 255, 255),
+            colour_outline: (0, 0, 0),
+            outline_width: None,
+            fallbacks: self.emoji_fallbacks(),
+            glyph_cache: self.glyph_cache.clone(),
+        })
+    }
+
+    /**
+        Looks up the regular faces of the families, to be used as `Fontspec::fallbacks`.
+        Families that are not in the registry are skipped.
+    */
+    pub fn fallbacks(&self, families: &[&str]) -> Vec<FontFace> {
+        families
+            .iter()
+            .filter_map(|family| self.find(family, None))
+            .cloned()
+            .collect()
+    }
+
+    /**
+        The faces with colour glyphs, e.g. the installed emoji fonts.
+        Appended to `Fontspec::fallbacks`, emoji are drawn in colour within the text.
+    */
+    pub fn colour_faces(&self) -> Vec<FontFace> {
+        self.faces
+            .iter()
+            .filter(|face| face.has_colour_glyphs())
+            .cloned()
+            .collect()
+    }
+
+    /**
+        The fallbacks every fontspec of the registry starts with, so emoji are drawn in colour:
+        the colour faces of the registry, then the installed emoji faces that are not in it.
+    */
+    pub fn emoji_fallbacks(&self) -> Vec<FontFace> {
+        let mut faces = self.colour_faces();
+        for face in installed_emoji_faces() {
+            if !faces.iter().any(|known| known.family == face.family) {
+                faces.push(face.clone());
+            }
+        }
+        faces
+    }
+
+    /**
+        The sorted family names of all faces without duplicates
+    */
+    pub fn families(&self) -> Vec<&str> {
+        let mut families: Vec<&str> = self.faces.iter().map(|face| face.family.as_str()).collect();
+        families.sort();
+        families.dedup();
+        families
+    }
+
+    pub fn faces(&self) -> &[FontFace] {
+        &self.faces
+    }
+}
+
+/**
+    The directories fonts are usually installed to on the current platform.
+    On Linux these are the fontconfig default locations including the XDG data directories.
+*/
+pub fn system_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let home = env::var_os("HOME").map(PathBuf::from);
+    if cfg!(target_os = "windows") {
+        let windir = env::var_os("WINDIR").unwrap_or_else(|| "C:\\Windows".into());
+        dirs.push(PathBuf::from(windir).join("Fonts"));
+        if let Some(local) = env::var_os("LOCALAPPDATA") {
+            dirs.push(PathBuf::from(local).join("Microsoft\\Windows\\Fonts"));
+        }
+    } else if cfg!(target_os = "macos") {
+        dirs.push(PathBuf::from("/System/Library/Fonts"));
+        dirs.push(PathBuf::from("/Library/Fonts"));
+        if let Some(home) = &home {
+            dirs.push(home.join("Library/Fonts"));
+        }
+    } else {
+        let data_dirs = env::var("XDG_DATA_DIRS")
+            .unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+        for data_dir in data_dirs.split(':').filter(|dir| !dir.is_empty()) {
+            dirs.push(Path::new(data_dir).join("fonts"));
+        }
+        match env::var_os("XDG_DATA_HOME") {
+            Some(data_home) => dirs.push(PathBuf::from(data_home).join("fonts")),
+            None => {
+                if let Some(home) = &home {
+                    dirs.push(home.join(".local/share/fonts"));
+                }
+            }
+        }
+        if let Some(home) = &home {
+            dirs.push(home.join(".fonts"));
+        }
+    }
+    dirs
+}
+
+fn parse_faces(data: Vec<u8>, path: Option<PathBuf>) -> Result<Vec<FontFace>, FontError> {
+    // emoji fonts often only have bitmaps, which rusttype cannot load without any outlines
+    let bitmap_only = ttf_parser::Face::parse(&data, 0)
+        .map(|face| is_bitmap_only(&face))
+        .unwrap_or(false);
+    let data = if bitmap_only {
+        with_empty_outlines(&data).ok_or(FontError::InvalidFont)?
+    } else {
+        data
+    };
+    let data: Arc<[u8]> = data.into();
+    let face_count = ttf_parser::fonts_in_collection(&data).unwrap_or(1);
+    let collection =
+        FontCollection::from_bytes(data.clone()).map_err(|_| FontError::InvalidFont)?;
+
+    let mut faces = Vec::new();
+    for index in 0..face_count {
+        let names = ttf_parser::Face::parse(&data, index)
+            .ok()
+            .map(|face| read_names(&face));
+        let font = collection.font_at(index as usize).ok();
+        if let (Some((family, style)), Some(font)) = (names, font) {
+            faces.push(FontFace {
+                family,
+                style,
+                font,
+                source: FontSource {
+                    data: SharedBytes::ByArc(data.clone()),
+                    index,
+                    id: next_font_id(),
+                },
+                path: path.clone(),
+            });
+        }
+    }
+    if faces.is_empty() {
+        return Err(FontError::InvalidFont);
+    }
+    Ok(faces)
+}
+
+/**
+    Reads the family and style name, preferring the typographic names if present
+*/
+fn read_names(face: &ttf_parser::Face) -> (String, String) {
+    let lookup = |ids: &[u16]| {
+        ids.iter().find_map(|id| {
+            face.names()
+                .into_iter()
+                .filter(|name| name.name_id == *id)
+                .find_map(|name| name.to_string())
+        })
+    };
+    let family = lookup(&[name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY]).unwrap_or_default();
+    let style = lookup(&[name_id::TYPOGRAPHIC_SUBFAMILY, name_id::SUBFAMILY])
+        .unwrap_or_else(|| "Regular".to_string());
+    (family, style)
+}
+
+/**
+    The colour emoji faces installed in the `system_font_dirs`.
+    Only the font files named like emoji fonts are read, the first call looks them up.
+*/
+pub fn installed_emoji_faces() -> &'static [FontFace] {
+    INSTALLED_EMOJI_FACES.get_or_init(|| {
+        let mut registry = FontRegistry::new();
+        let mut visited = HashSet::new();
+        for dir in system_font_dirs() {
+            registry.load_dir_once(&dir, is_emoji_font_file, &mut visited);
+        }
+        registry.colour_faces()
+    })
+}
+
+fn is_emoji_font_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    is_font_file(path) && EMOJI_FONT_NAMES.iter().any(|part| name.contains(part))
+}
+
+fn is_font_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            FONT_EXTENSIONS
+                .iter()
+                .any(|font_ext| ext.eq_ignore_ascii_case(font_ext))
+        })
+        .unwrap_or(false)
+}
+
+fn is_regular_style(style: &str) -> bool {
+    ["regular", "normal", "book", "roman"]
+        .iter()
+        .any(|regular| style.eq_ignore_ascii_case(regular))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fonts::{installed_emoji_faces, system_font_dirs, FontError, FontRegistry};
+    use crate::rendering::Fontspec;
+
+    #[test]
+    fn test_load_dir() {
+        let mut registry = FontRegistry::new();
+        assert_eq!(2, registry.load_dir("res/fonts"));
+        assert_eq!(vec!["Impact", "Roboto"], registry.families());
+
+        let face = registry.find("impact", None).unwrap();
+        assert_eq!("Impact", face.family);
+        assert!(face.path.as_ref().unwrap().ends_with("impact.ttf"));
+        assert!(registry.find("roboto", Some("regular")).is_some());
+        assert!(registry.find("roboto", Some("Bold Italic")).is_none());
+        assert!(registry.find("Comic Sans MS", None).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_dir_with_link_loop() {
+        let dir = std::path::Path::new("test_output/font_link_loop");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir.join("fonts")).unwrap();
+        std::fs::copy("res/fonts/impact.ttf", dir.join("fonts/impact.ttf")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("fonts/parent")).unwrap();
+        std::os::unix::fs::symlink("fonts", dir.join("linked")).unwrap();
+
+        let mut registry = FontRegistry::new();
+        assert_eq!(1, registry.load_dir(dir));
+        assert_eq!(vec!["Impact"], registry.families());
+    }
+
+    #[test]
+    fn test_load_invalid_file() {
+        let mut registry = FontRegistry::new();
+        match registry.load_file("res/images/puffin.jpg") {
+            Err(FontError::InvalidFont) => {}
+            _ => panic!("A jpeg is not a font"),
+        }
+        match registry.load_file("res/fonts/missing.ttf") {
+            Err(FontError::Io(_)) => {}
+            _ => panic!("The font file does not exist"),
+        }
+        assert!(registry.faces().is_empty());
+    }
+
+    #[test]
+    fn test_fontspec_from_registry() {
+        let registry = FontRegistry::with_baked_fonts();
+        let fontspec = registry.fontspec("Roboto", None).unwrap();
+        assert_eq!("Roboto", fontspec.family);
+        assert_eq!(64.0, fontspec.scale.x);
+    }
+
+    #[test]
+    fn test_colour_faces() {
+        let mut registry = FontRegistry::with_baked_fonts();
+        assert!(registry.colour_faces().is_empty());
+        // the bitmap font without outlines can be loaded as well
+        assert_eq!(3, registry.load_dir("res/test"));
+        let families: Vec<_> = registry
+            .colour_faces()
+            .into_iter()
+            .map(|face| face.family)
+            .collect();
+        assert_eq!(2, families.len());
+        assert!(families.contains(&"Test Emoji CBDT".to_string()));
+    }
+
+    #[test]
+    fn test_emoji_fallbacks() {
+        let mut registry = FontRegistry::with_baked_fonts();
+        registry.load_dir("res/test");
+        let fallbacks: Vec<String> = registry
+            .fontspec("Impact", None)
+            .unwrap()
+            .fallbacks
+            .into_iter()
+            .map(|face| face.family)
+            .collect();
+        assert!(fallbacks.contains(&"Test Emoji COLR".to_string()));
+        assert!(fallbacks.contains(&"Test Emoji CBDT".to_string()));
+        assert!(!fallbacks.contains(&"Test RTL".to_string()));
+        // installed emoji fonts are added to the fonts baked into the library as well
+        assert!(installed_emoji_faces().iter().all(|face| face.has_colour_glyphs()));
+        assert_eq!(installed_emoji_faces().len(), Fontspec::impact().fallbacks.len());
+    }
+
+    #[test]
+    fn test_system_font_dirs() {
+        let dirs = system_font_dirs();
+        assert!(!dirs.is_empty());
+        // scanning must not fail, even if there are no fonts installed
+        FontRegistry::new().load_system_fonts();
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rusttype::{point, PositionedGlyph};
+
+/// The number of positions within a pixel that glyphs are rasterized at, per axis
+const SUBPIXEL_STEPS: f32 = 8.0;
+
+/// The number of glyphs a cache holds before it is cleared
+pub const DEFAULT_GLYPH_CACHE_CAPACITY: usize = 4096;
+
+/**
+    Identifies the data of a font face, see `FontSource::id`.
+    The baked fonts have fixed ids, every face that is loaded gets a new one.
+*/
+pub type FontId = u64;
+
+/// The number of ids reserved for the baked fonts
+pub(crate) const BAKED_FONT_IDS: FontId = 2;
+
+static NEXT_FONT_ID: AtomicU64 = AtomicU64::new(BAKED_FONT_IDS);
+
+/**
+    A new id for a loaded font face
+*/
+pub(crate) fn next_font_id() -> FontId {
+    NEXT_FONT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    font: FontId,
+    glyph: u32,
+    /// The bits of the horizontal and vertical scale
+    scale: (u32, u32),
+    /// The offset of the glyph within its pixel, in steps of `1 / SUBPIXEL_STEPS`
+    subpixel: (u8, u8),
+}
+
+/**
+    The antialiased coverage of a rasterized glyph.
+    `x` and `y` are the offset of its top left corner from the pixel the glyph is positioned in.
+*/
+#[derive(Debug)]
+pub(crate) struct GlyphCoverage {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>,
+}
+
+/**
+    A glyph coverage placed on the target image, at the pixel (`x`, `y`)
+*/
+pub(crate) struct PlacedCoverage {
+    pub x: i32,
+    pub y: i32,
+    pub coverage: Arc<GlyphCoverage>,
+}
+
+/**
+    A thread-safe cache of rasterized glyphs, keyed by font, glyph, scale and subpixel offset.
+    Glyphs are rasterized at the nearest of a few subpixel offsets, so repeated glyphs can be reused
+    wherever they are positioned. A cache is shared by cloning the `Arc` it is kept in,
+    e.g. all fontspecs created by a `FontRegistry` use the cache of the registry.
+*/
+#[derive(Debug)]
+pub struct GlyphCache {
+    capacity: usize,
+    glyphs: RwLock<HashMap<GlyphKey, Arc<GlyphCoverage>>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl Default for GlyphCache {
+    fn default() -> GlyphCache {
+        GlyphCache::with_capacity(DEFAULT_GLYPH_CACHE_CAPACITY)
+    }
+}
+
+impl GlyphCache {
+    pub fn new() -> GlyphCache {
+        GlyphCache::default()
+    }
+
+    /**
+        A cache that holds up to `capacity` glyphs, once it is full it is cleared
+    */
+    pub fn with_capacity(capacity: usize) -> GlyphCache {
+        GlyphCache {
+            capacity,
+            glyphs: RwLock::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of glyphs in the cache
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How often a glyph was found in the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How often a glyph had to be rasterized
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.write().clear();
+    }
+
+    /**
+        The coverage of the glyph of the font, rasterized at the nearest subpixel offset.
+        Glyphs without any outline have no coverage.
+    */
+    pub(crate) fn rasterize(
+        &self,
+        font: FontId,
+        glyph: &PositionedGlyph<'static>,
+    ) -> Option<PlacedCoverage> {
+        let position = glyph.position();
+        let (pixel_x, step_x) = quantize(position.x);
+        let (pixel_y, step_y) = quantize(position.y);
+        let scale = glyph.scale();
+        let key = GlyphKey {
+            font,
+            glyph: glyph.id().0,
+            scale: (scale.x.to_bits(), scale.y.to_bits()),
+            subpixel: (step_x, step_y),
+        };
+
+        let cached = self.read().get(&key).cloned();
+        let coverage = match cached {
+            Some(coverage) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                coverage
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                // the glyph is rasterized without holding the lock, so other threads are not blocked
+                let offset = point(
+                    f32::from(step_x) / SUBPIXEL_STEPS,
+                    f32::from(step_y) / SUBPIXEL_STEPS,
+                );
+                let placed = rasterize(&glyph.unpositioned().clone().positioned(offset))?;
+                let mut glyphs = self.write();
+                if glyphs.len() >= self.capacity {
+                    glyphs.clear();
+                }
+                glyphs.insert(key, placed.coverage.clone());
+                placed.coverage
+            }
+        };
+        Some(PlacedCoverage {
+            x: pixel_x + coverage.x,
+            y: pixel_y + coverage.y,
+            coverage,
+        })
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashMap<GlyphKey, Arc<GlyphCoverage>>> {
+        // the map is consistent even if a thread panicked while holding the lock
+        self.glyphs.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<GlyphKey, Arc<GlyphCoverage>>> {
+        self.glyphs.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/**
+    Rasterizes the glyph at its exact position, without any cache
+*/
+pub(crate) fn rasterize(glyph: &PositionedGlyph<'static>) -> Option<PlacedCoverage> {
+    let bounding_box = glyph.pixel_bounding_box()?;
+    let width = bounding_box.width() as u32;
+    let height = bounding_box.height() as u32;
+    let mut data = vec![0.0; (width * height) as usize];
+    glyph.draw(|x, y, v| data[(y * width + x) as usize] = v);
+    let position = glyph.position();
+    let (pixel_x, pixel_y) = (position.x.floor() as i32, position.y.floor() as i32);
+    Some(PlacedCoverage {
+        x: bounding_box.min.x,
+        y: bounding_box.min.y,
+        coverage: Arc::new(GlyphCoverage {
+            x: bounding_box.min.x - pixel_x,
+            y: bounding_box.min.y - pixel_y,
+            width,
+            height,
+            data,
+        }),
+    })
+}
+
+/**
+    Splits a coordinate into its pixel and the nearest subpixel step within that pixel
+*/
+fn quantize(coordinate: f32) -> (i32, u8) {
+    let pixel = coordinate.floor();
+    let step = ((coordinate - pixel) * SUBPIXEL_STEPS).round();
+    if step >= SUBPIXEL_STEPS {
+        (pixel as i32 + 1, 0)
+    } else {
+        (pixel as i32, step as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::Fontspec;
+    use rusttype::Scale;
+    use std::thread;
+
+    fn glyph(c: char, x: f32, y: f32) -> PositionedGlyph<'static> {
+        Fontspec::impact()
+            .font
+            .glyph(c)
+            .scaled(Scale::uniform(48.0))
+            .positioned(point(x, y))
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!((3, 0), quantize(3.01));
+        assert_eq!((3, 4), quantize(3.5));
+        assert_eq!((4, 0), quantize(3.99));
+        assert_eq!((-2, 6), quantize(-1.25));
+    }
+
+    #[test]
+    fn test_cached_glyphs_are_reused() {
+        let cache = GlyphCache::new();
+        let first = cache.rasterize(0, &glyph('M', 10.0, 50.0)).unwrap();
+        // the same glyph in another pixel, at almost the same subpixel offset
+        let second = cache.rasterize(0, &glyph('M', 110.01, 80.0)).unwrap();
+        assert_eq!(1, cache.misses());
+        assert_eq!(1, cache.hits());
+        assert!(Arc::ptr_eq(&first.coverage, &second.coverage));
+        assert_eq!((first.x + 100, first.y + 30), (second.x, second.y));
+
+        // other fonts, offsets and glyphs are rasterized on their own
+        cache.rasterize(1, &glyph('M', 10.0, 50.0));
+        cache.rasterize(0, &glyph('M', 10.5, 50.0));
+        cache.rasterize(0, &glyph('W', 10.0, 50.0));
+        assert_eq!(4, cache.len());
+        // a space has no outline
+        assert!(cache.rasterize(0, &glyph(' ', 10.0, 50.0)).is_none());
+    }
+
+    #[test]
+    fn test_cached_coverage_matches_exact_coverage() {
+        let cache = GlyphCache::new();
+        let glyph = glyph('g', 20.5, 60.25);
+        let exact = rasterize(&glyph).unwrap();
+        let cached = cache.rasterize(0, &glyph).unwrap();
+        assert_eq!((exact.x, exact.y), (cached.x, cached.y));
+        assert_eq!(exact.coverage.data.len(), cached.coverage.data.len());
+        // the glyph is rasterized at another pixel, which only changes the rounding
+        for (exact, cached) in exact.coverage.data.iter().zip(cached.coverage.data.iter()) {
+            assert!((exact - cached).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_cache_is_cleared_when_full() {
+        let cache = GlyphCache::with_capacity(2);
+        for c in "abc".chars() {
+            cache.rasterize(0, &glyph(c, 0.0, 50.0));
+        }
+        assert_eq!(1, cache.len());
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_is_shared_by_threads() {
+        let cache = Arc::new(GlyphCache::new());
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for c in "memes".chars() {
+                        cache.rasterize(0, &glyph(c, idx as f32 * 100.0, 50.0));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // every thread draws the glyphs at the same subpixel offsets
+        assert_eq!(3, cache.len());
+        assert_eq!(20, cache.hits() + cache.misses());
+    }
+}
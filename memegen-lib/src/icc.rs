@@ -0,0 +1,388 @@
+use std::convert::TryInto;
+
+use rayon::prelude::*;
+
+use crate::layout::RgbaImage;
+
+/// The size of the header of an ICC profile, the tag table follows it
+const HEADER_SIZE: usize = 128;
+/// The number of steps of the table that encodes linear light as sRGB
+const ENCODE_STEPS: usize = 4096;
+/// The primaries of sRGB in the D50 profile connection space, the columns are red, green and blue
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+/// How far a profile may be from sRGB to be treated as sRGB
+const SRGB_TOLERANCE: f32 = 0.002;
+
+/**
+    A tone curve of an ICC profile, from encoded values to linear light, both from 0 to 1
+*/
+#[derive(Clone, Debug, PartialEq)]
+enum Curve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    /// The parametric curve with the parameters g, a, b, c, d, e and f
+    Parametric([f32; 7]),
+}
+
+impl Curve {
+    fn parse(data: &[u8]) -> Option<Curve> {
+        match data.get(0..4)? {
+            b"curv" => {
+                let count = read_u32(data, 8)? as usize;
+                match count {
+                    0 => Some(Curve::Gamma(1.0)),
+                    1 => Some(Curve::Gamma(f32::from(read_u16(data, 12)?) / 256.0)),
+                    _ => (0..count)
+                        .map(|idx| read_u16(data, 12 + 2 * idx).map(|v| f32::from(v) / 65535.0))
+                        .collect::<Option<Vec<f32>>>()
+                        .map(Curve::Table),
+                }
+            }
+            b"para" => {
+                let count = match read_u16(data, 8)? {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    _ => return None,
+                };
+                let mut params = [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+                for (idx, param) in params.iter_mut().take(count).enumerate() {
+                    *param = read_fixed(data, 12 + 4 * idx)?;
+                }
+                // the types without d, c or f are special cases of the full function
+                let [g, a, b, c, d, e, f] = params;
+                params = match count {
+                    1 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                    3 => [g, a, b, 0.0, -b / a, 0.0, 0.0],
+                    4 => [g, a, b, 0.0, -b / a, c, c],
+                    5 => [g, a, b, c, d, 0.0, 0.0],
+                    _ => [g, a, b, c, d, e, f],
+                };
+                Some(Curve::Parametric(params))
+            }
+            _ => None,
+        }
+    }
+
+    fn linear(&self, value: f32) -> f32 {
+        let linear = match self {
+            Curve::Gamma(gamma) => value.powf(*gamma),
+            Curve::Table(table) => {
+                let pos = value * (table.len() - 1) as f32;
+                let idx = (pos.floor() as usize).min(table.len() - 2);
+                let frac = pos - idx as f32;
+                table[idx] * (1.0 - frac) + table[idx + 1] * frac
+            }
+            Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if value >= *d {
+                    (a * value + b).max(0.0).powf(*g) + e
+                } else {
+                    c * value + f
+                }
+            }
+        };
+        linear.clamp(0.0, 1.0)
+    }
+
+    /**
+        Whether the curve is the one of sRGB
+    */
+    fn is_srgb(&self) -> bool {
+        (0..=32).all(|step| {
+            let value = step as f32 / 32.0;
+            (self.linear(value) - srgb_linear(value)).abs() < SRGB_TOLERANCE * 4.0
+        })
+    }
+}
+
+/**
+    Converts the colours of images with an RGB profile of primaries and tone curves to sRGB.
+    Profiles of lookup tables, e.g. of printers, are not supported.
+*/
+pub struct IccTransform {
+    /// The tables from encoded 8 bit values to linear light, per channel
+    to_linear: [Vec<f32>; 3],
+    /// From linear light of the profile to linear sRGB
+    matrix: [[f32; 3]; 3],
+}
+
+impl IccTransform {
+    /**
+        The transform of the profile to sRGB, `None` if the profile is sRGB already or is not supported
+    */
+    pub fn from_profile(profile: &[u8]) -> Option<IccTransform> {
+        if profile.len() < HEADER_SIZE + 4
+            || profile.get(12..16)? != b"mntr" && profile.get(12..16)? != b"scnr"
+            || profile.get(16..20)? != b"RGB "
+            || profile.get(20..24)? != b"XYZ "
+        {
+            return None;
+        }
+        let tag = |signature: &[u8; 4]| -> Option<&[u8]> {
+            // the count is not trusted, only the entries that fit into the profile are read
+            let count = read_u32(profile, HEADER_SIZE)? as usize;
+            profile[HEADER_SIZE + 4..]
+                .chunks_exact(12)
+                .take(count)
+                .find(|entry| &entry[0..4] == signature)
+                .and_then(|entry| {
+                    let offset = read_u32(entry, 4)? as usize;
+                    let size = read_u32(entry, 8)? as usize;
+                    profile.get(offset..offset.checked_add(size)?)
+                })
+        };
+        let xyz = |signature: &[u8; 4]| -> Option<[f32; 3]> {
+            let data = tag(signature)?;
+            if data.get(0..4)? != b"XYZ " {
+                return None;
+            }
+            Some([
+                read_fixed(data, 8)?,
+                read_fixed(data, 12)?,
+                read_fixed(data, 16)?,
+            ])
+        };
+        let primaries = [xyz(b"rXYZ")?, xyz(b"gXYZ")?, xyz(b"bXYZ")?];
+        let curves = [
+            Curve::parse(tag(b"rTRC")?)?,
+            Curve::parse(tag(b"gTRC")?)?,
+            Curve::parse(tag(b"bTRC")?)?,
+        ];
+
+        let mut to_xyz = [[0.0; 3]; 3];
+        for (channel, primary) in primaries.iter().enumerate() {
+            for (row, value) in primary.iter().enumerate() {
+                to_xyz[row][channel] = *value;
+            }
+        }
+        let is_srgb = curves.iter().all(Curve::is_srgb)
+            && to_xyz
+                .iter()
+                .flatten()
+                .zip(SRGB_TO_XYZ.iter().flatten())
+                .all(|(value, srgb)| (value - srgb).abs() < SRGB_TOLERANCE);
+        if is_srgb {
+            return None;
+        }
+        let matrix = multiply(&invert(&SRGB_TO_XYZ)?, &to_xyz);
+        let table = |curve: &Curve| -> Vec<f32> {
+            (0..256)
+                .map(|value| curve.linear(value as f32 / 255.0))
+                .collect()
+        };
+        Some(IccTransform {
+            to_linear: [table(&curves[0]), table(&curves[1]), table(&curves[2])],
+            matrix,
+        })
+    }
+
+    /**
+        Converts the colours of the image to sRGB, the alpha channel stays the same
+    */
+    pub fn apply(&self, image: &mut RgbaImage) {
+        let encode: Vec<u8> = (0..ENCODE_STEPS)
+            .map(|step| {
+                let linear = step as f32 / (ENCODE_STEPS - 1) as f32;
+                (srgb_encoded(linear) * 255.0).round() as u8
+            })
+            .collect();
+        let width = image.width() as usize;
+        image.par_chunks_mut(4 * width.max(1)).for_each(|row| {
+            for pixel in row.chunks_mut(4) {
+                let linear = [
+                    self.to_linear[0][usize::from(pixel[0])],
+                    self.to_linear[1][usize::from(pixel[1])],
+                    self.to_linear[2][usize::from(pixel[2])],
+                ];
+                for (channel, coefficients) in self.matrix.iter().enumerate() {
+                    let value: f32 = coefficients
+                        .iter()
+                        .zip(linear.iter())
+                        .map(|(a, b)| a * b)
+                        .sum();
+                    let step = (value.clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32).round();
+                    pixel[channel] = encode[step as usize];
+                }
+            }
+        });
+    }
+}
+
+fn srgb_linear(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn srgb_encoded(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut product = [[0.0; 3]; 3];
+    for (row, values) in product.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            *value = (0..3).map(|idx| a[row][idx] * b[idx][col]).sum();
+        }
+    }
+    product
+}
+
+fn invert(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let cofactor = |row: usize, col: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((col + 1) % 3, (col + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f32 = (0..3).map(|col| m[0][col] * cofactor(0, col)).sum();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for (row, values) in inverse.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            // the inverse is the transposed matrix of cofactors
+            *value = cofactor(col, row) / determinant;
+        }
+    }
+    Some(inverse)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/**
+    Reads a signed number with 16 integer and 16 fraction bits
+*/
+fn read_fixed(data: &[u8], pos: usize) -> Option<f32> {
+    Some(read_u32(data, pos)? as i32 as f32 / 65536.0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /**
+        A display profile of the primaries, given as the XYZ of red, green and blue,
+        and the tone curve of the gamma for all channels. The defaults are the ones of sRGB.
+    */
+    pub(crate) fn matrix_profile(primaries: Option<[[f32; 3]; 3]>, gamma: Option<f32>) -> Vec<u8> {
+        let fixed = |value: f32| ((value * 65536.0).round() as i32).to_be_bytes();
+        let primaries = primaries.unwrap_or_else(|| {
+            let mut primaries = [[0.0; 3]; 3];
+            for (channel, primary) in primaries.iter_mut().enumerate() {
+                for (row, value) in primary.iter_mut().enumerate() {
+                    *value = SRGB_TO_XYZ[row][channel];
+                }
+            }
+            primaries
+        });
+        let curve = match gamma {
+            Some(gamma) => {
+                let mut curve = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+                curve.extend_from_slice(&((gamma * 256.0).round() as u16).to_be_bytes());
+                curve.extend_from_slice(&[0, 0]);
+                curve
+            }
+            None => {
+                let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+                for param in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.040_45].iter() {
+                    curve.extend_from_slice(&fixed(*param));
+                }
+                curve
+            }
+        };
+        let mut tags: Vec<(&[u8; 4], Vec<u8>)> = Vec::new();
+        for (signature, primary) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().zip(primaries.iter()) {
+            let mut xyz = b"XYZ \0\0\0\0".to_vec();
+            for value in primary.iter() {
+                xyz.extend_from_slice(&fixed(*value));
+            }
+            tags.push((signature, xyz));
+        }
+        for signature in [b"rTRC", b"gTRC", b"bTRC"].iter() {
+            tags.push((signature, curve.clone()));
+        }
+
+        let mut profile = vec![0; HEADER_SIZE];
+        profile[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        profile[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        profile[36..40].copy_from_slice(b"acsp");
+        profile.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut offset = HEADER_SIZE + 4 + 12 * tags.len();
+        for (signature, data) in tags.iter() {
+            profile.extend_from_slice(*signature);
+            profile.extend_from_slice(&(offset as u32).to_be_bytes());
+            profile.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len();
+        }
+        for (_, data) in tags.iter() {
+            profile.extend_from_slice(data);
+        }
+        let size = (profile.len() as u32).to_be_bytes();
+        profile[0..4].copy_from_slice(&size);
+        profile
+    }
+
+    #[test]
+    fn test_matrix_profiles() {
+        assert!(IccTransform::from_profile(&matrix_profile(None, None)).is_none());
+        assert!(IccTransform::from_profile(b"not a profile").is_none());
+        // a broken tag count is limited to the tags that fit into the profile
+        let mut broken = matrix_profile(None, Some(1.0));
+        broken[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(IccTransform::from_profile(&broken).is_some());
+        // a needed tag that is missing is not searched for past the end of the profile
+        let missing = broken
+            .windows(4)
+            .position(|window| window == b"bTRC")
+            .unwrap();
+        broken[missing..missing + 4].copy_from_slice(b"kTRC");
+        assert!(IccTransform::from_profile(&broken).is_none());
+        let mut empty = broken[..HEADER_SIZE + 4].to_vec();
+        let size = (empty.len() as u32).to_be_bytes();
+        empty[0..4].copy_from_slice(&size);
+        assert!(IccTransform::from_profile(&empty).is_none());
+
+        // with linear tone curves and the sRGB primaries only the encoding changes
+        let transform = IccTransform::from_profile(&matrix_profile(None, Some(1.0))).unwrap();
+        let mut image = RgbaImage::from_pixel(2, 1, Rgba([0, 128, 255, 100]));
+        transform.apply(&mut image);
+        assert_eq!([0, 188, 255, 100], image.get_pixel(1, 0).data);
+
+        // the primaries of Adobe RGB are wider, so its colours are more saturated in sRGB
+        let adobe_rgb = [
+            [0.609_7, 0.311_1, 0.019_5],
+            [0.205_3, 0.625_7, 0.060_9],
+            [0.149_1, 0.063_2, 0.744_6],
+        ];
+        let transform =
+            IccTransform::from_profile(&matrix_profile(Some(adobe_rgb), Some(2.2))).unwrap();
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([100, 200, 100, 255]));
+        transform.apply(&mut image);
+        let [r, g, b, _] = image.get_pixel(0, 0).data;
+        assert!(r < 100 && g > 200 && b < 100, "{:?}", (r, g, b));
+        // white stays white
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 255]));
+        transform.apply(&mut image);
+        assert!(image.get_pixel(0, 0).data.iter().all(|value| *value >= 253));
+    }
+}
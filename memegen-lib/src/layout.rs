@@ -1,10 +1,22 @@
+use std::time::Duration;
+
 use image::{DynamicImage, ImageBuffer, Rgba};
-use rusttype::{point, PositionedGlyph, Scale, VMetrics};
+use rusttype::{point, Scale, VMetrics};
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::rendering::{draw_glyphs_with_outline, Fontspec};
+use crate::error::MemegenError;
+use crate::rendering::{draw_glyphs_with_outline, Fontspec, MAX_OUTLINE_WIDTH_RATIO};
+use crate::shaping::{is_rtl, shape_text, ShapedText};
 
 pub type RgbaImage = ImageBuffer<Rgba<u8>, Vec<u8>>;
 
+/// The number of rows a line may be wrapped into before its font is shrunk
+pub const DEFAULT_MAX_ROWS: u32 = 3;
+
+/// The distance between the text and the image borders in automatic layouts
+const BORDER_PADDING: f32 = 20.0;
+
 /**
 A single line of a caption.
 The Line owns all of its attributes.
@@ -18,82 +30,526 @@ pub struct Line {
         no error handling regarding unprintable chars is in place.
     */
     pub text: String,
+    /**
+        The anchor of the line on the image.
+    */
     pub orientation: Orientation,
+    /**
+        The alignment of the rows of a wrapped line relative to each other.
+    */
+    pub alignment: HorizontalAlignment,
     pub fontspec: Fontspec,
     pub number_from_layout_anchor: u32,
+    /**
+        The maximum number of rows the text is wrapped into by the automatic layout.
+        Only if the text does not fit into these rows, the font is shrunk.
+    */
+    pub max_rows: u32,
 }
 
-impl Line {
-    pub fn default() -> Line {
+impl Default for Line {
+    fn default() -> Line {
         Line {
             text: "Empty".to_string(),
             orientation: Orientation::Top,
+            alignment: HorizontalAlignment::Auto,
             fontspec: Fontspec::impact(),
             number_from_layout_anchor: 0,
+            max_rows: DEFAULT_MAX_ROWS,
         }
     }
+}
+
+impl Line {
     pub fn get_v_metrics(&self) -> VMetrics {
         self.fontspec.font.v_metrics(self.fontspec.scale)
     }
 }
 
-#[derive(Clone)]
+/**
+    One of nine anchor points of the image that a line is placed at by the automatic layout.
+    `Top` and `Bottom` are the classic centered caption positions.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Orientation {
+    TopLeft,
     Top,
+    TopRight,
+    Left,
+    Middle,
+    Right,
+    BottomLeft,
     Bottom,
+    BottomRight,
+}
+
+impl Orientation {
+    pub fn horizontal_alignment(&self) -> HorizontalAlignment {
+        match self {
+            Orientation::TopLeft | Orientation::Left | Orientation::BottomLeft => {
+                HorizontalAlignment::Left
+            }
+            Orientation::Top | Orientation::Middle | Orientation::Bottom => {
+                HorizontalAlignment::Center
+            }
+            Orientation::TopRight | Orientation::Right | Orientation::BottomRight => {
+                HorizontalAlignment::Right
+            }
+        }
+    }
+
+    pub fn vertical_alignment(&self) -> VerticalAlignment {
+        match self {
+            Orientation::TopLeft | Orientation::Top | Orientation::TopRight => {
+                VerticalAlignment::Top
+            }
+            Orientation::Left | Orientation::Middle | Orientation::Right => {
+                VerticalAlignment::Middle
+            }
+            Orientation::BottomLeft | Orientation::Bottom | Orientation::BottomRight => {
+                VerticalAlignment::Bottom
+            }
+        }
+    }
+}
+
+/**
+    The alignment of the rows of a text.
+    Justified rows are stretched to the full width by widening the spaces between words,
+    except for the last row which is aligned to the start of the text.
+    `Auto` centers left to right text and aligns right to left text on the right.
+    Under a centered anchor, a right to left block is placed at the right edge of the image as well.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HorizontalAlignment {
+    Left,
+    Center,
+    Right,
+    Justified,
+    Auto,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerticalAlignment {
+    Top,
+    Middle,
+    Bottom,
+}
+
+/**
+    The interval of an animation a line is shown in, by time or by frames.
+    The start is included and the end is not, without an end the line is shown until the animation ends.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Visibility {
+    /// Seconds since the start of the animation, a frame is shown if it starts in the interval
+    Time {
+        #[serde(default)]
+        start: f32,
+        #[serde(default)]
+        end: Option<f32>,
+    },
+    /// Indices of the frames, starting at 0
+    Frames {
+        #[serde(default)]
+        start: usize,
+        #[serde(default)]
+        end: Option<usize>,
+    },
+}
+
+impl Visibility {
+    /**
+        Whether the frame with the index, which starts at the time, is in the interval
+    */
+    pub fn contains(&self, frame: usize, time: Duration) -> bool {
+        match self {
+            Visibility::Time { start, end } => {
+                let time = time.as_secs_f32();
+                time >= *start && end.map(|end| time < end).unwrap_or(true)
+            }
+            Visibility::Frames { start, end } => {
+                frame >= *start && end.map(|end| frame < end).unwrap_or(true)
+            }
+        }
+    }
+}
+
+/**
+    A rectangular caption region of an image.
+    Text drawn into the box is wrapped to its width and uses the largest font size
+    between `min_scale` and `max_scale` at which all rows fit into the box.
+*/
+#[derive(Clone)]
+pub struct TextBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    pub horizontal_alignment: HorizontalAlignment,
+    pub vertical_alignment: VerticalAlignment,
+}
+
+impl TextBox {
+    /**
+        Creates a box with automatically aligned, vertically centered text and font sizes from 8 to 128
+    */
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> TextBox {
+        TextBox {
+            x,
+            y,
+            width,
+            height,
+            min_scale: 8.0,
+            max_scale: 128.0,
+            horizontal_alignment: HorizontalAlignment::Auto,
+            vertical_alignment: VerticalAlignment::Middle,
+        }
+    }
 }
 
 pub fn draw_lines_top_bottom(
     texts_top: Vec<String>,
     texts_bottom: Vec<String>,
     image: &mut RgbaImage,
-) {
+) -> Result<(), MemegenError> {
+    // wrapped lines occupy multiple rows, so the anchor of the next line is moved by all of them
     let mut orientation = &Orientation::Top;
-    for (ln, text) in texts_top.iter().enumerate() {
-        let number_from_layout_anchor = ln as u32;
+    let mut number_from_layout_anchor = 0;
+    for text in texts_top.iter() {
         let mut line = Line {
             text: text.clone(),
             orientation: orientation.clone(),
             number_from_layout_anchor,
             ..Line::default()
         };
-        draw_line(&mut line, image);
+        number_from_layout_anchor += draw_line(&mut line, image)?;
     }
 
     orientation = &Orientation::Bottom;
-    for (ln, text) in texts_bottom.iter().rev().enumerate() {
-        let number_from_layout_anchor = ln as u32;
+    number_from_layout_anchor = 0;
+    for text in texts_bottom.iter().rev() {
         let mut line = Line {
             text: text.clone(),
             orientation: orientation.clone(),
             number_from_layout_anchor,
             ..Line::default()
         };
-        draw_line(&mut line, image);
+        number_from_layout_anchor += draw_line(&mut line, image)?;
     }
+    Ok(())
 }
 
-pub fn draw_line_at(line: &mut Line, image: &mut RgbaImage, x_pos: f32, y_pos: f32) {
+/**
+    Draws the line in a single row with the top left corner of the text at the position
+*/
+pub fn draw_line_at(
+    line: &mut Line,
+    image: &mut RgbaImage,
+    x_pos: f32,
+    y_pos: f32,
+) -> Result<(), MemegenError> {
+    check_fontspec(&line.fontspec)?;
     let v_metrics = line.get_v_metrics();
-    let glyphs: Vec<_> = line
-        .fontspec
-        .font
-        .layout(
-            &line.text,
-            line.fontspec.scale,
-            point(x_pos, y_pos + v_metrics.ascent),
-        )
-        .collect();
-    draw_glyphs_with_outline(&line.fontspec, &glyphs, image);
+    let shaped = shape_text(
+        &line.fontspec,
+        &line.text,
+        point(x_pos, y_pos + v_metrics.ascent),
+    );
+    draw_glyphs_with_outline(&line.fontspec, &shaped, image);
+    Ok(())
 }
 
-pub fn draw_line(line: &mut Line, image: &mut RgbaImage) {
-    let (x_pos, y_pos) = autolayout_line(line, image);
-    draw_line_at(line, image, x_pos, y_pos)
+/**
+    Draws the rows of a multi-line text below each other, with the top left corner of the block at the position.
+    The rows are aligned within the block according to the alignment of the line.
+    Returns the number of rows that were drawn.
+*/
+pub(crate) fn draw_text_block_at(
+    line: &Line,
+    image: &mut RgbaImage,
+    x_pos: f32,
+    y_pos: f32,
+) -> Result<u32, MemegenError> {
+    check_fontspec(&line.fontspec)?;
+    let rows: Vec<String> = line.text.lines().map(str::to_string).collect();
+    let block_width = rows
+        .iter()
+        .map(|row| get_text_width(&line.fontspec, row))
+        .fold(0.0, f32::max);
+    draw_rows(line, &rows, &line.alignment, image, x_pos, block_width, y_pos)?;
+    Ok(rows.len() as u32)
 }
 
-pub fn generate_font_rendering_with_transparency(line: &mut Line) -> RgbaImage {
+/**
+    The number of rows `draw_line` wraps the line into on the image, without drawing anything
+*/
+pub(crate) fn count_rows(line: &Line, image: &RgbaImage) -> Result<u32, MemegenError> {
+    check_fontspec(&line.fontspec)?;
+    check_image(image)?;
+    let max_width = image.width() as f32 - 2.0 * BORDER_PADDING;
+    Ok(autolayout_rows(&mut line.clone(), max_width).len() as u32)
+}
 
+/**
+    Draws the line relative to its layout anchor.
+    The text is wrapped into rows that fit the width of the image,
+    the number of rows that were drawn is returned.
+*/
+pub fn draw_line(line: &mut Line, image: &mut RgbaImage) -> Result<u32, MemegenError> {
+    let (rows, x_pos, block_width, y_pos) = layout_block(line, image)?;
+    let alignment = line.alignment.clone();
+    draw_rows(line, &rows, &alignment, image, x_pos, block_width, y_pos)?;
+    Ok(rows.len() as u32)
+}
+
+/**
+    A row of a laid out line, with the top left corner of its text
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlacedRow {
+    pub text: String,
+    pub x: f32,
+    pub y: f32,
+}
+
+/**
+    Lays out the line relative to its layout anchor like `draw_line`, without drawing it.
+    The rows can be drawn with `draw_line_at`, justified rows are placed like the last row of a justified block.
+*/
+pub fn place_line(line: &mut Line, image: &RgbaImage) -> Result<Vec<PlacedRow>, MemegenError> {
+    let (rows, x_pos, block_width, y_pos) = layout_block(line, image)?;
+    let row_height = get_row_height(&line.fontspec);
+    let rtl = is_rtl(&line.text);
+    Ok(rows
+        .into_iter()
+        .enumerate()
+        .map(|(row, text)| {
+            let text_width = get_text_width(&line.fontspec, &text);
+            PlacedRow {
+                x: row_x_pos(&line.alignment, rtl, x_pos, block_width, text_width),
+                y: y_pos + row as f32 * row_height,
+                text,
+            }
+        })
+        .collect())
+}
+
+/**
+    Wraps the line into rows that fit the width of the image.
+    Returns the rows, the left edge and width of the block they form and the top of the first row.
+*/
+fn layout_block(
+    line: &mut Line,
+    image: &RgbaImage,
+) -> Result<(Vec<String>, f32, f32, f32), MemegenError> {
+    check_fontspec(&line.fontspec)?;
+    check_image(image)?;
+    let max_width = image.width() as f32 - 2.0 * BORDER_PADDING;
+    let rows = autolayout_rows(line, max_width);
+
+    // the rows form a block that is placed at the anchor like its widest row would be
+    let widest_row = rows
+        .iter()
+        .max_by_key(|row| get_text_width(&line.fontspec, row) as u32)
+        .cloned()
+        .unwrap_or_default();
+    let block_width = get_text_width(&line.fontspec, &widest_row);
+    let widest_line = Line {
+        text: widest_row,
+        ..line.clone()
+    };
+    let x_pos = get_x_pos(&widest_line, is_rtl(&line.text), image);
+    let y_pos = get_y_pos(line, rows.len() as u32, image);
+    Ok((rows, x_pos, block_width, y_pos))
+}
+
+/**
+    Draws the line into the text box, overriding the font size of the line.
+    If the text does not fit even at `min_scale`, it overflows the box.
+    Returns the number of rows that were drawn.
+*/
+pub fn draw_line_in_box(
+    line: &mut Line,
+    text_box: &TextBox,
+    image: &mut RgbaImage,
+) -> Result<u32, MemegenError> {
+    check_image(image)?;
+    if !(text_box.min_scale.is_finite() && text_box.min_scale > 0.0) {
+        return Err(MemegenError::InvalidScale(text_box.min_scale));
+    }
+    let text = &line.text;
+    let scale = fit_scale(
+        &line.fontspec,
+        text_box.min_scale,
+        text_box.max_scale,
+        |fontspec| {
+            let rows = wrap_text(fontspec, text, text_box.width);
+            rows_fit(fontspec, &rows, text_box.width)
+                && rows.len() as f32 * get_row_height(fontspec) <= text_box.height
+        },
+    );
+    line.fontspec.scale = scale;
+
+    let rows = wrap_text(&line.fontspec, &line.text, text_box.width);
+    let text_height = rows.len() as f32 * get_row_height(&line.fontspec);
+    let y_start = match text_box.vertical_alignment {
+        VerticalAlignment::Top => text_box.y,
+        VerticalAlignment::Middle => text_box.y + (text_box.height - text_height) / 2.0,
+        VerticalAlignment::Bottom => text_box.y + text_box.height - text_height,
+    };
+    draw_rows(
+        line,
+        &rows,
+        &text_box.horizontal_alignment,
+        image,
+        text_box.x,
+        text_box.width,
+        y_start,
+    )?;
+    Ok(rows.len() as u32)
+}
+
+/**
+    Draws the rows below each other, aligned within a block of the given width
+*/
+fn draw_rows(
+    line: &Line,
+    rows: &[String],
+    alignment: &HorizontalAlignment,
+    image: &mut RgbaImage,
+    x_pos: f32,
+    block_width: f32,
+    y_pos: f32,
+) -> Result<(), MemegenError> {
+    let row_height = get_row_height(&line.fontspec);
+    let rtl = is_rtl(&line.text);
+    for (row, text) in rows.iter().enumerate() {
+        let row_y_pos = y_pos + row as f32 * row_height;
+        let text_width = get_text_width(&line.fontspec, text);
+        let is_last_row = row + 1 == rows.len();
+        if *alignment == HorizontalAlignment::Justified && !is_last_row {
+            draw_justified_row(line, text, rtl, image, x_pos, block_width, row_y_pos)?;
+            continue;
+        }
+        let row_x_pos = row_x_pos(alignment, rtl, x_pos, block_width, text_width);
+        let mut row_line = Line {
+            text: text.clone(),
+            ..line.clone()
+        };
+        draw_line_at(&mut row_line, image, row_x_pos, row_y_pos)?;
+    }
+    Ok(())
+}
+
+/**
+    The left edge of a row within a block, justified rows are placed like the last row of the block
+*/
+fn row_x_pos(
+    alignment: &HorizontalAlignment,
+    rtl: bool,
+    x_pos: f32,
+    block_width: f32,
+    text_width: f32,
+) -> f32 {
+    match alignment {
+        HorizontalAlignment::Left => x_pos,
+        HorizontalAlignment::Center => x_pos + (block_width - text_width) / 2.0,
+        HorizontalAlignment::Right => x_pos + block_width - text_width,
+        HorizontalAlignment::Justified => {
+            if rtl {
+                x_pos + block_width - text_width
+            } else {
+                x_pos
+            }
+        }
+        HorizontalAlignment::Auto => {
+            if rtl {
+                x_pos + block_width - text_width
+            } else {
+                x_pos + (block_width - text_width) / 2.0
+            }
+        }
+    }
+}
+
+/**
+    Draws the words of the row with equal gaps so the row spans the whole block width.
+    The words of right to left rows are placed starting from the right.
+*/
+fn draw_justified_row(
+    line: &Line,
+    text: &str,
+    rtl: bool,
+    image: &mut RgbaImage,
+    x_pos: f32,
+    block_width: f32,
+    y_pos: f32,
+) -> Result<(), MemegenError> {
+    let mut words: Vec<&str> = text.split_whitespace().collect();
+    if rtl {
+        words.reverse();
+    }
+    let widths: Vec<f32> = words
+        .iter()
+        .map(|word| get_text_width(&line.fontspec, word))
+        .collect();
+    let gap = if words.len() > 1 {
+        (block_width - widths.iter().sum::<f32>()) / (words.len() - 1) as f32
+    } else {
+        0.0
+    };
+    let mut word_x_pos = x_pos;
+    for (word, width) in words.iter().zip(widths) {
+        let mut word_line = Line {
+            text: word.to_string(),
+            ..line.clone()
+        };
+        draw_line_at(&mut word_line, image, word_x_pos, y_pos)?;
+        word_x_pos += width + gap;
+    }
+    Ok(())
+}
+
+/**
+    Breaks the text into rows that are at most `max_width` pixels wide.
+    Rows are only broken on unicode word boundaries, newlines are treated like any other whitespace.
+    A single word that is wider than `max_width` is placed on a row of its own.
+*/
+pub fn wrap_text(fontspec: &Fontspec, text: &str, max_width: f32) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    for segment in text.split_word_bounds() {
+        let is_whitespace = segment.trim().is_empty();
+        // whitespace is never placed at the start of a row
+        if row.is_empty() && is_whitespace {
+            continue;
+        }
+        let candidate = format!("{}{}", row, segment);
+        if !row.is_empty()
+            && !is_whitespace
+            && get_text_width(fontspec, candidate.trim_end()) > max_width
+        {
+            rows.push(row.trim_end().to_string());
+            row = segment.to_string();
+        } else {
+            row = candidate;
+        }
+    }
+    rows.push(row.trim_end().to_string());
+    rows
+}
+
+pub fn generate_font_rendering_with_transparency(
+    line: &mut Line,
+) -> Result<RgbaImage, MemegenError> {
+    check_fontspec(&line.fontspec)?;
     let padding = 6.0;
     let splits = line.text.split("\n");
 
@@ -102,12 +558,7 @@ pub fn generate_font_rendering_with_transparency(line: &mut Line) -> RgbaImage {
     
 
     for split in splits {
-        let pre_glyphs: Vec<_> = line
-            .fontspec
-            .font
-            .layout(split, line.fontspec.scale, point(0.0, 0.0))
-            .collect();
-        let glyphs_width = get_glyph_width(&pre_glyphs) as f32;
+        let glyphs_width = get_text_width(&line.fontspec, split);
         let v_metrics = line.fontspec.font.v_metrics(line.fontspec.scale);
         let glyphs_height = (v_metrics.ascent - v_metrics.descent).ceil();
 
@@ -129,66 +580,161 @@ pub fn generate_font_rendering_with_transparency(line: &mut Line) -> RgbaImage {
             text: split.to_string(),
             fontspec: line.fontspec.clone(),
             orientation: Orientation::Top,  
-            number_from_layout_anchor: 0,
+            ..Line::default()
         };
-        draw_line_at(&mut single_line, &mut preview, 2 as f32, 2.0 + offset);
+        draw_line_at(&mut single_line, &mut preview, 2.0, 2.0 + offset)?;
     }
 
-    preview
+    Ok(preview)
+}
+
+/**
+    Wraps the text of the line into rows that fit `max_width`.
+    The font is only shrunk if the text needs more than `line.max_rows` rows.
+*/
+fn autolayout_rows(line: &mut Line, max_width: f32) -> Vec<String> {
+    let text = &line.text;
+    let max_rows = line.max_rows as usize;
+    let fits = |fontspec: &Fontspec| {
+        let rows = wrap_text(fontspec, text, max_width);
+        rows.len() <= max_rows && rows_fit(fontspec, &rows, max_width)
+    };
+    if !fits(&line.fontspec) {
+        line.fontspec.scale = fit_scale(&line.fontspec, 1.0, line.fontspec.scale.y, fits);
+    }
+    wrap_text(&line.fontspec, &line.text, max_width)
+}
+
+/**
+    Finds the largest integral font size between `min_scale` and `max_scale` for which `fits` holds.
+    The sizes are heights, the width of the font is scaled along so the aspect ratio of the fontspec is kept.
+    This is a binary search, so `fits` has to hold for all sizes below a fitting one.
+    If no size fits, `min_scale` is returned.
+*/
+fn fit_scale<F>(fontspec: &Fontspec, min_scale: f32, max_scale: f32, fits: F) -> Scale
+where
+    F: Fn(&Fontspec) -> bool,
+{
+    let aspect_ratio = fontspec.scale.x / fontspec.scale.y;
+    let scale = |height: u32| Scale {
+        x: height as f32 * aspect_ratio,
+        y: height as f32,
+    };
+    let mut probe = fontspec.clone();
+    let mut low = min_scale.max(1.0).floor() as u32;
+    let mut high = max_scale.floor().max(low as f32) as u32;
+
+    probe.scale = scale(high);
+    if fits(&probe) {
+        return probe.scale;
+    }
+    // all sizes from `high` upwards are known not to fit
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        probe.scale = scale(mid);
+        if fits(&probe) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    scale(low)
 }
 
-fn autolayout_line(mut line: &mut Line, image: &mut RgbaImage) -> (f32, f32) {
+fn rows_fit(fontspec: &Fontspec, rows: &[String], max_width: f32) -> bool {
+    rows.iter()
+        .all(|row| get_text_width(fontspec, row) <= max_width)
+}
+
+pub(crate) fn get_row_height(fontspec: &Fontspec) -> f32 {
+    let v_metrics = fontspec.font.v_metrics(fontspec.scale);
+    (v_metrics.ascent - v_metrics.descent).ceil()
+}
+
+fn get_y_pos(line: &Line, row_count: u32, image: &RgbaImage) -> f32 {
     let ratio = 1.0;
-    let border_padding = 20.0;
-    let mut x_pos = get_x_pos(&line, image);
-    println!("xpos: {}", x_pos);
-    while x_pos < border_padding {
-        line.fontspec.scale = Scale::uniform(line.fontspec.scale.x - 1.0);
-        x_pos = get_x_pos(&line, image);
-    }
-    let v_metrics = line.fontspec.font.v_metrics(line.fontspec.scale);
-    let glyphs_height = (v_metrics.ascent - v_metrics.descent).ceil() as f32;
-    let pos_y = match line.orientation {
-        Orientation::Top => border_padding + get_line_offset(&mut line, ratio, glyphs_height),
-        Orientation::Bottom => {
-            image.height() as f32
-                - border_padding
-                - glyphs_height as f32
-                - get_line_offset(&mut line, ratio, glyphs_height)
+    let glyphs_height = get_row_height(&line.fontspec);
+    let line_offset = get_line_offset(line.number_from_layout_anchor, ratio, glyphs_height);
+    let text_height = row_count as f32 * glyphs_height;
+    match line.orientation.vertical_alignment() {
+        VerticalAlignment::Top => BORDER_PADDING + line_offset,
+        VerticalAlignment::Middle => (image.height() as f32 - text_height) / 2.0 + line_offset,
+        // the block grows upwards, away from the bottom anchor
+        VerticalAlignment::Bottom => {
+            image.height() as f32 - BORDER_PADDING - text_height - line_offset
+        }
+    }
+}
+
+fn get_line_offset(rows_from_anchor: u32, ratio: f32, glyphs_height: f32) -> f32 {
+    rows_from_anchor as f32 * (glyphs_height * ratio)
+}
+
+/**
+    The left edge of the line at its anchor. Right to left text aligned with `Auto`
+    is placed at the right edge instead of the center, like its rows are aligned.
+*/
+fn get_x_pos(line: &Line, rtl: bool, image: &RgbaImage) -> f32 {
+    let image_width = image.width() as f32;
+    if line.text.is_empty() {
+        return image_width / 2.0;
+    }
+    let glyphs_width = get_text_width(&line.fontspec, &line.text);
+    let anchor = match (line.orientation.horizontal_alignment(), &line.alignment) {
+        (HorizontalAlignment::Center, HorizontalAlignment::Auto) if rtl => {
+            HorizontalAlignment::Right
         }
+        (anchor, _) => anchor,
     };
-    (x_pos, pos_y)
+    match anchor {
+        HorizontalAlignment::Left | HorizontalAlignment::Justified => BORDER_PADDING,
+        HorizontalAlignment::Center | HorizontalAlignment::Auto => {
+            (image_width - glyphs_width) / 2.0
+        }
+        HorizontalAlignment::Right => image_width - BORDER_PADDING - glyphs_width,
+    }
 }
 
-fn get_line_offset(line: &mut Line, ratio: f32, glyphs_height: f32) -> f32 {
-    line.number_from_layout_anchor as f32 * (glyphs_height * ratio)
+pub(crate) fn get_text_width(fontspec: &Fontspec, text: &str) -> f32 {
+    if text.is_empty() {
+        return 0.0;
+    }
+    let pre_glyphs = shape_text(fontspec, text, point(0.0, 0.0));
+    get_glyph_width(&pre_glyphs) as f32
 }
 
-fn get_x_pos(line: &Line, image: &mut RgbaImage) -> f32 {
-    if line.text.len() < 1 {
-        return (image.width() / 2) as f32;
+/**
+    The width of the drawn pixels, which is 0 for text without any visible glyphs like whitespace
+*/
+fn get_glyph_width(shaped: &ShapedText) -> u32 {
+    shaped
+        .pixel_bounds_x()
+        .map(|(min_x, max_x)| (max_x - min_x) as u32)
+        .unwrap_or(0)
+}
+
+/**
+    Checks the font size and the outline width, the outline is at most as wide as the font is large
+*/
+pub(crate) fn check_fontspec(fontspec: &Fontspec) -> Result<(), MemegenError> {
+    for size in [fontspec.scale.x, fontspec.scale.y].iter() {
+        if !(size.is_finite() && *size > 0.0) {
+            return Err(MemegenError::InvalidScale(*size));
+        }
     }
-    let pre_glyphs: Vec<_> = line
-        .fontspec
-        .font
-        .layout(&line.text, line.fontspec.scale, point(0.0, 0.0))
-        .collect();
-    let glyphs_width = get_glyph_width(&pre_glyphs) as f32;
-    let image_width = image.width() as f32;
-    let x_pos = (image_width - glyphs_width) / 2.0;
-    x_pos
+    let outline_width = fontspec.get_outline_width();
+    let max_outline_width = fontspec.scale.x.max(fontspec.scale.y) * MAX_OUTLINE_WIDTH_RATIO;
+    if !(0.0..=max_outline_width).contains(&outline_width) {
+        return Err(MemegenError::InvalidOutline(outline_width));
+    }
+    Ok(())
 }
 
-fn get_glyph_width(glyphs: &Vec<PositionedGlyph>) -> u32 {
-    let min_x = glyphs
-        .first()
-        .map(|g| g.pixel_bounding_box().unwrap().min.x)
-        .unwrap();
-    let max_x = glyphs
-        .last()
-        .map(|g| g.pixel_bounding_box().unwrap().max.x)
-        .unwrap();
-    (max_x - min_x) as u32
+fn check_image(image: &RgbaImage) -> Result<(), MemegenError> {
+    if image.width() == 0 || image.height() == 0 {
+        return Err(MemegenError::EmptyImage);
+    }
+    Ok(())
 }
 
 /*
@@ -197,6 +743,7 @@ These tests are in this file so they can directly test the private functions of
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fonts::FontRegistry;
     #[test]
     fn test_get_x_pos_valid() {
         let image = DynamicImage::new_rgb8(100, 100);
@@ -204,7 +751,7 @@ mod tests {
             text: "a".to_string(),
             ..Line::default()
         };
-        let x_pos = get_x_pos(&line, &mut image.to_rgba());
+        let x_pos = get_x_pos(&line, false, &image.to_rgba());
         // (100-24)/2 = 38
         assert_eq!(x_pos, 38.0);
     }
@@ -215,7 +762,7 @@ mod tests {
             text: "a".to_string(),
             ..Line::default()
         };
-        let x_pos = get_x_pos(&line, &mut image.to_rgba());
+        let x_pos = get_x_pos(&line, false, &image.to_rgba());
         // the glyph cannot fit, so we get a negative position
         assert!(x_pos < 0.0);
         // (22-24)/2 = -1
@@ -228,7 +775,7 @@ mod tests {
             text: "aaaaaaaa".to_string(),
             ..Line::default()
         };
-        let x_pos = get_x_pos(&line, &mut image.to_rgba());
+        let x_pos = get_x_pos(&line, false, &image.to_rgba());
         // chars: 8*24 = 192
         // spaces: 7*2 ~ 7*3 = 14 ~ 21
         // line-total: 206 ~ 213
@@ -237,11 +784,357 @@ mod tests {
     }
 
     #[test]
-    fn test_font_img() {
+    fn test_wrap_text_on_word_boundaries() {
+        let fontspec = Fontspec::impact();
+        let text = "one does not simply walk into mordor";
+        let rows = wrap_text(&fontspec, text, 300.0);
+        assert!(rows.len() > 1);
+        for row in rows.iter() {
+            assert!(get_text_width(&fontspec, row) <= 300.0);
+            assert_eq!(row.trim(), row);
+        }
+        assert_eq!(text, rows.join(" "));
+    }
+
+    #[test]
+    fn test_wrap_text_long_word() {
+        let fontspec = Fontspec::impact();
+        let rows = wrap_text(&fontspec, "a bbbbbbbbbbbbbbbbbbbbbbbb c", 100.0);
+        assert_eq!(vec!["a", "bbbbbbbbbbbbbbbbbbbbbbbb", "c"], rows);
+    }
+
+    #[test]
+    fn test_wrap_text_empty() {
+        let fontspec = Fontspec::impact();
+        assert_eq!(vec![""], wrap_text(&fontspec, "", 100.0));
+        assert_eq!(vec![""], wrap_text(&fontspec, "   ", 100.0));
+    }
+
+    #[test]
+    fn test_draw_line_wraps_before_shrinking() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(500, 500).to_rgba();
+        let mut line = Line {
+            text: "one does not simply walk into mordor".to_string(),
+            ..Line::default()
+        };
+        let rows = draw_line(&mut line, &mut image)?;
+        assert!(rows > 1 && rows <= DEFAULT_MAX_ROWS);
+        assert_eq!(64.0, line.fontspec.scale.x);
+
+        let mut line = Line {
+            text: "one does not simply walk into mordor".to_string(),
+            max_rows: 1,
+            ..Line::default()
+        };
+        let rows = draw_line(&mut line, &mut image)?;
+        assert_eq!(1, rows);
+        assert!(line.fontspec.scale.x < 64.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_scale_finds_largest_size() {
+        let fontspec = Fontspec::impact();
+        let fits = |fontspec: &Fontspec| get_text_width(fontspec, "Hello") <= 200.0;
+        let scale = fit_scale(&fontspec, 1.0, 300.0, fits);
+
+        let mut probe = fontspec.clone();
+        probe.scale = scale;
+        assert!(fits(&probe));
+        probe.scale = Scale::uniform(scale.x + 1.0);
+        assert!(!fits(&probe));
+    }
+
+    #[test]
+    fn test_fit_scale_keeps_aspect_ratio() -> Result<(), MemegenError> {
+        let mut fontspec = Fontspec::impact();
+        fontspec.scale = Scale { x: 40.0, y: 80.0 };
+        let fits = |fontspec: &Fontspec| get_text_width(fontspec, "Hello") <= 60.0;
+        let scale = fit_scale(&fontspec, 1.0, 80.0, fits);
+        assert!(scale.y < 80.0);
+        assert_eq!(scale.y / 2.0, scale.x);
+
+        // a line that fits keeps its size, one that does not is shrunk in both directions
+        let mut image = DynamicImage::new_rgb8(500, 300).to_rgba();
+        let mut line = Line {
+            text: "wide".to_string(),
+            ..Line::default()
+        };
+        line.fontspec.scale = Scale { x: 60.5, y: 30.25 };
+        draw_line(&mut line, &mut image)?;
+        assert_eq!(Scale { x: 60.5, y: 30.25 }, line.fontspec.scale);
+        line.text = "one does not simply walk into mordor ".repeat(8);
+        draw_line(&mut line, &mut image)?;
+        assert!(line.fontspec.scale.y < 30.0);
+        assert_eq!(line.fontspec.scale.y * 2.0, line.fontspec.scale.x);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_scale_nothing_fits() {
+        let fontspec = Fontspec::impact();
+        let scale = fit_scale(&fontspec, 10.0, 300.0, |_| false);
+        assert_eq!(10.0, scale.x);
+    }
+
+    #[test]
+    fn test_draw_line_in_box() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(500, 500).to_rgba();
+        let text_box = TextBox::new(100.0, 100.0, 200.0, 150.0);
+        let mut line = Line {
+            text: "one does not simply walk into mordor".to_string(),
+            ..Line::default()
+        };
+        let rows = draw_line_in_box(&mut line, &text_box, &mut image)?;
+        assert!(rows > 1);
+        assert!(rows as f32 * get_row_height(&line.fontspec) <= text_box.height);
+
+        // apart from the outline, nothing is drawn outside of the box
+        let margin = line.fontspec.get_outline_width().ceil() as u32 + 1;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if !(100 - margin..=300 + margin).contains(&x)
+                || !(100 - margin..=250 + margin).contains(&y)
+            {
+                assert_eq!(0, pixel.data[0]);
+            }
+        }
+        image.save("test_output/test_draw_line_in_box.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_line_in_tiny_box() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(100, 100).to_rgba();
+        let text_box = TextBox::new(10.0, 10.0, 1.0, 1.0);
+        let mut line = Line {
+            text: "nothing fits here".to_string(),
+            ..Line::default()
+        };
+        draw_line_in_box(&mut line, &text_box, &mut image)?;
+        assert_eq!(text_box.min_scale, line.fontspec.scale.x);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_x_pos_anchors() {
+        let image = DynamicImage::new_rgb8(100, 100).to_rgba();
+        let line = Line {
+            text: "a".to_string(),
+            orientation: Orientation::TopLeft,
+            ..Line::default()
+        };
+        assert_eq!(BORDER_PADDING, get_x_pos(&line, false, &image));
+        let line = Line {
+            orientation: Orientation::BottomRight,
+            ..line
+        };
+        // 100-20-24 = 56
+        assert_eq!(56.0, get_x_pos(&line, false, &image));
+    }
+
+    #[test]
+    fn test_get_y_pos_anchors() {
+        let image = DynamicImage::new_rgb8(500, 500).to_rgba();
+        let line = Line {
+            orientation: Orientation::Middle,
+            ..Line::default()
+        };
+        let row_height = get_row_height(&line.fontspec);
+        assert_eq!((500.0 - 2.0 * row_height) / 2.0, get_y_pos(&line, 2, &image));
+        let line = Line {
+            orientation: Orientation::BottomLeft,
+            number_from_layout_anchor: 1,
+            ..line
+        };
+        assert_eq!(
+            500.0 - BORDER_PADDING - 3.0 * row_height,
+            get_y_pos(&line, 2, &image)
+        );
+    }
+
+    #[test]
+    fn test_draw_line_at_all_anchors() -> Result<(), MemegenError> {
+        let anchors = vec![
+            Orientation::TopLeft,
+            Orientation::Top,
+            Orientation::TopRight,
+            Orientation::Left,
+            Orientation::Middle,
+            Orientation::Right,
+            Orientation::BottomLeft,
+            Orientation::Bottom,
+            Orientation::BottomRight,
+        ];
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
+        for orientation in anchors {
+            let mut line = Line {
+                text: "such anchor".to_string(),
+                orientation,
+                ..Line::default()
+            };
+            assert_eq!(1, draw_line(&mut line, &mut image)?);
+        }
+        image.save("test_output/test_draw_line_at_all_anchors.jpg")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_justified_rows() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(500, 500).to_rgba();
+        let mut line = Line {
+            text: "the quick brown fox jumps over the lazy dog and keeps running".to_string(),
+            orientation: Orientation::Middle,
+            alignment: HorizontalAlignment::Justified,
+            ..Line::default()
+        };
+        let rows = draw_line(&mut line, &mut image)?;
+        assert!(rows > 1);
+        image.save("test_output/test_draw_justified_rows.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_mixed_script_line() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(600, 150).to_rgba();
+        let mut line = Line {
+            text: "Memes → ♥".to_string(),
+            fontspec: Fontspec {
+                fallbacks: FontRegistry::with_baked_fonts().fallbacks(&["Impact"]),
+                ..Fontspec::roboto()
+            },
+            ..Line::default()
+        };
+        draw_line(&mut line, &mut image)?;
+        image.save("test_output/test_draw_mixed_script_line.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_rtl_line() -> Result<(), MemegenError> {
+        // the test font of the shaping tests, written by `res/test/make_rtl_font.py`
+        let mut registry = FontRegistry::new();
+        registry.load_file("res/test/rtl.ttf")?;
+        let fontspec = registry.fontspec("Test RTL", None).unwrap();
+        let mut image = DynamicImage::new_rgb8(400, 300).to_rgba();
+        let mut line = Line {
+            text: "שלום עולם, זה מם ארוך מאוד".to_string(),
+            fontspec,
+            ..Line::default()
+        };
+        let rows = draw_line(&mut line, &mut image)?;
+        assert!(rows > 1);
+        image.save("test_output/test_draw_rtl_line.png")?;
+
+        // the block is placed at the right edge, a centered alignment centers it
+        let image = DynamicImage::new_rgb8(400, 300).to_rgba();
+        line.text = "שלום".to_string();
+        let placed = place_line(&mut line, &image)?;
+        let text_width = get_text_width(&line.fontspec, &line.text);
+        assert_eq!(400.0 - BORDER_PADDING - text_width, placed[0].x);
+        line.alignment = HorizontalAlignment::Center;
+        let placed = place_line(&mut line, &image)?;
+        assert_eq!((400.0 - text_width) / 2.0, placed[0].x);
+        line.alignment = HorizontalAlignment::Auto;
+        line.orientation = Orientation::BottomLeft;
+        assert_eq!(BORDER_PADDING, place_line(&mut line, &image)?[0].x);
+        Ok(())
+    }
+
+    #[test]
+    fn test_draw_whitespace_line() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(200, 200).to_rgba();
+        let mut line = Line {
+            text: "   ".to_string(),
+            ..Line::default()
+        };
+        assert_eq!(0.0, get_text_width(&line.fontspec, &line.text));
+        assert_eq!(1, draw_line(&mut line, &mut image)?);
+        draw_line_at(&mut line, &mut image, 10.0, 10.0)?;
+        generate_font_rendering_with_transparency(&mut line)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_input_is_rejected() {
+        let mut image = DynamicImage::new_rgb8(200, 200).to_rgba();
+        let mut line = Line::default();
+        line.fontspec.scale = Scale::uniform(0.0);
+        match draw_line(&mut line, &mut image) {
+            Err(MemegenError::InvalidScale(_)) => {}
+            _ => panic!("A font size of 0 is invalid"),
+        }
+        line.fontspec.scale = Scale::uniform(f32::NAN);
+        assert!(draw_line_at(&mut line, &mut image, 0.0, 0.0).is_err());
+        assert!(generate_font_rendering_with_transparency(&mut line).is_err());
+
+        let mut empty = RgbaImage::new(0, 0);
+        match draw_line(&mut Line::default(), &mut empty) {
+            Err(MemegenError::EmptyImage) => {}
+            _ => panic!("There is nothing to draw on"),
+        }
+        let text_box = TextBox {
+            min_scale: -1.0,
+            ..TextBox::new(0.0, 0.0, 100.0, 100.0)
+        };
+        assert!(draw_line_in_box(&mut Line::default(), &text_box, &mut image).is_err());
+
+        // outlines that are not finite or wider than the font is large cannot be drawn
+        let mut line = Line::default();
+        for width in [f32::INFINITY, f32::NAN, -1.0, line.fontspec.scale.y * 2.0].iter() {
+            line.fontspec.outline_width = Some(*width);
+            match draw_line(&mut line, &mut image) {
+                Err(MemegenError::InvalidOutline(_)) => {}
+                _ => panic!("An outline width of {} is invalid", width),
+            }
+        }
+        line.fontspec.outline_width = Some(line.fontspec.scale.y);
+        assert!(draw_line(&mut line, &mut image).is_ok());
+    }
+
+    #[test]
+    fn test_font_img() -> Result<(), MemegenError> {
         let test_img_data = generate_font_rendering_with_transparency(&mut Line {
             text: "Test".to_string(),
             ..Line::default()
-        });
-        test_img_data.save("test_output/test_font_img.png").unwrap();
+        })?;
+        test_img_data.save("test_output/test_font_img.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_visibility() {
+        let seconds = |seconds: f32| Duration::from_secs_f32(seconds);
+        let first_second = Visibility::Time {
+            start: 0.0,
+            end: Some(1.0),
+        };
+        assert!(first_second.contains(0, seconds(0.0)));
+        assert!(first_second.contains(9, seconds(0.9)));
+        assert!(!first_second.contains(10, seconds(1.0)));
+        let later = Visibility::Time {
+            start: 1.0,
+            end: None,
+        };
+        assert!(!later.contains(0, seconds(0.0)));
+        assert!(later.contains(1000, seconds(100.0)));
+        let frames = Visibility::Frames {
+            start: 2,
+            end: Some(4),
+        };
+        assert_eq!(
+            vec![false, false, true, true, false],
+            (0..5)
+                .map(|frame| frames.contains(frame, seconds(0.0)))
+                .collect::<Vec<_>>()
+        );
+
+        let parsed: Visibility = serde_json::from_str(r#"{"type": "time", "end": 1.5}"#).unwrap();
+        assert_eq!(
+            Visibility::Time {
+                start: 0.0,
+                end: Some(1.5)
+            },
+            parsed
+        );
     }
 }
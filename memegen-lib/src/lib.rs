@@ -1,52 +1,124 @@
 
 pub mod memegen {}
 
+mod animation;
+mod effects;
+mod emoji;
+mod error;
+mod export;
+mod fonts;
+mod glyph_cache;
+mod icc;
 mod layout;
+mod link;
+mod loading;
+mod meme;
+mod metadata;
+mod project;
+mod render_cache;
 mod rendering;
 mod service;
-
+mod shaping;
+mod template;
+mod webp;
+
+pub use animation::Animation;
+pub use animation::AnimationFrame;
+pub use effects::animate_lines;
+pub use effects::Effect;
+pub use effects::EffectSettings;
+pub use effects::MAX_EFFECT_FRAMES;
+pub use error::MemegenError;
+pub use export::ExportFormat;
+pub use export::ExportOptions;
+pub use export::PngCompression;
+pub use export::DEFAULT_JPEG_QUALITY;
+pub use fonts::installed_emoji_faces;
+pub use fonts::system_font_dirs;
+pub use fonts::FontError;
+pub use fonts::FontFace;
+pub use fonts::FontRegistry;
+pub use glyph_cache::FontId;
+pub use glyph_cache::GlyphCache;
+pub use glyph_cache::DEFAULT_GLYPH_CACHE_CAPACITY;
 pub use layout::draw_line;
 pub use layout::draw_line_at;
+pub use layout::draw_line_in_box;
 pub use layout::draw_lines_top_bottom;
 pub use layout::generate_font_rendering_with_transparency;
+pub use layout::place_line;
+pub use layout::wrap_text;
+pub use layout::HorizontalAlignment;
 pub use layout::Line;
 pub use layout::Orientation;
-pub use service::PositionedLine;
+pub use layout::PlacedRow;
+pub use layout::TextBox;
+pub use layout::VerticalAlignment;
+pub use layout::Visibility;
+pub use link::decode_link_text;
+pub use link::encode_link_text;
+pub use link::percent_decode;
+pub use link::MemeLink;
+pub use link::LINK_EXTENSIONS;
+pub use loading::load_image;
+pub use loading::open_image;
+pub use meme::draw_layers;
+pub use meme::render;
+pub use meme::render_all;
+pub use meme::render_animation;
+pub use meme::render_with_fonts;
+pub use meme::Background;
+pub use meme::Meme;
+pub use meme::Placement;
+pub use meme::TextLayer;
+pub use meme::TextStyle;
+pub use metadata::read_exif;
+pub use project::load_project;
+pub use project::project_from_str;
+pub use project::project_to_string;
+pub use project::save_project;
+pub use project::ProjectFormat;
+pub use project::PROJECT_VERSION;
+pub use render_cache::RenderCache;
+pub use render_cache::RenderKey;
+pub use render_cache::DEFAULT_RENDER_CACHE_SIZE;
+pub use rendering::parse_colour;
+pub use rendering::Fontspec;
 pub use service::PreviewService;
 pub use service::UpdateRequest;
+pub use template::Template;
+pub use template::TemplateLibrary;
+pub use template::TextSlot;
+pub use template::TEMPLATE_MANIFEST;
 
 #[cfg(test)]
 mod tests {
-    use crate::service::PreviewService;
-    use crate::{draw_line_at, draw_lines_top_bottom, Line};
+    use crate::{draw_line_at, draw_lines_top_bottom, Line, MemegenError};
 
     #[test]
-    fn test_rendering_exact_position() {
-        let text1 = vec!["Toptext".to_string()];
-        let text1 = vec!["Toptext".to_string()];
-
-        let mut image = image::open("res/images/puffin.jpg").unwrap().to_rgba();
+    fn test_rendering_exact_position() -> Result<(), MemegenError> {
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
         let mut line = Line {
             text: "TXßg".to_string(),
             ..Line::default()
         };
-        draw_line_at(&mut line, &mut image, 50.0, 50.0);
-        image
-            .save("test_output/test_rendering_exact_position.png")
-            .unwrap();
+        draw_line_at(&mut line, &mut image, 50.0, 50.0)?;
+        image.save("test_output/test_rendering_exact_position.png")?;
+        Ok(())
     }
 
     #[test]
-    fn test_rendering_empty() {
+    fn test_rendering_empty() -> Result<(), MemegenError> {
         let texts_top = vec!["".to_string()];
         let texts_bottom = vec!["".to_string()];
-        let mut image = image::open("res/images/puffin.jpg").unwrap().to_rgba();
-        draw_lines_top_bottom(texts_top, texts_bottom, &mut image);
-        image.save("test_output/test_rendering_empty.jpg").unwrap();
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
+        draw_lines_top_bottom(texts_top, texts_bottom, &mut image)?;
+        image.save("test_output/test_rendering_empty.jpg")?;
+        Ok(())
     }
 
     #[test]
-    fn test_rendering_multiline() {
+    fn test_rendering_multiline() -> Result<(), MemegenError> {
         let texts_top = vec![
             "Toptext hoes here".to_string(),
             "Second line top".to_string(),
@@ -59,16 +131,15 @@ mod tests {
                 .to_string(),
         ];
 
-        let mut image = image::open("res/images/puffin.jpg").unwrap().to_rgba();
-        draw_lines_top_bottom(texts_top, texts_bottom, &mut image);
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
+        draw_lines_top_bottom(texts_top, texts_bottom, &mut image)?;
 
-        image
-            .save("test_output/test_rendering_multiline.jpg")
-            .unwrap();
+        image.save("test_output/test_rendering_multiline.jpg")?;
+        Ok(())
     }
 
     #[test]
-    fn test_rendering_with_long_texts() {
+    fn test_rendering_with_long_texts() -> Result<(), MemegenError> {
         let texts_top = vec![
             "Toptext hoes here".to_string(),
             "Second line top".to_string(),
@@ -114,16 +185,15 @@ mod tests {
                                      ulmer extended—but the term font might be applied either to one \
                                      of these alone or to the whole typeface.".to_string()];
 
-        let mut image = image::open("res/images/puffin.jpg").unwrap().to_rgba();
-        draw_lines_top_bottom(texts_top, texts_bottom, &mut image);
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
+        draw_lines_top_bottom(texts_top, texts_bottom, &mut image)?;
 
-        image
-            .save("test_output/test_rendering_with_long_texts.jpg")
-            .unwrap();
+        image.save("test_output/test_rendering_with_long_texts.jpg")?;
+        Ok(())
     }
 
     #[test]
-    fn test_rendering_with_one_insanely_long_text() {
+    fn test_rendering_with_one_insanely_long_text() -> Result<(), MemegenError> {
         let texts_top = vec![
             "Toptext hoes here".to_string(),
             "Second line top".to_string(),
@@ -167,11 +237,10 @@ mod tests {
                                      ulmer extended—but the term font might be applied either to one \
                                      of these alone or to the whole typeface.".to_string()];
 
-        let mut image = image::open("res/images/puffin.jpg").unwrap().to_rgba();
-        draw_lines_top_bottom(texts_top, texts_bottom, &mut image);
+        let mut image = image::open("res/images/puffin.jpg")?.to_rgba();
+        draw_lines_top_bottom(texts_top, texts_bottom, &mut image)?;
 
-        image
-            .save("test_output/test_rendering_with_one_insanely_long_text.jpg")
-            .unwrap();
+        image.save("test_output/test_rendering_with_one_insanely_long_text.jpg")?;
+        Ok(())
     }
 }
@@ -0,0 +1,366 @@
+use crate::error::MemegenError;
+use crate::layout::{draw_lines_top_bottom, RgbaImage};
+use crate::loading::open_image;
+use crate::template::TemplateLibrary;
+
+/// The extensions a meme link can end with
+pub const LINK_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "gif"];
+
+/**
+    The escape sequences of the URL scheme and the characters they stand for.
+    A single `_` or `-` is a space, the doubled ones are the characters themselves.
+*/
+const ESCAPES: [(&str, char); 14] = [
+    ("__", '_'),
+    ("--", '-'),
+    ("_", ' '),
+    ("-", ' '),
+    ("~n", '\n'),
+    ("~q", '?'),
+    ("~a", '&'),
+    ("~p", '%'),
+    ("~h", '#'),
+    ("~s", '/'),
+    ("~b", '\\'),
+    ("~l", '<'),
+    ("~g", '>'),
+    ("''", '"'),
+];
+
+/**
+    A meme in the URL scheme of memegen.link, e.g. `/two_panels/top_text/bottom_text.png`.
+    The first path segment is the template, every further segment is a line of text.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemeLink {
+    /// The id of the template
+    pub template: String,
+    pub lines: Vec<String>,
+    /// The extension of the link, one of `LINK_EXTENSIONS`
+    pub extension: Option<String>,
+}
+
+impl MemeLink {
+    pub fn new(template: &str, lines: &[&str]) -> MemeLink {
+        MemeLink {
+            template: template.to_string(),
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            extension: Some("png".to_string()),
+        }
+    }
+
+    /**
+        Parses the path of a link. The segments may be percent-encoded,
+        the extension is only split off the last segment if it is a known one.
+    */
+    pub fn parse(path: &str) -> Result<MemeLink, MemegenError> {
+        let mut segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if segments.len() > 1 && segments.last() == Some(&"") {
+            // a trailing slash
+            segments.pop();
+        }
+        let mut extension = None;
+        if let Some(last) = segments.last_mut() {
+            if let Some(idx) = last.rfind('.') {
+                let candidate = last[idx + 1..].to_lowercase();
+                if LINK_EXTENSIONS.contains(&candidate.as_str()) {
+                    extension = Some(candidate);
+                    *last = &last[..idx];
+                }
+            }
+        }
+
+        let decode = |segment: &str| {
+            percent_decode(segment).ok_or_else(|| {
+                MemegenError::Link(format!("invalid URL encoding: {}", segment))
+            })
+        };
+        let template = decode(segments[0])?;
+        if template.is_empty() {
+            return Err(MemegenError::Link("there is no template".to_string()));
+        }
+        let lines = segments[1..]
+            .iter()
+            .map(|segment| decode(segment).map(|segment| decode_link_text(&segment)))
+            .collect::<Result<_, _>>()?;
+        Ok(MemeLink {
+            template,
+            lines,
+            extension,
+        })
+    }
+
+    /**
+        The path of the link, with the text escaped so it can be parsed again
+    */
+    pub fn to_path(&self) -> String {
+        let mut path = format!("/{}", percent_encode(&self.template));
+        for line in self.lines.iter() {
+            path.push('/');
+            path.push_str(&percent_encode(&encode_link_text(line)));
+        }
+        if let Some(extension) = &self.extension {
+            path.push('.');
+            path.push_str(extension);
+        }
+        path
+    }
+
+    /**
+        The lines drawn at the top and at the bottom of the image.
+        A single line is the top text, otherwise the last line is the bottom text and all others are at the top.
+        Escaped newlines split a line into rows, blank lines are left empty.
+    */
+    pub fn top_bottom(&self) -> (Vec<String>, Vec<String>) {
+        let rows = |lines: &[String]| -> Vec<String> {
+            lines
+                .iter()
+                .flat_map(|line| line.split('\n'))
+                .filter(|row| !row.trim().is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        match self.lines.split_last() {
+            Some((bottom, top)) if !top.is_empty() => {
+                (rows(top), rows(std::slice::from_ref(bottom)))
+            }
+            _ => (rows(&self.lines), Vec::new()),
+        }
+    }
+
+    /**
+        Draws the lines onto the background of the template, as classic top and bottom text
+    */
+    pub fn render(&self, templates: &TemplateLibrary) -> Result<RgbaImage, MemegenError> {
+        let template = templates
+            .get(&self.template)
+            .ok_or_else(|| MemegenError::UnknownTemplate(self.template.clone()))?;
+        let mut image = open_image(&template.background)?;
+        let (top, bottom) = self.top_bottom();
+        draw_lines_top_bottom(top, bottom, &mut image)?;
+        Ok(image)
+    }
+}
+
+/**
+    Replaces the escape sequences of the URL scheme by the characters they stand for,
+    e.g. `what~q_-_dash--board` becomes `what?   dash-board`. A single `_` is an empty line.
+*/
+pub fn decode_link_text(text: &str) -> String {
+    if text == "_" || text == "-" {
+        return String::new();
+    }
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match ESCAPES.iter().find(|(escape, _)| rest.starts_with(escape)) {
+            Some((escape, replacement)) => {
+                decoded.push(*replacement);
+                rest = &rest[escape.len()..];
+            }
+            None => {
+                decoded.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    decoded
+}
+
+/**
+    Escapes the text for the URL scheme, the reverse of `decode_link_text`.
+    A single space stays a space, as a single `_` is an empty line.
+    Escape sequences like `~q` and two `'` in a row cannot be written, they are decoded as well.
+*/
+pub fn encode_link_text(text: &str) -> String {
+    if text.is_empty() {
+        return "_".to_string();
+    }
+    if text == " " {
+        return text.to_string();
+    }
+    // the escapes are chosen from the end, so a space never forms a doubled `_` or `-`
+    // with the escape after it, e.g. two spaces become `-_` instead of `__`
+    let mut escapes: Vec<&str> = Vec::with_capacity(text.len());
+    for (idx, c) in text.char_indices().rev() {
+        let next = escapes.last().copied().unwrap_or("");
+        let escape = match c {
+            '_' => "__",
+            '-' => "--",
+            ' ' if next.starts_with('_') => "-",
+            ' ' => "_",
+            '"' => "''",
+            c => match ESCAPES
+                .iter()
+                .find(|(escape, replacement)| *replacement == c && escape.starts_with('~'))
+            {
+                Some((escape, _)) => escape,
+                None => &text[idx..idx + c.len_utf8()],
+            },
+        };
+        escapes.push(escape);
+    }
+    escapes.iter().rev().copied().collect()
+}
+
+/**
+    Decodes the percent escapes of a component of a URL, `None` if an escape is invalid
+    or the decoded bytes are not UTF-8. Other characters are kept as they are.
+*/
+pub fn percent_decode(component: &str) -> Option<String> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = bytes.get(idx + 1..idx + 3)?;
+            // `from_str_radix` would accept a sign like in `%+1`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            idx += 3;
+        } else {
+            decoded.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/**
+    Percent-encodes everything but the characters that are safe in a path segment
+*/
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'\'' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_link_text() {
+        assert_eq!(
+            "one does not simply",
+            decode_link_text("one_does-not_simply")
+        );
+        assert_eq!(
+            "snake_case kebab-case",
+            decode_link_text("snake__case_kebab--case")
+        );
+        assert_eq!(
+            "why? 100% #1 & <b>",
+            decode_link_text("why~q_100~p_~h1_~a_~lb~g")
+        );
+        assert_eq!("a/b\\c \"quoted\"", decode_link_text("a~sb~bc_''quoted''"));
+        assert_eq!("first\nsecond", decode_link_text("first~nsecond"));
+        assert_eq!("", decode_link_text("_"));
+        // unknown escapes are kept
+        assert_eq!("~x", decode_link_text("~x"));
+    }
+
+    #[test]
+    fn test_link_text_round_trip() {
+        for text in [
+            "one does not simply",
+            "snake_case and kebab-case",
+            "what? 50% of #memes & <tags>",
+            "a/b\\c \"quoted\"",
+            "two\nrows",
+            "",
+            " ",
+            "  ",
+            "spaced  out _ - _-",
+            " -  _ ",
+            "ünïcödé ✓",
+        ]
+        .iter()
+        {
+            let link = MemeLink::new("two_panels", &[text, "bottom"]);
+            assert_eq!(link, MemeLink::parse(&link.to_path()).unwrap(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_parse_link() {
+        let link = MemeLink::parse("/two_panels/top_text/bottom~q.png").unwrap();
+        assert_eq!("two_panels", link.template);
+        assert_eq!(vec!["top text", "bottom?"], link.lines);
+        assert_eq!(Some("png".to_string()), link.extension);
+
+        let link = MemeLink::parse("/two_panels/such_text/much.wow").unwrap();
+        assert_eq!(vec!["such text", "much.wow"], link.lines);
+        assert_eq!(None, link.extension);
+
+        let link = MemeLink::parse("two_panels.JPG").unwrap();
+        assert!(link.lines.is_empty());
+        assert_eq!(Some("jpg".to_string()), link.extension);
+
+        let link = MemeLink::parse("/two_panels/caf%C3%A9_au_lait/").unwrap();
+        assert_eq!(vec!["café au lait"], link.lines);
+
+        assert!(MemeLink::parse("/").is_err());
+        assert!(MemeLink::parse("/two_panels/%zz.png").is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(Some("café+au lait".to_string()), percent_decode("caf%C3%a9+au%20lait"));
+        assert_eq!(None, percent_decode("%zz"));
+        assert_eq!(None, percent_decode("100%"));
+        assert_eq!(None, percent_decode("%C3"));
+        // signs are no hex digits
+        assert_eq!(None, percent_decode("%+1"));
+        assert_eq!(None, percent_decode("%-1"));
+    }
+
+    #[test]
+    fn test_link_top_bottom() {
+        let top_bottom = |path: &str| MemeLink::parse(path).unwrap().top_bottom();
+        assert_eq!(
+            (vec!["only top".to_string()], vec![]),
+            top_bottom("/t/only_top.png")
+        );
+        assert_eq!(
+            (vec![], vec!["only bottom".to_string()]),
+            top_bottom("/t/_/only_bottom.png")
+        );
+        assert_eq!(
+            (
+                vec!["first".to_string(), "second".to_string()],
+                vec!["third".to_string()]
+            ),
+            top_bottom("/t/first~nsecond/third.png")
+        );
+    }
+
+    #[test]
+    fn test_render_link() -> Result<(), MemegenError> {
+        let mut templates = TemplateLibrary::new();
+        templates.load_dir("res/test/templates");
+        let link = MemeLink::parse("/two_panels/linked_memes/are_the_best.png")?;
+        let image = link.render(&templates)?;
+        assert_eq!((400, 400), image.dimensions());
+        // the bottom text is drawn in white over the dark background
+        let (width, height) = image.dimensions();
+        assert!((height * 3 / 4..height)
+            .any(|y| (0..width).any(|x| image.get_pixel(x, y).data == [255, 255, 255, 255])));
+        image.save("test_output/test_render_link.png")?;
+
+        match MemeLink::new("nope", &["a"]).render(&templates) {
+            Err(MemegenError::UnknownTemplate(id)) => assert_eq!("nope", id),
+            _ => panic!("There is no template nope"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::Path;
+
+use image::imageops;
+
+use crate::error::MemegenError;
+use crate::icc::IccTransform;
+use crate::layout::RgbaImage;
+use crate::metadata::{exif_orientation, read_exif, read_icc_profile};
+
+/**
+    Decodes an image the way it is meant to be seen: turned upright by its EXIF orientation,
+    and with the colours of its embedded ICC profile converted to sRGB.
+    Text placed on the decoded image ends up where it is seen on the image.
+*/
+pub fn load_image(data: &[u8]) -> Result<RgbaImage, MemegenError> {
+    let mut image = image::load_from_memory(data)?.to_rgba();
+    let transform = read_icc_profile(data).and_then(|profile| IccTransform::from_profile(&profile));
+    if let Some(transform) = transform {
+        transform.apply(&mut image);
+    }
+    let orientation = read_exif(data)
+        .and_then(|exif| exif_orientation(&exif))
+        .unwrap_or(1);
+    Ok(orient(image, orientation))
+}
+
+/**
+    Reads and decodes an image file, see `load_image`
+*/
+pub fn open_image<P: AsRef<Path>>(path: P) -> Result<RgbaImage, MemegenError> {
+    load_image(&fs::read(path)?)
+}
+
+/**
+    Turns the image upright by its EXIF orientation:
+    1 is upright, 2 to 4 are mirrored horizontally, turned halfway and mirrored vertically,
+    5 to 8 are turned by a quarter and mirrored or not. Unknown orientations are left as they are.
+*/
+fn orient(image: RgbaImage, orientation: u16) -> RgbaImage {
+    match orientation {
+        2 => imageops::flip_horizontal(&image),
+        3 => imageops::rotate180(&image),
+        4 => imageops::flip_vertical(&image),
+        5 => imageops::flip_horizontal(&imageops::rotate90(&image)),
+        6 => imageops::rotate90(&image),
+        7 => imageops::flip_horizontal(&imageops::rotate270(&image)),
+        8 => imageops::rotate270(&image),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{ExportFormat, ExportOptions};
+    use crate::icc::tests::matrix_profile;
+    use crate::meme::{Background, Meme};
+    use image::Rgba;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    /**
+        EXIF data in the byte order with nothing but the orientation
+    */
+    fn orientation_exif(orientation: u16, big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut exif = if big_endian {
+            b"MM".to_vec()
+        } else {
+            b"II".to_vec()
+        };
+        exif.extend_from_slice(&u16_bytes(42));
+        exif.extend_from_slice(&u32_bytes(8));
+        exif.extend_from_slice(&u16_bytes(1));
+        // the orientation, a single short
+        exif.extend_from_slice(&u16_bytes(0x0112));
+        exif.extend_from_slice(&u16_bytes(3));
+        exif.extend_from_slice(&u32_bytes(1));
+        exif.extend_from_slice(&u16_bytes(orientation));
+        exif.extend_from_slice(&[0, 0]);
+        exif.extend_from_slice(&u32_bytes(0));
+        exif
+    }
+
+    /**
+        A 3x2 image with red, green and blue in the top row and white below them
+    */
+    fn image() -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(3, 2, Rgba(WHITE));
+        image.put_pixel(0, 0, Rgba(RED));
+        image.put_pixel(1, 0, Rgba(GREEN));
+        image.put_pixel(2, 0, Rgba(BLUE));
+        image
+    }
+
+    fn png_with_exif(exif: &[u8]) -> Result<Vec<u8>, MemegenError> {
+        let options = ExportOptions {
+            keep_exif: true,
+            ..ExportOptions::new(ExportFormat::Png)
+        };
+        options.encode(&image(), Some(exif))
+    }
+
+    #[test]
+    fn test_exif_orientation() -> Result<(), MemegenError> {
+        // the corner the red pixel of the top left corner is seen in
+        let expected = [
+            (1, (3, 2), (0, 0)),
+            (2, (3, 2), (2, 0)),
+            (3, (3, 2), (2, 1)),
+            (4, (3, 2), (0, 1)),
+            (5, (2, 3), (0, 0)),
+            (6, (2, 3), (1, 0)),
+            (7, (2, 3), (1, 2)),
+            (8, (2, 3), (0, 2)),
+        ];
+        for (orientation, dimensions, red) in expected.iter() {
+            for big_endian in [false, true].iter() {
+                let exif = orientation_exif(*orientation, *big_endian);
+                let loaded = load_image(&png_with_exif(&exif)?)?;
+                assert_eq!(*dimensions, loaded.dimensions(), "{}", orientation);
+                assert_eq!(RED, loaded.get_pixel(red.0, red.1).data, "{}", orientation);
+            }
+        }
+        // turned by a quarter clockwise, the top row becomes the right column
+        let loaded = load_image(&png_with_exif(&orientation_exif(6, false))?)?;
+        assert_eq!(GREEN, loaded.get_pixel(1, 1).data);
+        assert_eq!(BLUE, loaded.get_pixel(1, 2).data);
+        assert_eq!(WHITE, loaded.get_pixel(0, 1).data);
+
+        // kept EXIF data marks the turned image as upright
+        let meme = Meme::new(Background::Encoded {
+            data: png_with_exif(&orientation_exif(6, true))?,
+        });
+        let exif = meme.load_exif()?.expect("The background has EXIF data");
+        assert_eq!(Some(1), exif_orientation(&exif));
+        Ok(())
+    }
+
+    #[test]
+    fn test_icc_profile_to_srgb() -> Result<(), MemegenError> {
+        // a grey image in the middle of a JPEG block
+        let image = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+        let jpeg = ExportOptions {
+            jpeg_quality: 100,
+            ..ExportOptions::new(ExportFormat::Jpeg)
+        }
+        .encode(&image, None)?;
+        let with_profile = |profile: &[u8]| {
+            let mut segment = b"ICC_PROFILE\0\x01\x01".to_vec();
+            segment.extend_from_slice(profile);
+            let mut data = jpeg[..2].to_vec();
+            data.extend_from_slice(&[0xff, 0xe2]);
+            data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(&segment);
+            data.extend_from_slice(&jpeg[2..]);
+            data
+        };
+        let plain = load_image(&jpeg)?;
+
+        // with linear tone curves, mid grey is brighter in sRGB
+        let linear = load_image(&with_profile(&matrix_profile(None, Some(1.0))))?;
+        let grey = linear.get_pixel(4, 4).data;
+        assert!(grey[0] > 180 && grey[0] < 195, "{:?}", grey);
+        assert_eq!(grey[0], grey[2]);
+
+        // an sRGB profile changes nothing
+        let srgb = load_image(&with_profile(&matrix_profile(None, None)))?;
+        assert_eq!(plain.into_raw(), srgb.into_raw());
+        Ok(())
+    }
+}
@@ -0,0 +1,699 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use image::{ImageFormat, Rgba};
+use rayon::prelude::*;
+use rusttype::Scale;
+use serde::{Deserialize, Serialize};
+
+use crate::animation::Animation;
+use crate::error::MemegenError;
+use crate::fonts::{FontError, FontRegistry};
+use crate::layout::{
+    check_fontspec, count_rows, draw_line, draw_line_in_box, draw_text_block_at, HorizontalAlignment, Line,
+    Orientation, RgbaImage, TextBox, VerticalAlignment, Visibility,
+};
+use crate::loading::{load_image, open_image};
+use crate::metadata::{read_exif, reset_orientation};
+use crate::rendering::Fontspec;
+
+/// The smallest font size in pixels text boxes shrink their text to
+const MIN_BOX_SCALE: f32 = 8.0;
+
+/**
+    A meme as a document: a background and the text layers on top of it.
+    All positions and sizes are fractions of the background, so a meme renders the same
+    on a preview and on the full resolution image.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Meme {
+    pub background: Background,
+    /// The text layers from bottom to top, later layers are drawn over earlier ones
+    #[serde(default)]
+    pub layers: Vec<TextLayer>,
+}
+
+/**
+    The image the text is drawn onto
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Background {
+    /// An image file, relative paths are resolved against the working directory
+    File { path: PathBuf },
+    /// An encoded image file embedded in the document, e.g. an uploaded image
+    Encoded {
+        #[serde(with = "base64_data")]
+        data: Vec<u8>,
+    },
+    /// A plain area of a single colour
+    Solid {
+        width: u32,
+        height: u32,
+        colour: (u8, u8, u8),
+    },
+}
+
+/**
+    A caption of the meme
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TextLayer {
+    pub text: String,
+    #[serde(default)]
+    pub style: TextStyle,
+    #[serde(default)]
+    pub placement: Placement,
+    /// When the layer is shown on an animated background, it is shown on every frame if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visible: Option<Visibility>,
+}
+
+/**
+    The looks of a text layer.
+    Fonts are referenced by their family and style names and looked up in a `FontRegistry`.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextStyle {
+    pub font_family: String,
+    pub font_style: Option<String>,
+    /// Families that are used for characters the font has no glyphs for
+    pub fallbacks: Vec<String>,
+    /// The font size as a fraction of the image height
+    pub size: f32,
+    pub fill: (u8, u8, u8),
+    pub outline: (u8, u8, u8),
+    /**
+        The width of the outline as a fraction of the image height.
+        If it is not set, the width is proportional to the font size.
+    */
+    pub outline_width: Option<f32>,
+    pub alignment: HorizontalAlignment,
+    /// The number of rows anchored text is wrapped into before its font is shrunk
+    pub max_rows: u32,
+}
+
+/**
+    Where a text layer is placed on the image.
+    Coordinates are fractions of the image width and height, measured from the top left corner.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Placement {
+    /**
+        Placed by the automatic layout at an anchor of the image, like the classic captions.
+        Layers at the same anchor are stacked in their order, reading from top to bottom.
+    */
+    Anchor { orientation: Orientation },
+    /// The top left corner of the text is at the point, rows are separated by newlines
+    Point { x: f32, y: f32 },
+    /// The text is wrapped into the box and shrunk until it fits
+    Box {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        vertical_alignment: VerticalAlignment,
+    },
+}
+
+impl Default for TextStyle {
+    fn default() -> TextStyle {
+        TextStyle {
+            font_family: "Impact".to_string(),
+            font_style: None,
+            fallbacks: Vec::new(),
+            size: 0.1,
+            fill: (255, 255, 255),
+            outline: (0, 0, 0),
+            outline_width: None,
+            alignment: HorizontalAlignment::Auto,
+            max_rows: crate::layout::DEFAULT_MAX_ROWS,
+        }
+    }
+}
+
+impl Default for Placement {
+    fn default() -> Placement {
+        Placement::Anchor {
+            orientation: Orientation::Top,
+        }
+    }
+}
+
+impl Meme {
+    pub fn new(background: Background) -> Meme {
+        Meme {
+            background,
+            layers: Vec::new(),
+        }
+    }
+
+    /**
+        Creates the classic meme with the top texts stacked below each other at the top
+        and the bottom texts at the bottom of the image
+    */
+    pub fn top_bottom(background: Background, texts_top: &[&str], texts_bottom: &[&str]) -> Meme {
+        let anchored = |texts: &[&str], orientation: Orientation| -> Vec<TextLayer> {
+            texts
+                .iter()
+                .map(|text| TextLayer {
+                    text: text.to_string(),
+                    style: TextStyle::default(),
+                    placement: Placement::Anchor {
+                        orientation: orientation.clone(),
+                    },
+                    visible: None,
+                })
+                .collect()
+        };
+        let mut layers = anchored(texts_top, Orientation::Top);
+        layers.extend(anchored(texts_bottom, Orientation::Bottom));
+        Meme { background, layers }
+    }
+
+    /**
+        Decodes or creates the background image in its full resolution, upright and in sRGB
+    */
+    pub fn load_background(&self) -> Result<RgbaImage, MemegenError> {
+        let image = match &self.background {
+            Background::File { path } => open_image(path)?,
+            Background::Encoded { data } => load_image(data)?,
+            Background::Solid {
+                width,
+                height,
+                colour,
+            } => {
+                let (r, g, b) = *colour;
+                RgbaImage::from_pixel(*width, *height, Rgba([r, g, b, 255]))
+            }
+        };
+        Ok(image)
+    }
+
+    /**
+        Decodes all frames of a GIF background, any other background is a still of a single frame
+    */
+    pub fn load_animation(&self) -> Result<Animation, MemegenError> {
+        let data = match &self.background {
+            Background::File { path } => std::fs::read(path)?,
+            Background::Encoded { data } => data.clone(),
+            Background::Solid { .. } => return Ok(Animation::still(self.load_background()?)),
+        };
+        match image::guess_format(&data) {
+            Ok(ImageFormat::GIF) => Animation::decode_gif(&data[..]),
+            _ => Ok(Animation::still(load_image(&data)?)),
+        }
+    }
+
+    /**
+        The EXIF data of the background image, to be kept by `ExportOptions::encode`.
+        The image is marked as upright, since `load_background` turns it upright.
+    */
+    pub fn load_exif(&self) -> Result<Option<Vec<u8>>, MemegenError> {
+        let mut exif = match &self.background {
+            Background::File { path } => read_exif(&std::fs::read(path)?),
+            Background::Encoded { data } => read_exif(data),
+            Background::Solid { .. } => None,
+        };
+        if let Some(exif) = exif.as_mut() {
+            reset_orientation(exif);
+        }
+        Ok(exif)
+    }
+}
+
+impl TextLayer {
+    pub fn new(text: &str, placement: Placement) -> TextLayer {
+        TextLayer {
+            text: text.to_string(),
+            style: TextStyle::default(),
+            placement,
+            visible: None,
+        }
+    }
+
+    /**
+        Whether the layer is shown on the frame, which starts at the time since the start of the animation
+    */
+    pub fn is_visible_at(&self, frame: usize, time: Duration) -> bool {
+        self.visible
+            .as_ref()
+            .map(|visible| visible.contains(frame, time))
+            .unwrap_or(true)
+    }
+}
+
+impl TextStyle {
+    /**
+        Looks up the fonts of the style and sizes them for an image of the given height.
+        Sizes and outline widths that cannot be drawn are rejected.
+    */
+    pub fn fontspec(
+        &self,
+        fonts: &FontRegistry,
+        image_height: u32,
+    ) -> Result<Fontspec, MemegenError> {
+        let style = self.font_style.as_deref();
+        let mut fontspec = fonts
+            .fontspec(&self.font_family, style)
+            .ok_or_else(|| FontError::NotFound(self.font_family.clone()))?;
+        let fallbacks: Vec<&str> = self.fallbacks.iter().map(String::as_str).collect();
+        let height = image_height as f32;
+        // the fallbacks of the style come before the emoji faces the fontspec starts with
+        let mut chain = fonts.fallbacks(&fallbacks);
+        chain.append(&mut fontspec.fallbacks);
+        fontspec.fallbacks = chain;
+        fontspec.scale = Scale::uniform(self.size * height);
+        fontspec.colour_main = self.fill;
+        fontspec.colour_outline = self.outline;
+        fontspec.outline_width = self.outline_width.map(|width| width * height);
+        check_fontspec(&fontspec)?;
+        Ok(fontspec)
+    }
+}
+
+/**
+    Renders the meme in the resolution of its background, with the fonts baked into the library
+*/
+pub fn render(meme: &Meme) -> Result<RgbaImage, MemegenError> {
+    render_with_fonts(meme, &FontRegistry::with_baked_fonts())
+}
+
+/**
+    Renders the meme in the resolution of its background, looking up the fonts in the registry
+*/
+pub fn render_with_fonts(meme: &Meme, fonts: &FontRegistry) -> Result<RgbaImage, MemegenError> {
+    let mut image = meme.load_background()?;
+    draw_layers(&meme.layers, fonts, &mut image)?;
+    Ok(image)
+}
+
+/**
+    Renders the meme onto every frame of its background, looking up the fonts in the registry.
+    Backgrounds that are not animated GIFs render to a single frame.
+*/
+pub fn render_animation(meme: &Meme, fonts: &FontRegistry) -> Result<Animation, MemegenError> {
+    let mut animation = meme.load_animation()?;
+    animation.draw_layers(&meme.layers, fonts)?;
+    Ok(animation)
+}
+
+/**
+    Renders independent memes in parallel on all cores, looking up the fonts in the registry.
+    The results are in the order of the memes, the glyph cache of the registry is shared by all of them.
+*/
+pub fn render_all(memes: &[Meme], fonts: &FontRegistry) -> Vec<Result<RgbaImage, MemegenError>> {
+    memes
+        .par_iter()
+        .map(|meme| render_with_fonts(meme, fonts))
+        .collect()
+}
+
+/**
+    Draws the text layers onto an image, e.g. a preview of the background.
+    The layers are scaled to the dimensions of the image.
+    All layers are drawn whatever their visibility, which only applies to the frames of an animation.
+*/
+pub fn draw_layers(
+    layers: &[TextLayer],
+    fonts: &FontRegistry,
+    image: &mut RgbaImage,
+) -> Result<(), MemegenError> {
+    let (width, height) = image.dimensions();
+    let mut lines = Vec::with_capacity(layers.len());
+    for layer in layers {
+        let orientation = match &layer.placement {
+            Placement::Anchor { orientation } => orientation.clone(),
+            _ => Orientation::TopLeft,
+        };
+        lines.push(Line {
+            text: layer.text.clone(),
+            orientation,
+            alignment: layer.style.alignment.clone(),
+            fontspec: layer.style.fontspec(fonts, height)?,
+            max_rows: layer.style.max_rows,
+            ..Line::default()
+        });
+    }
+
+    // anchored text is stacked away from its anchor, so lines at the bottom
+    // are moved up by the rows of all lines that follow them at the same anchor
+    let mut rows = Vec::with_capacity(lines.len());
+    for (layer, line) in layers.iter().zip(lines.iter()) {
+        rows.push(match &layer.placement {
+            Placement::Anchor { .. } => count_rows(line, image)?,
+            _ => 0,
+        });
+    }
+    for idx in 0..lines.len() {
+        if let Placement::Anchor { orientation } = &layers[idx].placement {
+            let stacked = |other: &usize| match &layers[*other].placement {
+                Placement::Anchor { orientation: other } => other == orientation,
+                _ => false,
+            };
+            let offset: u32 = match orientation.vertical_alignment() {
+                VerticalAlignment::Bottom => (idx + 1..lines.len())
+                    .filter(stacked)
+                    .map(|other| rows[other])
+                    .sum(),
+                _ => (0..idx).filter(stacked).map(|other| rows[other]).sum(),
+            };
+            lines[idx].number_from_layout_anchor = offset;
+        }
+    }
+
+    for (layer, line) in layers.iter().zip(lines.iter_mut()) {
+        match &layer.placement {
+            Placement::Anchor { .. } => {
+                draw_line(line, image)?;
+            }
+            Placement::Point { x, y } => {
+                draw_text_block_at(line, image, x * width as f32, y * height as f32)?;
+            }
+            Placement::Box {
+                x,
+                y,
+                width: box_width,
+                height: box_height,
+                vertical_alignment,
+            } => {
+                let max_scale = line.fontspec.scale.y;
+                let text_box = TextBox {
+                    min_scale: MIN_BOX_SCALE.min(max_scale),
+                    max_scale,
+                    horizontal_alignment: layer.style.alignment.clone(),
+                    vertical_alignment: vertical_alignment.clone(),
+                    ..TextBox::new(
+                        x * width as f32,
+                        y * height as f32,
+                        box_width * width as f32,
+                        box_height * height as f32,
+                    )
+                };
+                draw_line_in_box(line, &text_box, image)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+    Embedded images are stored as base64 strings, which is far more compact than a list of numbers
+*/
+mod base64_data {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{draw_lines_top_bottom, MemegenError};
+
+    fn solid(width: u32, height: u32) -> Background {
+        Background::Solid {
+            width,
+            height,
+            colour: (40, 90, 160),
+        }
+    }
+
+    fn drawn_rows(image: &RgbaImage) -> Vec<u32> {
+        (0..image.height())
+            .filter(|y| (0..image.width()).any(|x| image.get_pixel(x, *y).data[0] > 200))
+            .collect()
+    }
+
+    #[test]
+    fn test_meme_round_trip() {
+        let mut meme = Meme::top_bottom(
+            Background::File {
+                path: PathBuf::from("res/images/puffin.jpg"),
+            },
+            &["One does not simply"],
+            &["write a meme generator"],
+        );
+        meme.layers.push(TextLayer {
+            style: TextStyle {
+                fallbacks: vec!["Roboto".to_string()],
+                outline_width: Some(0.01),
+                alignment: HorizontalAlignment::Justified,
+                ..TextStyle::default()
+            },
+            ..TextLayer::new(
+                "in a box",
+                Placement::Box {
+                    x: 0.1,
+                    y: 0.4,
+                    width: 0.8,
+                    height: 0.2,
+                    vertical_alignment: VerticalAlignment::Middle,
+                },
+            )
+        });
+        let json = serde_json::to_string(&meme).unwrap();
+        let parsed: Meme = serde_json::from_str(&json).unwrap();
+        assert_eq!(meme, parsed);
+    }
+
+    #[test]
+    fn test_meme_defaults() {
+        let json = r#"{
+            "background": {"type": "solid", "width": 100, "height": 50, "colour": [0, 0, 0]},
+            "layers": [{"text": "hello"}]
+        }"#;
+        let meme: Meme = serde_json::from_str(json).unwrap();
+        assert_eq!(TextStyle::default(), meme.layers[0].style);
+        assert_eq!(Placement::default(), meme.layers[0].placement);
+    }
+
+    #[test]
+    fn test_render_matches_top_bottom() -> Result<(), MemegenError> {
+        let mut meme = Meme::top_bottom(
+            solid(800, 512),
+            &["Toptext", "Second line top"],
+            &["First lower", "second lower"],
+        );
+        // the default line has a font size of 64 pixels
+        for layer in meme.layers.iter_mut() {
+            layer.style.size = 0.125;
+        }
+        // both are drawn with a glyph cache, so the glyphs are rasterized at the same subpixel offsets
+        let fonts = FontRegistry::with_baked_fonts();
+        let rendered = render_with_fonts(&meme, &fonts)?;
+        let mut image = meme.load_background()?;
+        draw_lines_top_bottom(
+            vec!["Toptext".to_string(), "Second line top".to_string()],
+            vec!["First lower".to_string(), "second lower".to_string()],
+            &mut image,
+        )?;
+        assert!(rendered.pixels().zip(image.pixels()).all(|(a, b)| a == b));
+        rendered.save("test_output/test_render_matches_top_bottom.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_emoji_are_drawn_in_colour() -> Result<(), MemegenError> {
+        let mut meme = Meme::top_bottom(solid(300, 200), &["WOW 😀"], &[]);
+        meme.layers[0].style.fallbacks = vec!["Roboto".to_string()];
+        let mut fonts = FontRegistry::with_baked_fonts();
+        fonts.load_file("res/test/emoji-colr.ttf")?;
+        let fontspec = meme.layers[0].style.fontspec(&fonts, 200)?;
+        assert_eq!("Roboto", fontspec.fallbacks[0].family);
+        assert_eq!("Test Emoji COLR", fontspec.fallbacks[1].family);
+
+        // without any emoji font in the style, the yellow face of the test font is drawn
+        let rendered = render_with_fonts(&meme, &fonts)?;
+        assert!(rendered.pixels().any(|pixel| pixel.data == [255, 220, 0, 255]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_with_glyph_cache() -> Result<(), MemegenError> {
+        let meme = Meme::top_bottom(solid(600, 400), &["Cached glyphs"], &["cached memes"]);
+        let fonts = FontRegistry::with_baked_fonts();
+        let cached = render_with_fonts(&meme, &fonts)?;
+        let cache = fonts.glyph_cache().unwrap();
+        assert!(cache.hits() > 0);
+        assert!(!cache.is_empty());
+
+        let mut uncached_fonts = fonts.clone();
+        uncached_fonts.set_glyph_cache(None);
+        let uncached = render_with_fonts(&meme, &uncached_fonts)?;
+        // glyphs are moved by less than a tenth of a pixel to their subpixel steps
+        let max_difference = cached
+            .pixels()
+            .zip(uncached.pixels())
+            .flat_map(|(a, b)| {
+                a.data
+                    .iter()
+                    .zip(b.data.iter())
+                    .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            })
+            .max()
+            .unwrap();
+        assert!(max_difference < 64, "difference of {}", max_difference);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_all() -> Result<(), MemegenError> {
+        let memes: Vec<Meme> = (0..8)
+            .map(|idx| {
+                Meme::top_bottom(
+                    solid(300, 200),
+                    &[&format!("meme {}", idx)],
+                    &["in parallel"],
+                )
+            })
+            .collect();
+        let fonts = FontRegistry::with_baked_fonts();
+        let rendered = render_all(&memes, &fonts);
+        assert_eq!(memes.len(), rendered.len());
+        for (meme, image) in memes.iter().zip(rendered) {
+            assert_eq!(
+                image?.into_raw(),
+                render_with_fonts(meme, &fonts)?.into_raw()
+            );
+        }
+
+        let broken = Meme::new(Background::File {
+            path: PathBuf::from("res/images/does_not_exist.jpg"),
+        });
+        let rendered = render_all(&[memes[0].clone(), broken], &fonts);
+        assert!(rendered[0].is_ok());
+        assert!(rendered[1].is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_is_resolution_independent() -> Result<(), MemegenError> {
+        let mut meme = Meme::new(solid(400, 200));
+        meme.layers.push(TextLayer {
+            style: TextStyle {
+                size: 0.25,
+                ..TextStyle::default()
+            },
+            ..TextLayer::new("Hi", Placement::Point { x: 0.25, y: 0.5 })
+        });
+        let small = render(&meme)?;
+        meme.background = solid(800, 400);
+        let large = render(&meme)?;
+
+        let small_rows = drawn_rows(&small);
+        let large_rows = drawn_rows(&large);
+        let small_height = small_rows.last().unwrap() - small_rows.first().unwrap();
+        let large_height = large_rows.last().unwrap() - large_rows.first().unwrap();
+        assert!(*small_rows.first().unwrap() >= 100);
+        assert!(*large_rows.first().unwrap() >= 200);
+        assert!((large_height as i32 - 2 * small_height as i32).abs() <= 3);
+        large.save("test_output/test_render_is_resolution_independent.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_box_and_rows() -> Result<(), MemegenError> {
+        let mut meme = Meme::new(solid(600, 400));
+        meme.layers.push(TextLayer::new(
+            "first\nsecond",
+            Placement::Point { x: 0.05, y: 0.05 },
+        ));
+        meme.layers.push(TextLayer::new(
+            "a long text that has to be wrapped into the box",
+            Placement::Box {
+                x: 0.5,
+                y: 0.5,
+                width: 0.4,
+                height: 0.4,
+                vertical_alignment: VerticalAlignment::Top,
+            },
+        ));
+        let image = render(&meme)?;
+        // the box text stays within the lower right quarter
+        for y in 0..200 {
+            for x in 300..600 {
+                assert!(image.get_pixel(x, y).data[0] < 200);
+            }
+        }
+        assert!(drawn_rows(&image).iter().any(|y| *y > 200));
+        image.save("test_output/test_render_box_and_rows.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_unknown_font() {
+        let mut meme = Meme::new(solid(100, 100));
+        meme.layers.push(TextLayer {
+            style: TextStyle {
+                font_family: "Comic Sans".to_string(),
+                ..TextStyle::default()
+            },
+            ..TextLayer::new("text", Placement::default())
+        });
+        match render(&meme) {
+            Err(MemegenError::Font(FontError::NotFound(family))) => {
+                assert_eq!("Comic Sans", family)
+            }
+            _ => panic!("Comic Sans is not baked into the library"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_outline_is_rejected() {
+        let fonts = FontRegistry::with_baked_fonts();
+        let style = TextStyle {
+            outline_width: Some(f32::INFINITY),
+            ..TextStyle::default()
+        };
+        match style.fontspec(&fonts, 100) {
+            Err(MemegenError::InvalidOutline(_)) => {}
+            _ => panic!("An infinite outline cannot be drawn"),
+        }
+        let mut meme = Meme::top_bottom(solid(100, 100), &["top"], &[]);
+        meme.layers[0].style.outline_width = Some(-0.1);
+        assert!(render(&meme).is_err());
+    }
+
+    #[test]
+    fn test_render_animation() -> Result<(), MemegenError> {
+        let frames = [(150, 40, 40), (40, 150, 40), (40, 40, 150)]
+            .iter()
+            .map(|(r, g, b)| crate::AnimationFrame {
+                image: RgbaImage::from_pixel(300, 200, Rgba([*r, *g, *b, 255])),
+                delay: std::time::Duration::from_millis(200),
+            })
+            .collect();
+        let mut data = Vec::new();
+        Animation { frames }.encode_gif(&mut data)?;
+
+        let meme = Meme::top_bottom(Background::Encoded { data }, &["every"], &["frame"]);
+        let animation = render_animation(&meme, &FontRegistry::with_baked_fonts())?;
+        assert_eq!(3, animation.frames.len());
+        for frame in animation.frames.iter() {
+            assert_eq!(std::time::Duration::from_millis(200), frame.delay);
+            assert!(!drawn_rows(&frame.image).is_empty());
+        }
+        animation.save_gif("test_output/test_render_animation.gif")?;
+
+        // anything but a GIF is a still
+        let still = render_animation(&Meme::new(solid(30, 20)), &FontRegistry::with_baked_fonts())?;
+        assert!(!still.is_animated());
+        let puffin = Meme::new(Background::File {
+            path: "res/images/puffin.jpg".into(),
+        });
+        assert_eq!(1, puffin.load_animation()?.frames.len());
+        Ok(())
+    }
+}
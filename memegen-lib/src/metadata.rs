@@ -0,0 +1,208 @@
+use std::convert::TryInto;
+
+/// The signature of PNG files
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// The prefix of the EXIF data in the APP1 segment of JPEG files
+pub(crate) const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// The JPEG marker that starts the compressed image data, no metadata follows it
+const JPEG_START_OF_SCAN: u8 = 0xda;
+/// The JPEG application segment EXIF data is stored in
+pub(crate) const JPEG_APP1: u8 = 0xe1;
+/// The EXIF tag of the orientation of the image
+const ORIENTATION_TAG: u16 = 0x0112;
+/// The prefix of ICC profiles in the APP2 segments of JPEG files
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+/// The JPEG application segment ICC profiles are stored in
+const JPEG_APP2: u8 = 0xe2;
+
+/**
+    Finds the EXIF data of an encoded JPEG, PNG or WebP image.
+    Returns the TIFF structure of the data, without the container specific headers,
+    or `None` if the image has no EXIF data or is in another format.
+*/
+pub fn read_exif(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xff, 0xd8]) {
+        jpeg_segments(data)
+            .find(|(marker, segment)| *marker == JPEG_APP1 && segment.starts_with(JPEG_EXIF_HEADER))
+            .map(|(_, segment)| segment[JPEG_EXIF_HEADER.len()..].to_vec())
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        png_chunks(data)
+            .find(|(name, _)| name == b"eXIf")
+            .map(|(_, chunk)| chunk.to_vec())
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        riff_chunks(&data[12..])
+            .find(|(name, _)| name == b"EXIF")
+            // some encoders keep the header of the JPEG segment
+            .map(|(_, chunk)| {
+                chunk
+                    .strip_prefix(JPEG_EXIF_HEADER)
+                    .unwrap_or(chunk)
+                    .to_vec()
+            })
+    } else {
+        None
+    }
+}
+
+/**
+    The markers and contents of the JPEG segments before the image data
+*/
+pub(crate) fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = 2;
+    std::iter::from_fn(move || {
+        // markers may be padded with any number of 0xff bytes
+        while data.get(pos) == Some(&0xff) && data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        if data.get(pos) != Some(&0xff) || pos + 4 > data.len() {
+            return None;
+        }
+        let marker = data[pos + 1];
+        let length = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+        if marker == JPEG_START_OF_SCAN || length < 2 || pos + 2 + length > data.len() {
+            return None;
+        }
+        let segment = &data[pos + 4..pos + 2 + length];
+        pos += 2 + length;
+        Some((marker, segment))
+    })
+}
+
+/**
+    The names and contents of the chunks of a PNG file
+*/
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut pos = PNG_SIGNATURE.len();
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos + 8)?;
+        let length = u32::from_be_bytes(header[0..4].try_into().ok()?) as usize;
+        let name: [u8; 4] = header[4..8].try_into().ok()?;
+        // the chunk is followed by its checksum
+        let chunk = data.get(pos + 8..(pos + 8).checked_add(length)?)?;
+        pos += 12 + length;
+        Some((name, chunk))
+    })
+}
+
+/**
+    The names and contents of the chunks in the payload of a RIFF file, like a WebP image
+*/
+fn riff_chunks(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let header = data.get(pos..pos + 8)?;
+        let name: [u8; 4] = header[0..4].try_into().ok()?;
+        let length = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let chunk = data.get(pos + 8..(pos + 8).checked_add(length)?)?;
+        // chunks are padded to an even length
+        pos += 8 + length + length % 2;
+        Some((name, chunk))
+    })
+}
+
+/**
+    The position of the orientation value in EXIF data, and whether the data is big endian
+*/
+fn orientation_position(exif: &[u8]) -> Option<(usize, bool)> {
+    let big_endian = match exif.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| -> Option<u16> {
+        let bytes = exif.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |pos: usize| -> Option<u32> {
+        let bytes = exif.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    if read_u16(2)? != 42 {
+        return None;
+    }
+    // the orientation is in the first directory of entries
+    let directory = read_u32(4)? as usize;
+    let count = usize::from(read_u16(directory)?);
+    (0..count)
+        .map(|idx| directory + 2 + 12 * idx)
+        .find(|entry| read_u16(*entry) == Some(ORIENTATION_TAG))
+        // the value of a single short is stored in the entry itself
+        .map(|entry| (entry + 8, big_endian))
+        .filter(|(pos, _)| pos + 2 <= exif.len())
+}
+
+/**
+    The EXIF orientation, from 1 for upright images to 8, see `loading::orient`
+*/
+pub(crate) fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    let (pos, big_endian) = orientation_position(exif)?;
+    let bytes = [exif[pos], exif[pos + 1]];
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+/**
+    Marks the image of the EXIF data as upright, after its orientation was applied to the pixels
+*/
+pub(crate) fn reset_orientation(exif: &mut [u8]) {
+    if let Some((pos, big_endian)) = orientation_position(exif) {
+        let upright = if big_endian {
+            1u16.to_be_bytes()
+        } else {
+            1u16.to_le_bytes()
+        };
+        exif[pos..pos + 2].copy_from_slice(&upright);
+    }
+}
+
+/**
+    Finds the embedded ICC profile of an encoded JPEG, PNG or WebP image
+*/
+pub(crate) fn read_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xff, 0xd8]) {
+        // large profiles are split into numbered segments
+        let mut parts: Vec<(u8, &[u8])> = jpeg_segments(data)
+            .filter(|(marker, segment)| {
+                *marker == JPEG_APP2
+                    && segment.starts_with(JPEG_ICC_HEADER)
+                    && segment.len() >= JPEG_ICC_HEADER.len() + 2
+            })
+            .map(|(_, segment)| {
+                let start = JPEG_ICC_HEADER.len();
+                (segment[start], &segment[start + 2..])
+            })
+            .collect();
+        if parts.is_empty() {
+            return None;
+        }
+        parts.sort_by_key(|(number, _)| *number);
+        Some(
+            parts
+                .into_iter()
+                .flat_map(|(_, part)| part.to_vec())
+                .collect(),
+        )
+    } else if data.starts_with(&PNG_SIGNATURE) {
+        // the profile is compressed, the decoder inflates it
+        let reader = png::Decoder::new(data).read_info().ok()?;
+        let profile = reader.info().icc_profile.as_ref()?;
+        Some(profile.to_vec())
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        riff_chunks(&data[12..])
+            .find(|(name, _)| name == b"ICCP")
+            .map(|(_, chunk)| chunk.to_vec())
+    } else {
+        None
+    }
+}
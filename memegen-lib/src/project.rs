@@ -0,0 +1,305 @@
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::MemegenError;
+use crate::meme::{Background, Meme};
+
+/// The version of the project file format that is written
+pub const PROJECT_VERSION: u32 = 1;
+
+/**
+    The formats a project file can be written in.
+    Both contain the same document, JSON is meant for programs and TOML for editing by hand.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectFormat {
+    Json,
+    Toml,
+}
+
+impl ProjectFormat {
+    /**
+        Picks the format by the file extension, `.json` or `.toml`
+    */
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ProjectFormat> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(ProjectFormat::Json),
+            "toml" => Some(ProjectFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/**
+    The contents of a project file.
+    The version allows to read files of older versions once the format changes.
+*/
+#[derive(Serialize, Deserialize)]
+struct ProjectFile {
+    version: u32,
+    meme: Meme,
+}
+
+/**
+    Serialises the meme as a project file in the format
+*/
+pub fn project_to_string(meme: &Meme, format: ProjectFormat) -> Result<String, MemegenError> {
+    let project = ProjectFile {
+        version: PROJECT_VERSION,
+        meme: meme.clone(),
+    };
+    let serialised = match format {
+        ProjectFormat::Json => {
+            serde_json::to_string_pretty(&project).map_err(|err| err.to_string())
+        }
+        ProjectFormat::Toml => toml::to_string(&project).map_err(|err| err.to_string()),
+    };
+    serialised.map_err(MemegenError::Project)
+}
+
+/**
+    Parses a project file in the format.
+    Files written by a newer version of the library are rejected.
+*/
+pub fn project_from_str(data: &str, format: ProjectFormat) -> Result<Meme, MemegenError> {
+    let project: ProjectFile = match format {
+        ProjectFormat::Json => serde_json::from_str(data).map_err(|err| err.to_string()),
+        ProjectFormat::Toml => toml::from_str(data).map_err(|err| err.to_string()),
+    }
+    .map_err(MemegenError::Project)?;
+    if project.version > PROJECT_VERSION {
+        return Err(MemegenError::Project(format!(
+            "version {} is not supported, the latest version is {}",
+            project.version, PROJECT_VERSION
+        )));
+    }
+    Ok(project.meme)
+}
+
+/**
+    Writes the meme to a project file, the format is chosen by the file extension.
+    A background file is stored relative to the directory of the project file if it is inside of it
+    and with its absolute path otherwise, so `load_project` finds it again.
+*/
+pub fn save_project<P: AsRef<Path>>(meme: &Meme, path: P) -> Result<(), MemegenError> {
+    let path = path.as_ref();
+    let format = format_of(path)?;
+    let data = match &meme.background {
+        Background::File { path: background } => {
+            let stored = Meme {
+                background: Background::File {
+                    path: stored_background_path(background, path)?,
+                },
+                ..meme.clone()
+            };
+            project_to_string(&stored, format)?
+        }
+        _ => project_to_string(meme, format)?,
+    };
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/**
+    The path of the background as it is written to the project file at `project`
+*/
+fn stored_background_path(background: &Path, project: &Path) -> Result<PathBuf, MemegenError> {
+    let cwd = env::current_dir()?;
+    let background = normalize(&cwd.join(background));
+    let project_dir = normalize(&cwd.join(project.parent().unwrap_or_else(|| Path::new(""))));
+    Ok(match background.strip_prefix(&project_dir) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => background,
+    })
+}
+
+/**
+    Removes `.` and `..` components from an absolute path without following links
+*/
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/**
+    Reads a meme from a project file, the format is chosen by the file extension.
+    A relative background path is resolved against the directory of the project file,
+    so projects can be moved together with their images.
+*/
+pub fn load_project<P: AsRef<Path>>(path: P) -> Result<Meme, MemegenError> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)?;
+    let mut meme = project_from_str(&data, format_of(path)?)?;
+    if let Background::File { path: background } = &mut meme.background {
+        if background.is_relative() {
+            if let Some(dir) = path.parent() {
+                *background = dir.join(&background);
+            }
+        }
+    }
+    Ok(meme)
+}
+
+fn format_of(path: &Path) -> Result<ProjectFormat, MemegenError> {
+    ProjectFormat::from_path(path).ok_or_else(|| {
+        MemegenError::Project(format!(
+            "unknown file extension of {}, expected .json or .toml",
+            path.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meme::{Placement, TextLayer, TextStyle};
+    use crate::{VerticalAlignment, Visibility};
+    use std::path::PathBuf;
+
+    fn project() -> Meme {
+        let mut meme = Meme::top_bottom(
+            Background::File {
+                path: PathBuf::from("test_output/project/puffin.jpg"),
+            },
+            &["Top"],
+            &["Bottom"],
+        );
+        meme.layers.push(TextLayer {
+            style: TextStyle {
+                font_family: "Roboto".to_string(),
+                font_style: Some("Regular".to_string()),
+                size: 0.05,
+                fill: (255, 0, 0),
+                outline_width: Some(0.002),
+                ..TextStyle::default()
+            },
+            visible: Some(Visibility::Time {
+                start: 0.5,
+                end: Some(2.0),
+            }),
+            ..TextLayer::new("Moved", Placement::Point { x: 0.2, y: 0.7 })
+        });
+        meme.layers.push(TextLayer {
+            visible: Some(Visibility::Frames {
+                start: 3,
+                end: None,
+            }),
+            ..TextLayer::new(
+                "Boxed",
+                Placement::Box {
+                    x: 0.1,
+                    y: 0.1,
+                    width: 0.5,
+                    height: 0.25,
+                    vertical_alignment: VerticalAlignment::Bottom,
+                },
+            )
+        });
+        meme
+    }
+
+    #[test]
+    fn test_project_formats() -> Result<(), MemegenError> {
+        let meme = project();
+        for format in [ProjectFormat::Json, ProjectFormat::Toml].iter() {
+            let data = project_to_string(&meme, *format)?;
+            assert_eq!(meme, project_from_str(&data, *format)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_embedded_image() -> Result<(), MemegenError> {
+        let data = fs::read("res/images/puffin.jpg")?;
+        let image_size = data.len();
+        let meme = Meme {
+            background: Background::Encoded { data },
+            ..project()
+        };
+        let json = project_to_string(&meme, ProjectFormat::Json)?;
+        // base64 grows the image by a third, a list of numbers would be more than three times its size
+        assert!(json.len() < 2 * image_size);
+        assert_eq!(meme, project_from_str(&json, ProjectFormat::Json)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_files() -> Result<(), MemegenError> {
+        fs::create_dir_all("test_output/project")?;
+        fs::copy("res/images/puffin.jpg", "test_output/project/puffin.jpg")?;
+        let meme = project();
+        for path in [
+            "test_output/project/meme.json",
+            "test_output/project/meme.toml",
+        ]
+        .iter()
+        {
+            save_project(&meme, path)?;
+            // the background is next to the project file and stored relative to it
+            let stored = fs::read_to_string(path)?;
+            assert!(!stored.contains("test_output"));
+            let loaded = load_project(path)?;
+            assert_eq!(
+                Background::File {
+                    path: PathBuf::from("test_output/project/puffin.jpg")
+                },
+                loaded.background
+            );
+            assert_eq!(meme.layers, loaded.layers);
+            loaded.load_background()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_background_outside_project_dir() -> Result<(), MemegenError> {
+        fs::create_dir_all("test_output/project/other")?;
+        let meme = Meme {
+            background: Background::File {
+                path: PathBuf::from("res/images/../images/puffin.jpg"),
+            },
+            ..project()
+        };
+        let path = "test_output/project/other/meme.json";
+        save_project(&meme, path)?;
+        let loaded = load_project(path)?;
+        // the background is not inside the project dir, so its absolute path is stored
+        assert_eq!(
+            Background::File {
+                path: env::current_dir()?.join("res/images/puffin.jpg")
+            },
+            loaded.background
+        );
+        loaded.load_background()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_projects() {
+        assert!(save_project(&project(), "test_output/meme.yaml").is_err());
+        assert_eq!(
+            Some(ProjectFormat::Toml),
+            ProjectFormat::from_path("meme.TOML")
+        );
+        assert!(project_from_str("{", ProjectFormat::Json).is_err());
+        let future =
+            r#"{"version": 99, "meme": {"background": {"type": "file", "path": "a.jpg"}}}"#;
+        match project_from_str(future, ProjectFormat::Json) {
+            Err(MemegenError::Project(msg)) => assert!(msg.contains("99")),
+            _ => panic!("Projects of future versions are not supported"),
+        }
+    }
+}
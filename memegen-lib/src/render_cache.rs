@@ -0,0 +1,582 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::MemegenError;
+use crate::fonts::{FontFace, FontRegistry};
+use crate::link::MemeLink;
+use crate::meme::{Background, Meme};
+use crate::template::TemplateLibrary;
+
+/**
+    Is part of every key, it has to be increased when the same input is rendered differently,
+    so entries of older versions in a disk cache are not used anymore
+*/
+const RENDER_CACHE_VERSION: u32 = 2;
+
+/// The size of the in-memory cache of `RenderCache::new` in bytes
+pub const DEFAULT_RENDER_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/**
+    The hash of everything a rendered image depends on, see `RenderCache`
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RenderKey([u8; 32]);
+
+impl RenderKey {
+    /**
+        The key as hex string, which is the file name in a disk cache
+    */
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn from_hex(hex: &str) -> Option<RenderKey> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = [0; 32];
+        for (idx, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * idx..2 * idx + 2], 16).ok()?;
+        }
+        Some(RenderKey(key))
+    }
+}
+
+impl fmt::Debug for RenderKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RenderKey({})", self.to_hex())
+    }
+}
+
+/**
+    Hashes the fields of a render input, every field is prefixed by its length,
+    so the boundaries between fields are part of the hash
+*/
+struct KeyHasher(Sha256);
+
+impl KeyHasher {
+    fn new(kind: &str, fonts: &[u8; 32], format: &str) -> KeyHasher {
+        let mut hasher = KeyHasher(Sha256::new());
+        hasher.field(&RENDER_CACHE_VERSION.to_le_bytes());
+        hasher.field(kind.as_bytes());
+        hasher.field(fonts);
+        hasher.field(format.as_bytes());
+        hasher
+    }
+
+    fn field(&mut self, data: &[u8]) {
+        self.0.update((data.len() as u64).to_le_bytes());
+        self.0.update(data);
+    }
+
+    fn finish(self) -> RenderKey {
+        RenderKey(self.0.finalize().into())
+    }
+}
+
+/**
+    The least recently used entries are dropped once the entries are larger than the capacity in bytes
+*/
+struct MemoryCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<RenderKey, (Arc<Vec<u8>>, u64)>,
+    /// The keys by the tick of their last use
+    order: BTreeMap<u64, RenderKey>,
+}
+
+impl MemoryCache {
+    fn new(capacity: usize) -> MemoryCache {
+        MemoryCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &RenderKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (data, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, *key);
+        *last_used = tick;
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: RenderKey, data: Arc<Vec<u8>>) {
+        self.remove(&key);
+        if data.len() > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.size += data.len();
+        self.entries.insert(key, (data, self.tick));
+        self.order.insert(self.tick, key);
+        while self.size > self.capacity {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &RenderKey) {
+        if let Some((data, last_used)) = self.entries.remove(key) {
+            self.size -= data.len();
+            self.order.remove(&last_used);
+        }
+    }
+}
+
+/**
+    Rendered images in files named by their keys.
+    The least recently used files are deleted once the files are larger than the limit in bytes.
+*/
+struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl DiskCache {
+    fn path(&self, key: &RenderKey) -> PathBuf {
+        self.dir.join(key.to_hex())
+    }
+
+    fn get(&self, key: &RenderKey) -> Option<Vec<u8>> {
+        let path = self.path(key);
+        let data = fs::read(&path).ok()?;
+        // the modification time is the time of the last use
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(data)
+    }
+
+    fn insert(&self, key: &RenderKey, data: &[u8]) -> Result<(), MemegenError> {
+        if data.len() as u64 > self.max_size {
+            return Ok(());
+        }
+        // the file is renamed into place, so other processes never read half of it
+        let path = self.path(key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)?;
+        self.evict()
+    }
+
+    /**
+        Deletes the least recently used entries until the entries fit into the limit
+    */
+    fn evict(&self) -> Result<(), MemegenError> {
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                name.to_str().and_then(RenderKey::from_hex).is_some()
+            })
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                let modified = metadata.modified().ok()?;
+                Some((modified, metadata.len(), entry.path()))
+            })
+            .collect();
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            // another process may have deleted the file already
+            let _ = fs::remove_file(path);
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/**
+    Hashes the faces of a registry and the emoji faces that are appended to every fontspec
+*/
+fn hash_fonts(faces: &[FontFace], emoji_fallbacks: &[FontFace]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for faces in [faces, emoji_fallbacks].iter() {
+        hasher.update((faces.len() as u64).to_le_bytes());
+        for face in faces.iter() {
+            hasher.update(face.family.as_bytes());
+            hasher.update([0]);
+            hasher.update(face.style.as_bytes());
+            hasher.update([0]);
+            hasher.update(face.source.index.to_le_bytes());
+            hasher.update(Sha256::digest(&face.source.data[..]));
+        }
+    }
+    hasher.finalize().into()
+}
+
+/**
+    A cache of rendered and encoded images, addressed by a hash of everything they depend on:
+    the bytes of the background, the text and style of every layer, the fonts and the output format.
+    Repeated renders of the same input are served without laying out any text.
+
+    Entries are kept in memory, the least recently used ones are dropped once the capacity is reached.
+    With a directory, entries are also stored on disk, so they survive restarts and can be shared by processes.
+    The fonts are hashed once when the cache is created, so a cache belongs to a single `FontRegistry`.
+    They include the installed emoji faces every fontspec falls back to.
+    The backgrounds of templates are hashed when they are loaded, so templates must be reloaded when they change.
+*/
+pub struct RenderCache {
+    fonts: [u8; 32],
+    memory: Mutex<MemoryCache>,
+    disk: Option<DiskCache>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl RenderCache {
+    /**
+        Creates a cache for the fonts of the registry that holds up to `capacity` bytes in memory
+    */
+    pub fn new(fonts: &FontRegistry, capacity: usize) -> RenderCache {
+        RenderCache {
+            fonts: hash_fonts(fonts.faces(), &fonts.emoji_fallbacks()),
+            memory: Mutex::new(MemoryCache::new(capacity)),
+            disk: None,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /**
+        Also stores the entries as files in the directory, which is created if it does not exist.
+        The least recently used files are deleted once they are larger than `max_size` bytes.
+    */
+    pub fn with_disk<P: AsRef<Path>>(
+        mut self,
+        dir: P,
+        max_size: u64,
+    ) -> Result<RenderCache, MemegenError> {
+        fs::create_dir_all(&dir)?;
+        let disk = DiskCache {
+            dir: dir.as_ref().to_path_buf(),
+            max_size,
+        };
+        disk.evict()?;
+        self.disk = Some(disk);
+        Ok(self)
+    }
+
+    /**
+        The key of the meme rendered in the format, e.g. the `ExportOptions::cache_name` of its export options.
+        Backgrounds are addressed by their content, a file and the same image embedded are the same input.
+    */
+    pub fn meme_key(&self, meme: &Meme, format: &str) -> Result<RenderKey, MemegenError> {
+        let mut hasher = KeyHasher::new("meme", &self.fonts, format);
+        match &meme.background {
+            Background::File { path } => {
+                hasher.field(b"image");
+                hasher.field(&fs::read(path)?);
+            }
+            Background::Encoded { data } => {
+                hasher.field(b"image");
+                hasher.field(data);
+            }
+            Background::Solid {
+                width,
+                height,
+                colour,
+            } => {
+                hasher.field(b"solid");
+                hasher.field(&width.to_le_bytes());
+                hasher.field(&height.to_le_bytes());
+                hasher.field(&[colour.0, colour.1, colour.2]);
+            }
+        }
+        let layers = serde_json::to_vec(&meme.layers)
+            .map_err(|err| MemegenError::Project(err.to_string()))?;
+        hasher.field(&layers);
+        Ok(hasher.finish())
+    }
+
+    /**
+        The key of the meme link rendered in the format, see `MemeLink::render`
+    */
+    pub fn link_key(
+        &self,
+        link: &MemeLink,
+        templates: &TemplateLibrary,
+        format: &str,
+    ) -> Result<RenderKey, MemegenError> {
+        let template = templates
+            .get(&link.template)
+            .ok_or_else(|| MemegenError::UnknownTemplate(link.template.clone()))?;
+        let mut hasher = KeyHasher::new("link", &self.fonts, format);
+        hasher.field(&template.background_hash);
+        let (top, bottom) = link.top_bottom();
+        for rows in [top, bottom].iter() {
+            hasher.field(&(rows.len() as u64).to_le_bytes());
+            for row in rows {
+                hasher.field(row.as_bytes());
+            }
+        }
+        Ok(hasher.finish())
+    }
+
+    /**
+        The image of the key, from memory or from disk
+    */
+    pub fn get(&self, key: &RenderKey) -> Option<Arc<Vec<u8>>> {
+        if let Some(data) = self.memory().get(key) {
+            return Some(data);
+        }
+        let data = Arc::new(self.disk.as_ref()?.get(key)?);
+        self.memory().insert(*key, data.clone());
+        Some(data)
+    }
+
+    /**
+        Stores the image in memory and on disk. Errors of the disk cache are returned,
+        the image is in the memory cache anyway.
+    */
+    pub fn insert(&self, key: RenderKey, data: Vec<u8>) -> Result<Arc<Vec<u8>>, MemegenError> {
+        let data = Arc::new(data);
+        self.memory().insert(key, data.clone());
+        if let Some(disk) = &self.disk {
+            disk.insert(&key, &data)?;
+        }
+        Ok(data)
+    }
+
+    /**
+        The cached image of the key, or the image `render` creates, which is cached then.
+        The flag tells whether the image was cached.
+    */
+    pub fn get_or_render<F>(
+        &self,
+        key: RenderKey,
+        render: F,
+    ) -> Result<(Arc<Vec<u8>>, bool), MemegenError>
+    where
+        F: FnOnce() -> Result<Vec<u8>, MemegenError>,
+    {
+        if let Some(data) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok((data, true));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = Arc::new(render()?);
+        self.memory().insert(key, data.clone());
+        if let Some(disk) = &self.disk {
+            // a full or read-only disk must not fail the render
+            let _ = disk.insert(&key, &data);
+        }
+        Ok((data, false))
+    }
+
+    /// How often `get_or_render` found the image in the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How often `get_or_render` had to render the image
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn memory(&self) -> MutexGuard<'_, MemoryCache> {
+        // the cache is consistent even if a thread panicked while holding the lock
+        self.memory.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn solid_meme(text: &str) -> Meme {
+        Meme::top_bottom(
+            Background::Solid {
+                width: 100,
+                height: 50,
+                colour: (0, 0, 0),
+            },
+            &[text],
+            &[],
+        )
+    }
+
+    fn key(n: u8) -> RenderKey {
+        RenderKey([n; 32])
+    }
+
+    #[test]
+    fn test_meme_keys() -> Result<(), MemegenError> {
+        let cache = RenderCache::new(&FontRegistry::with_baked_fonts(), 1024);
+        let meme = solid_meme("cached");
+        assert_eq!(cache.meme_key(&meme, "png")?, cache.meme_key(&meme, "png")?);
+        assert_ne!(
+            cache.meme_key(&meme, "png")?,
+            cache.meme_key(&meme, "jpeg:90")?
+        );
+        assert_ne!(
+            cache.meme_key(&meme, "png")?,
+            cache.meme_key(&solid_meme("cashed"), "png")?
+        );
+        let mut styled = meme.clone();
+        styled.layers[0].style.fill = (255, 0, 0);
+        assert_ne!(
+            cache.meme_key(&meme, "png")?,
+            cache.meme_key(&styled, "png")?
+        );
+
+        // the background is addressed by its content
+        let path = PathBuf::from("res/images/puffin.jpg");
+        let file = Meme::new(Background::File { path: path.clone() });
+        let encoded = Meme::new(Background::Encoded {
+            data: fs::read(&path)?,
+        });
+        assert_eq!(
+            cache.meme_key(&file, "png")?,
+            cache.meme_key(&encoded, "png")?
+        );
+
+        // other fonts render other images
+        let mut fonts = FontRegistry::new();
+        fonts.load_file("res/fonts/roboto.ttf")?;
+        let other_fonts = RenderCache::new(&fonts, 1024);
+        assert_ne!(
+            cache.meme_key(&meme, "png")?,
+            other_fonts.meme_key(&meme, "png")?
+        );
+        // and so do other emoji fonts, e.g. when the installed one is updated
+        let mut emoji = FontRegistry::new();
+        emoji.load_file("res/test/emoji-colr.ttf")?;
+        assert_ne!(
+            hash_fonts(fonts.faces(), &[]),
+            hash_fonts(fonts.faces(), emoji.faces())
+        );
+
+        let missing = Meme::new(Background::File {
+            path: PathBuf::from("res/images/does_not_exist.jpg"),
+        });
+        assert!(cache.meme_key(&missing, "png").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_keys() -> Result<(), MemegenError> {
+        let mut templates = TemplateLibrary::new();
+        templates.load_dir("res/test/templates");
+        let cache = RenderCache::new(&FontRegistry::with_baked_fonts(), 1024);
+        let link = MemeLink::parse("/two_panels/top/bottom.png")?;
+        // the same text in another spelling is the same image
+        let respelled = MemeLink::parse("/two_panels/top/bottom.jpg")?;
+        assert_eq!(
+            cache.link_key(&link, &templates, "png")?,
+            cache.link_key(&respelled, &templates, "png")?
+        );
+        let other = MemeLink::parse("/two_panels/top_/bottom.png")?;
+        assert_ne!(
+            cache.link_key(&link, &templates, "png")?,
+            cache.link_key(&other, &templates, "png")?
+        );
+        // the background is hashed when the template is loaded, it is not read for every key
+        let dir = Path::new("test_output/link_keys/two_panels");
+        fs::create_dir_all(dir)?;
+        for file in ["template.toml", "background.png"].iter() {
+            fs::copy(Path::new("res/test/templates/two_panels").join(file), dir.join(file))?;
+        }
+        let mut copied = TemplateLibrary::new();
+        copied.load_template(dir)?;
+        fs::remove_file(dir.join("background.png"))?;
+        assert_eq!(
+            cache.link_key(&link, &templates, "png")?,
+            cache.link_key(&link, &copied, "png")?
+        );
+        assert!(cache
+            .link_key(&MemeLink::new("nope", &[]), &templates, "png")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_cache_drops_least_recently_used() {
+        let mut memory = MemoryCache::new(10);
+        memory.insert(key(1), Arc::new(vec![0; 4]));
+        memory.insert(key(2), Arc::new(vec![0; 4]));
+        assert!(memory.get(&key(1)).is_some());
+        memory.insert(key(3), Arc::new(vec![0; 4]));
+        assert!(memory.get(&key(2)).is_none());
+        assert!(memory.get(&key(1)).is_some());
+        assert!(memory.get(&key(3)).is_some());
+        assert_eq!(8, memory.size);
+
+        // entries larger than the cache are not kept
+        memory.insert(key(4), Arc::new(vec![0; 11]));
+        assert!(memory.get(&key(4)).is_none());
+        memory.insert(key(3), Arc::new(vec![0; 10]));
+        assert!(memory.get(&key(1)).is_none());
+        assert_eq!(10, memory.size);
+    }
+
+    #[test]
+    fn test_get_or_render() -> Result<(), MemegenError> {
+        let cache = RenderCache::new(&FontRegistry::with_baked_fonts(), 1024);
+        let (data, cached) = cache.get_or_render(key(1), || Ok(vec![1, 2, 3]))?;
+        assert!(!cached);
+        let (again, cached) = cache.get_or_render(key(1), || panic!("The image is cached"))?;
+        assert!(cached);
+        assert_eq!(data, again);
+        assert_eq!((1, 1), (cache.hits(), cache.misses()));
+
+        // failed renders are not cached
+        assert!(cache
+            .get_or_render(key(2), || Err(MemegenError::EmptyImage))
+            .is_err());
+        assert!(cache.get(&key(2)).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_cache() -> Result<(), MemegenError> {
+        let dir = Path::new("test_output/render_cache");
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        let fonts = FontRegistry::with_baked_fonts();
+        let cache = RenderCache::new(&fonts, 1024).with_disk(dir, 10)?;
+        cache.insert(key(1), vec![1; 4])?;
+        cache.insert(key(2), vec![2; 4])?;
+        assert!(dir.join(key(1).to_hex()).is_file());
+
+        // a new cache finds the entries of the old one
+        let restarted = RenderCache::new(&fonts, 1024).with_disk(dir, 10)?;
+        assert_eq!(Some(Arc::new(vec![1; 4])), restarted.get(&key(1)));
+        restarted.insert(key(3), vec![3; 4])?;
+        // the second entry was used least recently
+        assert!(!dir.join(key(2).to_hex()).exists());
+        assert!(dir.join(key(1).to_hex()).is_file());
+        assert!(restarted.get(&key(3)).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_key_hex() {
+        let key = RenderKey([0xab; 32]);
+        assert_eq!(64, key.to_hex().len());
+        assert_eq!(Some(key), RenderKey::from_hex(&key.to_hex()));
+        assert_eq!(None, RenderKey::from_hex("abc"));
+    }
+}
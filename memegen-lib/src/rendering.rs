@@ -1,114 +1,666 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::emoji::{ColourGlyph, PlacedColourGlyph};
+use crate::fonts::{installed_emoji_faces, FontError, FontFace};
+use crate::glyph_cache::{self, FontId, GlyphCache, PlacedCoverage};
 use crate::layout::RgbaImage;
+use crate::shaping::ShapedText;
 use image::Rgba;
-use rusttype::{Font, PositionedGlyph, Scale};
+use rusttype::{Font, Scale, SharedBytes};
 
 const IMPACT_FONT_DATA: FontData = FontData(include_bytes!("../res/fonts/impact.ttf"));
 const ROBOTO_FONT_DATA: FontData = FontData(include_bytes!("../res/fonts/roboto.ttf"));
 
-const WOBBLE_OFFSET: [(i32, i32); 4] = [(2, 2), (2, -2), (-2, 2), (-2, -2)];
+/// The default outline width relative to the font size
+const OUTLINE_WIDTH_RATIO: f32 = 0.05;
+/// The widest outline relative to the font size, wider outlines only cover the image
+pub(crate) const MAX_OUTLINE_WIDTH_RATIO: f32 = 1.0;
 
 struct FontData(&'static [u8]);
 
+/// The glyph cache of the fontspecs of the baked fonts, it is created when it is first used
+static BAKED_GLYPH_CACHE: OnceLock<Arc<GlyphCache>> = OnceLock::new();
+
+#[derive(Clone)]
 pub enum FontDataSelector {
     ImpactFontData,
     RobotoFontData,
 }
 
+/**
+    The raw data of a font face, which is needed to shape text with it.
+    `index` is the number of the face within a font collection.
+*/
+#[derive(Clone)]
+pub struct FontSource {
+    pub data: SharedBytes<'static>,
+    pub index: u32,
+    /// Identifies the face in a `GlyphCache`
+    pub id: FontId,
+}
+
 #[derive(Clone)]
 pub struct Fontspec {
     pub font: Font<'static>,
+    pub source: FontSource,
+    /// The family name of the font, used to look it up in a `FontRegistry`
+    pub family: String,
+    pub style: String,
     pub scale: Scale,
     pub colour_outline: (u8, u8, u8),
     pub colour_main: (u8, u8, u8),
+    /**
+        The width of the outline in pixels.
+        If it is not set, the width is proportional to the font size.
+    */
+    pub outline_width: Option<f32>,
+    /**
+        Faces that are used in the given order for characters the font has no glyphs for.
+        Fontspecs are created with the colour emoji faces as fallbacks, see `FontRegistry::emoji_fallbacks`.
+    */
+    pub fallbacks: Vec<FontFace>,
+    /**
+        The cache the glyphs are rasterized with, without a cache every glyph is rasterized when it is drawn
+    */
+    pub glyph_cache: Option<Arc<GlyphCache>>,
 }
 
 impl Fontspec {
+    /**
+        The Impact font baked into the library, with the default size and colours.
+        Parsing the baked fonts is covered by the tests, so this does not fail at runtime.
+    */
     pub fn impact() -> Fontspec {
-        Fontspec {
-            font: get_font(FontDataSelector::ImpactFontData),
-            scale: Scale::uniform(64.0),
-            colour_main: (255, 255, 255),
-            colour_outline: (0, 0, 0),
-        }
+        Fontspec::baked(FontDataSelector::ImpactFontData).expect("Baked fonts are broken")
     }
+
+    /**
+        The Roboto font baked into the library, with the default size and colours
+    */
     pub fn roboto() -> Fontspec {
-        Fontspec {
-            font: get_font(FontDataSelector::RobotoFontData),
+        Fontspec::baked(FontDataSelector::RobotoFontData).expect("Baked fonts are broken")
+    }
+
+    /**
+        A fontspec of a font baked into the library.
+        All of them share a glyph cache, so lines drawn with the default fontspec reuse their glyphs.
+    */
+    pub fn baked(selector: FontDataSelector) -> Result<Fontspec, FontError> {
+        let family = match selector {
+            FontDataSelector::ImpactFontData => "Impact",
+            FontDataSelector::RobotoFontData => "Roboto",
+        };
+        Ok(Fontspec {
+            font: get_font(&selector)?,
+            source: get_font_source(&selector),
+            family: family.to_string(),
+            style: "Regular".to_string(),
             scale: Scale::uniform(64.0),
             colour_main: (255, 255, 255),
             colour_outline: (0, 0, 0),
-        }
+            outline_width: None,
+            fallbacks: installed_emoji_faces().to_vec(),
+            glyph_cache: Some(
+                BAKED_GLYPH_CACHE
+                    .get_or_init(|| Arc::new(GlyphCache::new()))
+                    .clone(),
+            ),
+        })
     }
 }
 
-pub fn get_font(selector: FontDataSelector) -> Font<'static> {
+impl Fontspec {
+    pub fn get_outline_width(&self) -> f32 {
+        self.outline_width
+            .unwrap_or(self.scale.y * OUTLINE_WIDTH_RATIO)
+    }
+}
+
+/**
+    Parses a colour given as hex code like `#ff8800` or `f80`, or by one of the basic colour names
+*/
+pub fn parse_colour(text: &str) -> Option<(u8, u8, u8)> {
+    let text = text.trim().to_lowercase();
+    let named = match text.as_str() {
+        "white" => Some((255, 255, 255)),
+        "black" => Some((0, 0, 0)),
+        "red" => Some((255, 0, 0)),
+        "green" => Some((0, 128, 0)),
+        "blue" => Some((0, 0, 255)),
+        "yellow" => Some((255, 255, 0)),
+        "grey" | "gray" => Some((128, 128, 128)),
+        _ => None,
+    };
+    if named.is_some() {
+        return named;
+    }
+    let hex = text.trim_start_matches('#');
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        // every digit of the short form is doubled, `f80` is `ff8800`
+        3 => Some((
+            channel(&hex[0..1])? * 17,
+            channel(&hex[1..2])? * 17,
+            channel(&hex[2..3])? * 17,
+        )),
+        6 => Some((
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        )),
+        _ => None,
+    }
+}
+
+pub fn get_font(selector: &FontDataSelector) -> Result<Font<'static>, FontError> {
     let res = match selector {
         FontDataSelector::ImpactFontData => Font::from_bytes(IMPACT_FONT_DATA.0),
         FontDataSelector::RobotoFontData => Font::from_bytes(ROBOTO_FONT_DATA.0),
     };
-    res.expect("Baked fonts are broken")
-}
-
-pub fn draw_glyphs_with_outline(
-    fontspec: &Fontspec,
-    glyphs: &Vec<PositionedGlyph>,
-    mut image: &mut RgbaImage,
-) {
-    // Draw offset glyphs
-    for offset in WOBBLE_OFFSET.iter() {
-        draw_glyphs(fontspec.colour_outline, &glyphs, &mut image, offset);
-    }
-    // Draw centered glyphs
-    draw_glyphs(fontspec.colour_main, &glyphs, &mut image, &(0, 0));
-}
-
-fn draw_glyphs(
-    colour: (u8, u8, u8),
-    glyphs: &Vec<PositionedGlyph>,
-    image: &mut RgbaImage,
-    offset: &(i32, i32),
-) {
-    let (max_x,max_y) = image.dimensions();
-    for glyph in glyphs {
-        if let Some(bounding_box) = glyph.pixel_bounding_box() {
-            // Draw the glyph into the image per-pixel by using the draw closure
-            glyph.draw(|x, y, v| {
-                // Offset the position by the glyph bounding box
-                let px = x as i32 + bounding_box.min.x as i32 + offset.0;
-                let py = y as i32 + bounding_box.min.y as i32 + offset.1;
-                if px < 0 || px < 0{
-                    return
+    res.map_err(|_| FontError::InvalidFont)
+}
+
+pub fn get_font_source(selector: &FontDataSelector) -> FontSource {
+    let (data, id) = match selector {
+        FontDataSelector::ImpactFontData => (IMPACT_FONT_DATA.0, 0),
+        FontDataSelector::RobotoFontData => (ROBOTO_FONT_DATA.0, 1),
+    };
+    FontSource {
+        data: SharedBytes::ByRef(data),
+        index: 0,
+        id,
+    }
+}
+
+/**
+    Draws the glyphs with an outline by compositing layers onto the image:
+    first the outline in `colour_outline`, then the glyphs themselves in `colour_main`
+    and last the colour glyphs in their own colours.
+    The outline is the coverage of all glyphs, dilated by the outline width.
+*/
+pub fn draw_glyphs_with_outline(fontspec: &Fontspec, text: &ShapedText, image: &mut RgbaImage) {
+    let outline_width = fontspec.get_outline_width().max(0.0);
+    let glyph_cache = fontspec.glyph_cache.as_deref();
+    let colour_glyphs: Vec<ColourGlyph> = text
+        .colour_glyphs
+        .iter()
+        .filter_map(PlacedColourGlyph::render)
+        .collect();
+    let margin = outline_width.ceil() as i32 + 1;
+    let fill = CoverageMask::from_glyphs(text, &colour_glyphs, glyph_cache, margin);
+    let outline = fill
+        .with_colour_glyphs(&colour_glyphs)
+        .dilate(outline_width);
+    outline.composite(fontspec.colour_outline, image);
+    fill.composite(fontspec.colour_main, image);
+    for colour_glyph in colour_glyphs.iter() {
+        composite_colour_glyph(colour_glyph, image);
+    }
+}
+
+/**
+    Blends the colour glyph onto the image, using the alpha of each of its pixels as opacity
+*/
+fn composite_colour_glyph(colour_glyph: &ColourGlyph, image: &mut RgbaImage) {
+    let (max_x, max_y) = image.dimensions();
+    for (px, py, pixel) in colour_glyph.image.enumerate_pixels() {
+        let x = colour_glyph.x + px as i32;
+        let y = colour_glyph.y + py as i32;
+        if pixel.data[3] == 0 || x < 0 || y < 0 || x as u32 >= max_x || y as u32 >= max_y {
+            continue;
+        }
+        let colour = (pixel.data[0], pixel.data[1], pixel.data[2]);
+        let opacity = f32::from(pixel.data[3]) / 255.0;
+        blend_pixel(image.get_pixel_mut(x as u32, y as u32), colour, opacity);
+    }
+}
+
+/**
+    The antialiased coverage of a layer of text, one value between 0 and 1 per pixel.
+    The mask covers a rectangle of the target image starting at (`x`, `y`).
+*/
+#[derive(Clone)]
+struct CoverageMask {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl CoverageMask {
+    /**
+        Rasterizes the outline glyphs into a mask that has `margin` pixels of free space on every side.
+        The mask is large enough to also hold the rendered colour glyphs of the text.
+        Glyphs are taken from the cache if there is one and the font of the glyph is known.
+    */
+    fn from_glyphs(
+        text: &ShapedText,
+        colour_glyphs: &[ColourGlyph],
+        cache: Option<&GlyphCache>,
+        margin: i32,
+    ) -> CoverageMask {
+        let placed: Vec<PlacedCoverage> = text
+            .glyphs
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, glyph)| match (cache, text.glyph_fonts.get(idx)) {
+                (Some(cache), Some(font)) => cache.rasterize(*font, glyph),
+                _ => glyph_cache::rasterize(glyph),
+            })
+            .collect();
+        let bounding_boxes: Vec<_> = placed
+            .iter()
+            .map(|glyph| {
+                (
+                    glyph.x,
+                    glyph.y,
+                    glyph.x + glyph.coverage.width as i32,
+                    glyph.y + glyph.coverage.height as i32,
+                )
+            })
+            .chain(colour_glyphs.iter().map(|glyph| {
+                let (width, height) = glyph.image.dimensions();
+                (
+                    glyph.x,
+                    glyph.y,
+                    glyph.x + width as i32,
+                    glyph.y + height as i32,
+                )
+            }))
+            .collect();
+        let min_x = bounding_boxes.iter().map(|bb| bb.0).min().unwrap_or(0);
+        let min_y = bounding_boxes.iter().map(|bb| bb.1).min().unwrap_or(0);
+        let max_x = bounding_boxes.iter().map(|bb| bb.2).max().unwrap_or(0);
+        let max_y = bounding_boxes.iter().map(|bb| bb.3).max().unwrap_or(0);
+
+        let x = min_x - margin;
+        let y = min_y - margin;
+        let width = (max_x - min_x + 2 * margin) as u32;
+        let height = (max_y - min_y + 2 * margin) as u32;
+        let mut mask = CoverageMask {
+            x,
+            y,
+            width,
+            height,
+            data: vec![0.0; (width * height) as usize],
+        };
+        for glyph in placed {
+            let coverage = &glyph.coverage;
+            for gy in 0..coverage.height {
+                for gx in 0..coverage.width {
+                    let v = coverage.data[(gy * coverage.width + gx) as usize];
+                    // Offset the position by the placement of the glyph
+                    let px = (gx as i32 + glyph.x - x) as u32;
+                    let py = (gy as i32 + glyph.y - y) as u32;
+                    let idx = (py * width + px) as usize;
+                    // overlapping glyphs must not add up their coverage
+                    mask.data[idx] = mask.data[idx].max(v);
                 }
-                // we can now safely cast them down
-                let px = px as u32;
-                let py = py as u32;
-
-                if v > 0.5 && px < max_x && py < max_y {
-                    image.put_pixel(
-                        px,
-                        py,
-                        // Turn the coverage into an alpha value
-                        Rgba {
-                            data: [colour.0, colour.1, colour.2, (v * 255.0) as u8],
-                        },
-                    )
+            }
+        }
+        mask
+    }
+
+    /**
+        Adds the opaque parts of the colour glyphs to the covered area
+    */
+    fn with_colour_glyphs(&self, colour_glyphs: &[ColourGlyph]) -> CoverageMask {
+        let mut mask = self.clone();
+        for colour_glyph in colour_glyphs {
+            for (px, py, pixel) in colour_glyph.image.enumerate_pixels() {
+                let x = colour_glyph.x + px as i32 - mask.x;
+                let y = colour_glyph.y + py as i32 - mask.y;
+                if x < 0 || y < 0 || x as u32 >= mask.width || y as u32 >= mask.height {
+                    continue;
+                }
+                let idx = (y as u32 * mask.width + x as u32) as usize;
+                mask.data[idx] = mask.data[idx].max(f32::from(pixel.data[3]) / 255.0);
+            }
+        }
+        mask
+    }
+
+    /**
+        Grows the covered area by `radius` pixels in every direction.
+        Each pixel is covered by how far it is within `radius` of the edge of the covered area,
+        so the outline is antialiased and fractional widths are possible.
+        The edge is found with a distance transform, so the cost does not depend on the radius.
+    */
+    fn dilate(&self, radius: f32) -> CoverageMask {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let radius_f64 = f64::from(radius);
+        // The edge is half a pixel outside of fully covered pixels and closer to less covered ones.
+        // Adding the offset o of a pixel to the distance d is (d + o)² = d² + 2do + o²,
+        // with d taken as the radius this is exact where the outline ends and is separable.
+        let mut distances: Vec<f64> = self
+            .data
+            .iter()
+            .map(|v| {
+                if *v < VISIBLE_COVERAGE {
+                    return FAR_DISTANCE;
                 }
-            });
+                let offset = 0.5 - f64::from(*v);
+                2.0 * radius_f64 * offset + offset * offset
+            })
+            .collect();
+        let mut envelope = LowerEnvelope::new(width.max(height));
+        for row in distances.chunks_mut(width.max(1)) {
+            envelope.transform(row);
+        }
+        let mut column = vec![0.0; height];
+        for px in 0..width {
+            for (py, value) in column.iter_mut().enumerate() {
+                *value = distances[py * width + px];
+            }
+            envelope.transform(&mut column);
+            for (py, value) in column.iter().enumerate() {
+                distances[py * width + px] = *value;
+            }
         }
+
+        // a pixel always covers itself, like the center of the disk around it
+        let own_weight = (radius + 0.5).min(1.0);
+        let data = self
+            .data
+            .iter()
+            .zip(distances.iter())
+            .map(|(v, squared)| {
+                let edge = squared.max(0.0).sqrt() as f32;
+                (radius - edge).clamp(0.0, 1.0).max(v * own_weight)
+            })
+            .collect();
+        CoverageMask { data, ..*self }
     }
+
+    /**
+        Blends the colour onto the image, using the coverage as its opacity
+    */
+    fn composite(&self, colour: (u8, u8, u8), image: &mut RgbaImage) {
+        let (max_x, max_y) = image.dimensions();
+        for py in 0..self.height {
+            for px in 0..self.width {
+                let v = self.data[(py * self.width + px) as usize];
+                let x = self.x + px as i32;
+                let y = self.y + py as i32;
+                if v <= 0.0 || x < 0 || y < 0 || x as u32 >= max_x || y as u32 >= max_y {
+                    continue;
+                }
+                blend_pixel(image.get_pixel_mut(x as u32, y as u32), colour, v);
+            }
+        }
+    }
+}
+
+/// The smallest coverage that changes an 8 bit pixel, rasterizers leave traces below it around glyphs
+const VISIBLE_COVERAGE: f32 = 0.5 / 255.0;
+/// The squared distance of pixels in rows and masks without any covered pixel
+const FAR_DISTANCE: f64 = 1e20;
+
+/**
+    The one dimensional squared euclidean distance transform by Felzenszwalb and Huttenlocher.
+    Each value becomes the smallest sum of a value and its squared distance,
+    found as the lower envelope of the parabolas rooted at the values.
+    Running it over the rows and then the columns transforms a whole image in linear time.
+*/
+struct LowerEnvelope {
+    /// The positions of the parabolas in the envelope
+    roots: Vec<usize>,
+    /// The boundaries between the parabolas in the envelope
+    boundaries: Vec<f64>,
+    values: Vec<f64>,
+}
+
+impl LowerEnvelope {
+    fn new(length: usize) -> LowerEnvelope {
+        LowerEnvelope {
+            roots: vec![0; length],
+            boundaries: vec![0.0; length + 1],
+            values: vec![0.0; length],
+        }
+    }
+
+    fn transform(&mut self, data: &mut [f64]) {
+        if data.is_empty() {
+            return;
+        }
+        self.values[..data.len()].copy_from_slice(data);
+        let f = &self.values;
+        let intersection = |q: usize, r: usize| {
+            let (qf, rf) = (q as f64, r as f64);
+            ((f[q] + qf * qf) - (f[r] + rf * rf)) / (2.0 * qf - 2.0 * rf)
+        };
+        let mut k = 0;
+        self.roots[0] = 0;
+        self.boundaries[0] = f64::NEG_INFINITY;
+        self.boundaries[1] = f64::INFINITY;
+        for q in 1..data.len() {
+            let mut s = intersection(q, self.roots[k]);
+            while s <= self.boundaries[k] {
+                k -= 1;
+                s = intersection(q, self.roots[k]);
+            }
+            k += 1;
+            self.roots[k] = q;
+            self.boundaries[k] = s;
+            self.boundaries[k + 1] = f64::INFINITY;
+        }
+        k = 0;
+        for (q, value) in data.iter_mut().enumerate() {
+            while self.boundaries[k + 1] < q as f64 {
+                k += 1;
+            }
+            let root = self.roots[k];
+            let distance = q as f64 - root as f64;
+            *value = distance * distance + f[root];
+        }
+    }
+}
+
+/**
+    Composites the colour with the given opacity over the pixel (porter-duff source-over).
+    The pixel keeps its own opacity where it is covered only partially,
+    so text on transparent images stays transparent around its edges.
+*/
+pub(crate) fn blend_pixel(pixel: &mut Rgba<u8>, colour: (u8, u8, u8), opacity: f32) {
+    let src_a = opacity.min(1.0);
+    let dst_a = f32::from(pixel.data[3]) / 255.0;
+    let out_a = src_a + dst_a * (1.0 - src_a);
+    if out_a <= 0.0 {
+        return;
+    }
+    let src = [colour.0, colour.1, colour.2];
+    for (channel, src_c) in pixel.data.iter_mut().zip(src.iter()) {
+        let blended =
+            (f32::from(*src_c) * src_a + f32::from(*channel) * dst_a * (1.0 - src_a)) / out_a;
+        *channel = blended.round() as u8;
+    }
+    pixel.data[3] = (out_a * 255.0).round() as u8;
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::rendering::{getFont, FontData, FontDataSelector};
+    use crate::rendering::{
+        blend_pixel, draw_glyphs_with_outline, get_font, parse_colour, CoverageMask, FontDataSelector,
+        Fontspec,
+    };
+    use crate::shaping::ShapedText;
+    use crate::{draw_line_at, Line, MemegenError};
+    use image::{DynamicImage, Rgba};
+    use rusttype::{point, Scale};
+    use std::sync::Arc;
+
     #[test]
     fn test_font_loaded_name() {
-        let font = getFont(FontDataSelector::ImpactFontData);
+        let font = get_font(&FontDataSelector::ImpactFontData).unwrap();
         let font_name_string: String = font
             .font_name_strings()
-            .map(|(a, b, c)| String::from_utf8_lossy(a))
+            .map(|(a, _, _)| String::from_utf8_lossy(a))
             .collect();
         //rintln!("Str: {}",font_name_string);
         assert!(font_name_string.contains("Impact"));
     }
+
+    #[test]
+    fn test_baked_fonts_are_valid() {
+        for selector in [
+            FontDataSelector::ImpactFontData,
+            FontDataSelector::RobotoFontData,
+        ]
+        .iter()
+        {
+            assert!(get_font(selector).is_ok());
+        }
+        assert_eq!("Roboto", Fontspec::roboto().family);
+    }
+
+    #[test]
+    fn test_baked_fonts_share_a_glyph_cache() -> Result<(), MemegenError> {
+        let cache = Fontspec::impact().glyph_cache.unwrap();
+        assert!(Arc::ptr_eq(&cache, &Fontspec::roboto().glyph_cache.unwrap()));
+
+        // the default lines reuse the glyphs drawn by earlier lines
+        let mut image = DynamicImage::new_rgba8(300, 100).to_rgba();
+        let mut line = Line {
+            text: "cached".to_string(),
+            ..Line::default()
+        };
+        draw_line_at(&mut line, &mut image, 10.0, 10.0)?;
+        let hits = cache.hits();
+        draw_line_at(&mut line, &mut image, 10.0, 10.0)?;
+        assert!(cache.hits() >= hits + 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_blend_pixel_over_opaque() {
+        let mut pixel = Rgba {
+            data: [0, 0, 0, 255],
+        };
+        blend_pixel(&mut pixel, (255, 255, 255), 0.5);
+        assert_eq!([128, 128, 128, 255], pixel.data);
+    }
+
+    #[test]
+    fn test_blend_pixel_over_transparent() {
+        let mut pixel = Rgba { data: [0, 0, 0, 0] };
+        blend_pixel(&mut pixel, (255, 0, 0), 0.5);
+        assert_eq!([255, 0, 0, 128], pixel.data);
+
+        // a second layer on top keeps the colour of the first one where it is not covered
+        blend_pixel(&mut pixel, (0, 0, 255), 0.5);
+        assert_eq!([85, 0, 170, 192], pixel.data);
+    }
+
+    #[test]
+    fn test_outline_width_scales_with_font() {
+        let mut fontspec = Fontspec::impact();
+        let default_width = fontspec.get_outline_width();
+        fontspec.scale = Scale::uniform(fontspec.scale.y * 2.0);
+        assert_eq!(default_width * 2.0, fontspec.get_outline_width());
+        fontspec.outline_width = Some(1.5);
+        assert_eq!(1.5, fontspec.get_outline_width());
+    }
+
+    #[test]
+    fn test_outline_surrounds_glyphs() {
+        let mut fontspec = Fontspec::impact();
+        fontspec.outline_width = Some(4.0);
+        let glyphs: Vec<_> = fontspec
+            .font
+            .layout("I", fontspec.scale, point(20.0, 80.0))
+            .collect();
+        let bounding_box = glyphs[0].pixel_bounding_box().unwrap();
+        let mut image = DynamicImage::new_rgb8(100, 100).to_rgba();
+        image.pixels_mut().for_each(|pixel| pixel.data = [255, 0, 0, 255]);
+        let text = ShapedText {
+            glyphs,
+            ..ShapedText::default()
+        };
+        draw_glyphs_with_outline(&fontspec, &text, &mut image);
+
+        // the outline is as thick on straight strokes as it is on the corners
+        let mid_y = ((bounding_box.min.y + bounding_box.max.y) / 2) as u32;
+        let left = bounding_box.min.x as u32;
+        let top = bounding_box.min.y as u32;
+        let mid_x = ((bounding_box.min.x + bounding_box.max.x) / 2) as u32;
+        for d in 1..3 {
+            assert_eq!([0, 0, 0, 255], image.get_pixel(left - d, mid_y).data);
+            assert_eq!([0, 0, 0, 255], image.get_pixel(mid_x, top - d).data);
+        }
+        assert_eq!([255, 0, 0, 255], image.get_pixel(left - 6, mid_y).data);
+        image
+            .save("test_output/test_outline_surrounds_glyphs.png")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dilate_grows_a_disk() {
+        let mut mask = CoverageMask {
+            x: 0,
+            y: 0,
+            width: 21,
+            height: 21,
+            data: vec![0.0; 21 * 21],
+        };
+        mask.data[10 * 21 + 10] = 1.0;
+        let dilated = mask.dilate(3.0);
+        let at = |x: usize, y: usize| dilated.data[y * 21 + x];
+        assert_eq!(1.0, at(10, 10));
+        assert_eq!(1.0, at(12, 11));
+        // the edge of the disk is antialiased
+        assert_eq!(0.5, at(13, 10));
+        assert_eq!(0.5, at(10, 7));
+        assert_eq!(0.0, at(14, 10));
+        assert_eq!(0.0, at(13, 13));
+
+        // a wide outline costs no more than a narrow one, the mask stays the same size
+        let wide = mask.dilate(100.0);
+        assert!(wide.data.iter().all(|v| *v == 1.0));
+    }
+
+    #[test]
+    fn test_text_keeps_background_opaque() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgb8(300, 120).to_rgba();
+        let mut line = Line {
+            text: "Opaque".to_string(),
+            ..Line::default()
+        };
+        draw_line_at(&mut line, &mut image, 10.0, 10.0)?;
+        assert!(image.pixels().all(|pixel| pixel.data[3] == 255));
+        // antialiased edges blend into intermediate values
+        assert!(image
+            .pixels()
+            .any(|pixel| pixel.data[0] > 0 && pixel.data[0] < 255));
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_on_transparent_background() -> Result<(), MemegenError> {
+        let mut image = DynamicImage::new_rgba8(300, 120).to_rgba();
+        let mut line = Line {
+            text: "Alpha".to_string(),
+            ..Line::default()
+        };
+        draw_line_at(&mut line, &mut image, 10.0, 10.0)?;
+        assert!(image.pixels().any(|pixel| pixel.data[3] == 255));
+        assert_eq!(&Rgba { data: [0, 0, 0, 0] }, image.get_pixel(0, 0));
+        image.save("test_output/test_text_on_transparent_background.png")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_colour() {
+        assert_eq!(Some((255, 136, 0)), parse_colour("#ff8800"));
+        assert_eq!(Some((255, 136, 0)), parse_colour("FF8800"));
+        assert_eq!(Some((255, 136, 0)), parse_colour("#f80"));
+        assert_eq!(Some((255, 255, 255)), parse_colour("White"));
+        assert_eq!(None, parse_colour("#ff88"));
+        assert_eq!(None, parse_colour("#gg8800"));
+        assert_eq!(None, parse_colour("+ff880"));
+        assert_eq!(None, parse_colour("purple-ish"));
+    }
 }
@@ -1,52 +1,74 @@
+use crate::export::ExportOptions;
+use crate::fonts::FontRegistry;
 use crate::layout::RgbaImage;
-use crate::{draw_line, draw_line_at, Line};
+use crate::meme::{draw_layers, Placement, TextLayer};
+use crate::MemegenError;
 use image::GenericImage;
-use image::{DynamicImage, FilterType, ImageBuffer};
+use image::{DynamicImage, FilterType};
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 
+/// The length of the longer edge of a preview image in pixels
+const PREVIEW_SIZE: f32 = 1024.0;
+
 pub struct PreviewService {
     original_image: RgbaImage,
     preview_image: RgbaImage,
     rx: Receiver<UpdateRequest>,
-    tx: Sender<RgbaImage>,
-    lines: Vec<PositionedLine>,
-}
-
-pub struct PositionedLine {
-    pub line: Line,
-    pub position: Option<(i32, i32)>,
-    pub line_id: usize,
+    tx: Sender<Result<RgbaImage, MemegenError>>,
+    layers: Vec<TextLayer>,
+    fonts: FontRegistry,
 }
 
+/**
+    Changes to the layers of the previewed meme.
+    Layers are identified by their index, in the order they were added.
+*/
 pub enum UpdateRequest {
     InitialUpdate {
-        positioned_line: PositionedLine,
+        layer: TextLayer,
     },
     TextUpdate {
-        line_id: usize,
+        layer_id: usize,
         new_text: String,
     },
+    /// Moves the layer to a point, given in fractions of the image width and height
     PositionUpdate {
-        line_id: usize,
-        position: (i32, i32),
+        layer_id: usize,
+        position: (f32, f32),
+    },
+    /// Renders the full resolution image and writes it to the file, the result is sent to `done`
+    SaveUpdate {
+        path: PathBuf,
+        options: ExportOptions,
+        done: Sender<Result<(), MemegenError>>,
     },
-    SaveUpdate,
 }
 
 impl PreviewService {
-    pub fn new(image: RgbaImage) -> (Sender<UpdateRequest>, Receiver<RgbaImage>, PreviewService) {
+    /**
+        Creates the service for the image, with the sender of its requests
+        and the receiver of the previews, or of the errors of drawing them
+    */
+    pub fn new(
+        image: RgbaImage,
+    ) -> (
+        Sender<UpdateRequest>,
+        Receiver<Result<RgbaImage, MemegenError>>,
+        PreviewService,
+    ) {
         let preview = PreviewService::generate_preview(&image);
         let (tx_update, rx_update) = mpsc::channel();
         let (tx_image, rx_image) = mpsc::channel();
-        let lines = Vec::new();
         let service = PreviewService {
             original_image: image,
             preview_image: preview,
             rx: rx_update,
             tx: tx_image,
-            lines,
+            layers: Vec::new(),
+            fonts: FontRegistry::with_baked_fonts(),
         };
         (tx_update, rx_image, service)
     }
@@ -54,65 +76,83 @@ impl PreviewService {
         thread::spawn(move || {
             // extract the inner fields here so we do not need inner mutability on the `self` struct
             let rx = self.rx;
-            let mut lines = self.lines;
+            let mut layers = self.layers;
+            let fonts = self.fonts;
             let preview_image = self.preview_image;
             let tx = self.tx;
             let original_image = self.original_image;
 
             // iterate over all incoming requests
-            rx.iter().for_each(|req| {
+            for req in rx.iter() {
                 match req {
-                    UpdateRequest::InitialUpdate { positioned_line } => {
-                        lines.push(positioned_line);
+                    UpdateRequest::InitialUpdate { layer } => {
+                        layers.push(layer);
                     }
-                    UpdateRequest::PositionUpdate { line_id, position } => {
-                        match lines.get_mut(line_id) {
-                            Some(line) => {
-                                line.position = Some((position.0, position.1));
-                            }
-                            _ => {}
+                    UpdateRequest::PositionUpdate { layer_id, position } => {
+                        if let Some(layer) = layers.get_mut(layer_id) {
+                            layer.placement = Placement::Point {
+                                x: position.0,
+                                y: position.1,
+                            };
                         }
                     }
-                    UpdateRequest::TextUpdate { line_id, new_text } => {
-                        match lines.get_mut(line_id) {
-                            Some(mut line) => {
-                                line.line.text = new_text;
-                            }
-                            _ => {}
+                    UpdateRequest::TextUpdate { layer_id, new_text } => {
+                        if let Some(layer) = layers.get_mut(layer_id) {
+                            layer.text = new_text;
                         }
                     }
-                    UpdateRequest::SaveUpdate =>{
-                        println!("Would save OI with: {:?}",original_image.dimensions());
+                    UpdateRequest::SaveUpdate {
+                        path,
+                        options,
+                        done,
+                    } => {
+                        let saved = PreviewService::export(&original_image, &layers, &fonts)
+                            .and_then(|export| options.save(&export, None, &path));
+                        // the frontend may not wait for the result
+                        let _ = done.send(saved);
                     }
                 };
 
-                let (x, y) = preview_image.dimensions();
-                let mut result = ImageBuffer::new(x, y);
-                result.clone_from(&preview_image);
-
-                //draw all lines (if any)
-                lines.iter_mut().for_each(|line| match line.position {
-                    Some(pos) => {
-                        draw_line_at(&mut line.line, &mut result, pos.0 as f32, pos.1 as f32);
-                    }
-                    None => {
-                        draw_line(&mut line.line, &mut result);
-                    }
-                });
-                let send_res = tx.send(result);
-                if send_res.is_err() {
-                    println!("Error sending: {}", send_res.unwrap_err())
+                //draw all layers (if any)
+                let mut result = preview_image.clone();
+                let drawn = draw_layers(&layers, &fonts, &mut result).map(|()| result);
+                if tx.send(drawn).is_err() {
+                    // nobody receives the previews anymore
+                    break;
                 }
-            });
+            }
         });
     }
 
+    /**
+        Renders the layers onto a copy of the full resolution original image.
+        The layers are positioned relative to the image, so they look like on the preview.
+    */
+    pub fn export(
+        original_image: &RgbaImage,
+        layers: &[TextLayer],
+        fonts: &FontRegistry,
+    ) -> Result<RgbaImage, MemegenError> {
+        let mut result = original_image.clone();
+        draw_layers(layers, fonts, &mut result)?;
+        Ok(result)
+    }
+
+    /**
+        The factor by which an image of the given dimensions is resized to obtain its preview
+    */
+    pub fn preview_ratio(dimensions: (u32, u32)) -> f32 {
+        let (x, y) = dimensions;
+        if x > y {
+            PREVIEW_SIZE / (x as f32)
+        } else {
+            PREVIEW_SIZE / (y as f32)
+        }
+    }
+
     pub fn generate_preview(image: &RgbaImage) -> RgbaImage {
         let (x, y) = image.dimensions();
-        let cropfactor = match x > y {
-            true => 1024.0 / (x as f32),
-            _ => 1024.0 / (y as f32),
-        };
+        let cropfactor = PreviewService::preview_ratio((x, y));
         let new_x = (x as f32 * cropfactor) as u32;
         let new_y = (y as f32 * cropfactor) as u32;
         let mut preview = DynamicImage::new_rgb8(x, y);
@@ -124,9 +164,11 @@ impl PreviewService {
 
 #[cfg(test)]
 mod tests {
-    use crate::service::{PositionedLine, PreviewService, UpdateRequest};
-    use crate::Line;
+    use crate::service::{PreviewService, UpdateRequest};
+    use crate::{ExportOptions, FontRegistry, MemegenError, Placement, TextLayer, TextStyle};
     use image::DynamicImage;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
     use std::time::Duration;
 
     #[test]
@@ -134,57 +176,120 @@ mod tests {
         let image = DynamicImage::new_rgb8(1920, 1080).to_rgba();
         let (_, _, preview) = PreviewService::new(image);
         //maxwidth
-        assert_eq!(512, preview.preview_image.width());
-        //9:16*512=288
-        assert_eq!(288, preview.preview_image.height());
+        assert_eq!(1024, preview.preview_image.width());
+        //9:16*1024=576
+        assert_eq!(576, preview.preview_image.height());
 
         let portrait_image = DynamicImage::new_rgb8(1080, 1920).to_rgba();
         let (_, _, portrait_preview) = PreviewService::new(portrait_image);
         //maxheight
-        assert_eq!(512, portrait_preview.preview_image.height());
-        //9:16*512=288
-        assert_eq!(288, portrait_preview.preview_image.width());
+        assert_eq!(1024, portrait_preview.preview_image.height());
+        //9:16*1024=576
+        assert_eq!(576, portrait_preview.preview_image.width());
     }
 
     #[test]
     fn test_service_start_send_receive() {
         let image = DynamicImage::new_rgb8(1920, 1080).to_rgba();
-        let (tx, rx, mut preview) = PreviewService::new(image);
+        let (tx, rx, preview) = PreviewService::new(image);
 
         preview.start();
 
         tx.send(UpdateRequest::InitialUpdate {
-            positioned_line: PositionedLine {
-                line: Line {
-                    text: "Test".to_string(),
-                    ..Line::default()
-                },
-                position: None,
-                line_id: 0,
-            },
-        });
+            layer: TextLayer::new("Test", Placement::default()),
+        })
+        .unwrap();
 
         tx.send(UpdateRequest::PositionUpdate {
-            line_id: 0,
-            position: (100, 100),
-        });
+            layer_id: 0,
+            position: (0.1, 0.1),
+        })
+        .unwrap();
 
         match rx.recv_timeout(Duration::from_secs(10)) {
-            Ok(res) => {
-                assert_eq!(512, res.width());
+            Ok(Ok(res)) => {
+                assert_eq!(1024, res.width());
                 res.save("test_output/test_service_start_send_receive_1.jpg")
                     .unwrap();
             }
-            Err(err) => panic!("Errored on receiving"),
+            _ => panic!("Errored on receiving"),
         }
 
         match rx.recv_timeout(Duration::from_secs(10)) {
-            Ok(res) => {
-                assert_eq!(512, res.width());
+            Ok(Ok(res)) => {
+                assert_eq!(1024, res.width());
                 res.save("test_output/test_service_start_send_receive_2.jpg")
                     .unwrap();
             }
-            Err(err) => panic!("Errored on receiving"),
+            _ => panic!("Errored on receiving"),
         }
     }
+
+    #[test]
+    fn test_service_reports_errors() {
+        let image = DynamicImage::new_rgb8(200, 100).to_rgba();
+        let (tx, rx, preview) = PreviewService::new(image);
+        preview.start();
+
+        // the layer cannot be drawn in a font size of 0
+        let layer = TextLayer {
+            style: TextStyle {
+                size: 0.0,
+                ..TextStyle::default()
+            },
+            ..TextLayer::new("Test", Placement::default())
+        };
+        tx.send(UpdateRequest::InitialUpdate { layer }).unwrap();
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Err(MemegenError::InvalidScale(_))) => {}
+            _ => panic!("The preview cannot be drawn"),
+        }
+
+        // neither can the full resolution image
+        let (done, saved) = mpsc::channel();
+        tx.send(UpdateRequest::SaveUpdate {
+            path: PathBuf::from("test_output/test_service_reports_errors.png"),
+            options: ExportOptions::default(),
+            done,
+        })
+        .unwrap();
+        match saved.recv_timeout(Duration::from_secs(10)) {
+            Ok(Err(MemegenError::InvalidScale(_))) => {}
+            _ => panic!("The export failed"),
+        }
+    }
+
+    #[test]
+    fn test_export_keeps_original_resolution() -> Result<(), MemegenError> {
+        let image = DynamicImage::new_rgb8(2048, 1024).to_rgba();
+        assert_eq!(0.5, PreviewService::preview_ratio(image.dimensions()));
+
+        // a layer at (100, 100) with a font size of 64 pixels on the 1024x512 preview
+        let layers = vec![TextLayer {
+            style: TextStyle {
+                size: 0.125,
+                ..TextStyle::default()
+            },
+            ..TextLayer::new(
+                "Test",
+                Placement::Point {
+                    x: 100.0 / 1024.0,
+                    y: 100.0 / 512.0,
+                },
+            )
+        }];
+        let export = PreviewService::export(&image, &layers, &FontRegistry::with_baked_fonts())?;
+        assert_eq!((2048, 1024), export.dimensions());
+
+        // the line was drawn at twice the preview position and font size
+        let drawn_rows: Vec<u32> = (0..export.height())
+            .filter(|y| (0..export.width()).any(|x| export.get_pixel(x, *y).data[0] > 0))
+            .collect();
+        let first_row = *drawn_rows.first().unwrap();
+        let last_row = *drawn_rows.last().unwrap();
+        assert!(first_row > 190);
+        assert!(last_row - first_row > 64);
+        export.save("test_output/test_export_keeps_original_resolution.png")?;
+        Ok(())
+    }
 }
@@ -0,0 +1,293 @@
+use rusttype::{point, vector, Font, GlyphId, Point, PositionedGlyph, Scale};
+use rustybuzz::{Direction, UnicodeBuffer};
+use unicode_bidi::ParagraphBidiInfo;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::emoji::PlacedColourGlyph;
+use crate::glyph_cache::FontId;
+use crate::rendering::{FontSource, Fontspec};
+
+/**
+    The glyphs of a row of text.
+    Outline glyphs are drawn in the colours of the fontspec, colour glyphs keep their own colours
+    and are only rendered when the text is drawn.
+*/
+#[derive(Default)]
+pub struct ShapedText {
+    pub glyphs: Vec<PositionedGlyph<'static>>,
+    /// The font of each glyph in `glyphs`, glyphs without a font are not cached
+    pub glyph_fonts: Vec<FontId>,
+    pub colour_glyphs: Vec<PlacedColourGlyph>,
+}
+
+impl ShapedText {
+    /**
+        The leftmost and rightmost pixel column covered by any glyph, `None` if nothing is drawn
+    */
+    pub fn pixel_bounds_x(&self) -> Option<(i32, i32)> {
+        let outline_bounds = self
+            .glyphs
+            .iter()
+            .filter_map(|glyph| glyph.pixel_bounding_box())
+            .map(|bb| (bb.min.x, bb.max.x));
+        let colour_bounds = self
+            .colour_glyphs
+            .iter()
+            .map(|glyph| glyph.x_range);
+        outline_bounds
+            .chain(colour_bounds)
+            .fold(None, |bounds, (min_x, max_x)| match bounds {
+                None => Some((min_x, max_x)),
+                Some((min, max)) => Some((min.min(min_x), max.max(max_x))),
+            })
+    }
+}
+
+/**
+    Lays out the text in a single row starting at the baseline point `start`.
+
+    The text is reordered with the unicode bidirectional algorithm first,
+    then each directional run is split by the fonts of the fallback chain
+    and every font run is shaped on its own, so ligatures, joining forms and marks are applied.
+*/
+pub fn shape_text(fontspec: &Fontspec, text: &str, start: Point<f32>) -> ShapedText {
+    let mut shaped = ShapedText::default();
+    if text.is_empty() {
+        return shaped;
+    }
+    let bidi = ParagraphBidiInfo::new(text, None);
+    let (levels, runs) = bidi.visual_runs(0..text.len());
+
+    let mut x_pos = start.x;
+    for run in runs {
+        let rtl = levels[run.start].is_rtl();
+        let mut font_runs = font_runs(fontspec, &text[run]);
+        // the font runs are in logical order, right to left runs are placed from their end
+        if rtl {
+            font_runs.reverse();
+        }
+        for (font, source, run_text) in font_runs {
+            let run_start = point(x_pos, start.y);
+            x_pos = shape_run(
+                font,
+                source,
+                fontspec.scale,
+                run_text,
+                rtl,
+                run_start,
+                &mut shaped,
+            );
+        }
+    }
+    shaped
+}
+
+/**
+    Whether the base direction of the text is right to left
+*/
+pub fn is_rtl(text: &str) -> bool {
+    ParagraphBidiInfo::new(text, None).paragraph_level.is_rtl()
+}
+
+/**
+    Shapes a run of text in a single font and direction and appends its glyphs.
+    Glyphs the font defines in colour are placed, but not rendered yet.
+    Returns the x position behind the last glyph of the run.
+*/
+fn shape_run(
+    font: &Font<'static>,
+    source: &FontSource,
+    scale: Scale,
+    text: &str,
+    rtl: bool,
+    start: Point<f32>,
+    shaped: &mut ShapedText,
+) -> f32 {
+    let face = match rustybuzz::Face::from_slice(&source.data, source.index) {
+        Some(face) => face,
+        None => {
+            // fonts the shaper cannot read are laid out glyph by glyph
+            let glyph_count = shaped.glyphs.len();
+            shaped.glyphs.extend(font.layout(text, scale, start));
+            shaped.glyph_fonts.resize(shaped.glyphs.len(), source.id);
+            return shaped.glyphs[glyph_count..]
+                .last()
+                .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+                .unwrap_or(start.x);
+        }
+    };
+
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.set_direction(if rtl {
+        Direction::RightToLeft
+    } else {
+        Direction::LeftToRight
+    });
+    buffer.guess_segment_properties();
+    let output = rustybuzz::shape(&face, &[], buffer);
+
+    // the shaper works in font units, rusttype scales the font height to `scale.y` pixels
+    let v_metrics = font.v_metrics_unscaled();
+    let units_to_px_y = scale.y / (v_metrics.ascent - v_metrics.descent);
+    let units_to_px_x = units_to_px_y * scale.x / scale.y;
+
+    let mut x_pos = start.x;
+    for (info, position) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+        let glyph_pos = point(
+            x_pos + position.x_offset as f32 * units_to_px_x,
+            start.y - position.y_offset as f32 * units_to_px_y,
+        );
+        let units_to_px = vector(units_to_px_x, units_to_px_y);
+        let glyph_id = info.glyph_id as u16;
+        match PlacedColourGlyph::place(&face, source, glyph_id, units_to_px, glyph_pos) {
+            Some(colour_glyph) => shaped.colour_glyphs.push(colour_glyph),
+            None => {
+                shaped.glyphs.push(
+                    font.glyph(GlyphId(info.glyph_id))
+                        .scaled(scale)
+                        .positioned(glyph_pos),
+                );
+                shaped.glyph_fonts.push(source.id);
+            }
+        }
+        x_pos += position.x_advance as f32 * units_to_px_x;
+    }
+    x_pos
+}
+
+/**
+    Splits the text into runs of graphemes that are rendered with the same font.
+    A grapheme is rendered with the first font of the fallback chain that has glyphs
+    for all of its characters, if there is none, the primary font is used.
+*/
+fn font_runs<'a>(
+    fontspec: &'a Fontspec,
+    text: &'a str,
+) -> Vec<(&'a Font<'static>, &'a FontSource, &'a str)> {
+    let fonts: Vec<(&Font<'static>, &FontSource)> =
+        std::iter::once((&fontspec.font, &fontspec.source))
+            .chain(
+                fontspec
+                    .fallbacks
+                    .iter()
+                    .map(|face| (&face.font, &face.source)),
+            )
+            .collect();
+
+    let mut runs = Vec::new();
+    let mut run_start = 0;
+    let mut run_font = 0;
+    for (idx, grapheme) in text.grapheme_indices(true) {
+        let font = fonts
+            .iter()
+            .position(|(font, _)| grapheme.chars().all(|c| font.glyph(c).id().0 != 0))
+            .unwrap_or(0);
+        if font != run_font && idx > run_start {
+            let (font, source) = fonts[run_font];
+            runs.push((font, source, &text[run_start..idx]));
+            run_start = idx;
+        }
+        run_font = font;
+    }
+    if run_start < text.len() {
+        let (font, source) = fonts[run_font];
+        runs.push((font, source, &text[run_start..]));
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::FontRegistry;
+
+    /**
+        A font with hebrew and arabic glyphs, written by `res/test/make_rtl_font.py`
+    */
+    fn rtl_fontspec() -> Fontspec {
+        let mut registry = FontRegistry::new();
+        registry.load_file("res/test/rtl.ttf").unwrap();
+        registry.fontspec("Test RTL", None).unwrap()
+    }
+
+    #[test]
+    fn test_font_runs_use_fallbacks() {
+        let fontspec = Fontspec {
+            fallbacks: FontRegistry::with_baked_fonts().fallbacks(&["Impact"]),
+            ..Fontspec::roboto()
+        };
+        // roboto has no glyph for the heart, impact has one
+        let runs: Vec<_> = font_runs(&fontspec, "I ♥ memes")
+            .into_iter()
+            .map(|(_, _, run)| run)
+            .collect();
+        assert_eq!(vec!["I ", "♥", " memes"], runs);
+
+        let glyphs = shape_text(&fontspec, "I ♥ memes", point(0.0, 0.0)).glyphs;
+        assert!(glyphs.iter().all(|glyph| glyph.id().0 != 0));
+        // the runs are placed after each other
+        assert!(glyphs
+            .windows(2)
+            .all(|pair| pair[0].position().x < pair[1].position().x));
+    }
+
+    #[test]
+    fn test_font_runs_without_fallbacks() {
+        let fontspec = Fontspec::roboto();
+        let runs = font_runs(&fontspec, "I ♥ memes");
+        assert_eq!(1, runs.len());
+        assert!(font_runs(&fontspec, "").is_empty());
+    }
+
+    #[test]
+    fn test_shaping_matches_simple_layout() {
+        let fontspec = Fontspec::impact();
+        let text = "One does not simply";
+        let shaped = shape_text(&fontspec, text, point(10.0, 50.0)).glyphs;
+        let laid_out: Vec<_> = fontspec
+            .font
+            .layout(text, fontspec.scale, point(10.0, 50.0))
+            .collect();
+        assert_eq!(laid_out.len(), shaped.len());
+        for (shaped, laid_out) in shaped.iter().zip(laid_out.iter()) {
+            assert_eq!(laid_out.id(), shaped.id());
+            assert!((laid_out.position().x - shaped.position().x).abs() < 1.0);
+            assert_eq!(laid_out.position().y, shaped.position().y);
+        }
+    }
+
+    #[test]
+    fn test_is_rtl() {
+        assert!(!is_rtl("memes"));
+        assert!(is_rtl("שלום memes"));
+        assert!(is_rtl("مرحبا"));
+    }
+
+    #[test]
+    fn test_rtl_runs_are_reordered() {
+        let fontspec = rtl_fontspec();
+        let text = "ab שלום";
+        let glyphs = shape_text(&fontspec, text, point(0.0, 0.0)).glyphs;
+        assert_eq!(7, glyphs.len());
+        let shin = fontspec.font.glyph('ש').id();
+        let mem = fontspec.font.glyph('ם').id();
+        // the first hebrew letter is the right most glyph
+        assert_eq!(shin, glyphs[6].id());
+        assert_eq!(mem, glyphs[3].id());
+    }
+
+    #[test]
+    fn test_arabic_letters_are_joined() {
+        let fontspec = rtl_fontspec();
+        let isolated = fontspec.font.glyph('ب').id();
+        let glyphs = shape_text(&fontspec, "ببب", point(0.0, 0.0)).glyphs;
+        assert_eq!(3, glyphs.len());
+        // all letters take their initial, medial and final forms
+        assert!(glyphs.iter().all(|glyph| glyph.id() != isolated));
+        // and are placed from right to left
+        assert!(glyphs
+            .windows(2)
+            .all(|pair| pair[0].position().x < pair[1].position().x));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::MemegenError;
+use crate::fonts::FontRegistry;
+use crate::layout::{RgbaImage, VerticalAlignment};
+use crate::meme::{render_with_fonts, Background, Meme, Placement, TextLayer, TextStyle};
+
+/// The name of the manifest file in a template directory
+pub const TEMPLATE_MANIFEST: &str = "template.toml";
+
+/**
+    A meme format with a fixed background and named caption slots.
+    The manifest of a template is a `template.toml` file next to its background image.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Template {
+    /// The name of the template directory, which is used to look the template up
+    #[serde(skip)]
+    pub id: String,
+    /// The human readable name of the format
+    pub name: String,
+    /// The background image, relative to the template directory
+    pub background: PathBuf,
+    /// The SHA-256 of the background image, it is read once when the template is loaded
+    #[serde(skip)]
+    pub background_hash: [u8; 32],
+    pub slots: Vec<TextSlot>,
+}
+
+/**
+    A named text box of a template.
+    The box is given in fractions of the image width and height, like the `Placement::Box` it becomes.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TextSlot {
+    pub name: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    #[serde(default = "default_vertical_alignment")]
+    pub vertical_alignment: VerticalAlignment,
+    /// The style of the text, the font size is the largest size the text is drawn with
+    #[serde(default)]
+    pub style: TextStyle,
+}
+
+fn default_vertical_alignment() -> VerticalAlignment {
+    VerticalAlignment::Middle
+}
+
+impl Template {
+    /**
+        Reads the manifest of the template in the directory.
+        The background path is resolved against the directory and the background is hashed.
+    */
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Template, MemegenError> {
+        let dir = dir.as_ref();
+        let manifest = fs::read_to_string(dir.join(TEMPLATE_MANIFEST))?;
+        let mut template: Template = toml::from_str(&manifest)
+            .map_err(|err| MemegenError::Template(format!("{}: {}", dir.display(), err)))?;
+        template.id = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        template.background = dir.join(&template.background);
+        template.background_hash = Sha256::digest(fs::read(&template.background)?).into();
+        Ok(template)
+    }
+
+    pub fn slot(&self, name: &str) -> Option<&TextSlot> {
+        self.slots.iter().find(|slot| slot.name == name)
+    }
+
+    /**
+        Creates a meme with the captions in the slots of their names.
+        Slots without a caption stay empty, captions for slots the template does not have are an error.
+    */
+    pub fn fill(&self, captions: &HashMap<String, String>) -> Result<Meme, MemegenError> {
+        if let Some(unknown) = captions.keys().find(|name| self.slot(name).is_none()) {
+            return Err(MemegenError::UnknownSlot(unknown.clone()));
+        }
+        let layers = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                captions.get(&slot.name).map(|text| TextLayer {
+                    text: text.clone(),
+                    style: slot.style.clone(),
+                    placement: Placement::Box {
+                        x: slot.x,
+                        y: slot.y,
+                        width: slot.width,
+                        height: slot.height,
+                        vertical_alignment: slot.vertical_alignment.clone(),
+                    },
+                    visible: None,
+                })
+            })
+            .collect();
+        Ok(Meme {
+            background: Background::File {
+                path: self.background.clone(),
+            },
+            layers,
+        })
+    }
+
+    /**
+        Fills the template with the captions and renders it with the fonts of the registry
+    */
+    pub fn render(
+        &self,
+        captions: &HashMap<String, String>,
+        fonts: &FontRegistry,
+    ) -> Result<RgbaImage, MemegenError> {
+        render_with_fonts(&self.fill(captions)?, fonts)
+    }
+}
+
+/**
+    A collection of templates that can be looked up by their ids
+*/
+#[derive(Clone, Debug, Default)]
+pub struct TemplateLibrary {
+    templates: Vec<Template>,
+}
+
+impl TemplateLibrary {
+    pub fn new() -> TemplateLibrary {
+        TemplateLibrary::default()
+    }
+
+    /**
+        Loads the template in the directory, replacing a loaded template with the same id
+    */
+    pub fn load_template<P: AsRef<Path>>(&mut self, dir: P) -> Result<&Template, MemegenError> {
+        let template = Template::load(dir)?;
+        self.templates.retain(|other| other.id != template.id);
+        self.templates.push(template);
+        Ok(&self.templates[self.templates.len() - 1])
+    }
+
+    /**
+        Loads all templates in the subdirectories of the directory, returning the number of templates that were added.
+        Directories without a manifest and invalid manifests are skipped.
+    */
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> usize {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+        let mut dirs: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.join(TEMPLATE_MANIFEST).is_file())
+            .collect();
+        dirs.sort();
+        dirs.iter()
+            .filter(|dir| self.load_template(dir).is_ok())
+            .count()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Template> {
+        self.templates.iter().find(|template| template.id == id)
+    }
+
+    /// The templates in the order they were loaded
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE_DIR: &str = "res/test/templates";
+
+    fn captions(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, text)| (name.to_string(), text.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_load_template() -> Result<(), MemegenError> {
+        let template = Template::load(Path::new(TEMPLATE_DIR).join("two_panels"))?;
+        assert_eq!("two_panels", template.id);
+        assert_eq!("Two Panels", template.name);
+        assert_eq!(
+            Path::new(TEMPLATE_DIR).join("two_panels/background.png"),
+            template.background
+        );
+        assert_eq!(2, template.slots.len());
+        let bottom = template.slot("bottom").unwrap();
+        assert_eq!(VerticalAlignment::Top, bottom.vertical_alignment);
+        assert_eq!((0, 0, 0), bottom.style.fill);
+        assert_eq!(
+            VerticalAlignment::Middle,
+            template.slot("top").unwrap().vertical_alignment
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_template_dir() {
+        let mut library = TemplateLibrary::new();
+        // the directory without a manifest is skipped
+        assert_eq!(1, library.load_dir(TEMPLATE_DIR));
+        assert!(library.get("two_panels").is_some());
+        assert!(library.get("broken").is_none());
+        assert_eq!(0, library.load_dir("res/does_not_exist"));
+    }
+
+    #[test]
+    fn test_fill_template() -> Result<(), MemegenError> {
+        let template = Template::load(Path::new(TEMPLATE_DIR).join("two_panels"))?;
+        let meme = template.fill(&captions(&[("bottom", "only the bottom")]))?;
+        assert_eq!(1, meme.layers.len());
+        assert_eq!("only the bottom", meme.layers[0].text);
+
+        match template.fill(&captions(&[("middle", "nope")])) {
+            Err(MemegenError::UnknownSlot(slot)) => assert_eq!("middle", slot),
+            _ => panic!("The template has no middle slot"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_template() -> Result<(), MemegenError> {
+        let template = Template::load(Path::new(TEMPLATE_DIR).join("two_panels"))?;
+        let image = template.render(
+            &captions(&[
+                ("top", "writing memes by hand"),
+                ("bottom", "filling templates"),
+            ]),
+            &FontRegistry::with_baked_fonts(),
+        )?;
+        let (width, height) = image.dimensions();
+        // the captions are drawn into the right half of the panels
+        let drawn = |x_range: std::ops::Range<u32>, y_range: std::ops::Range<u32>| {
+            y_range.clone().any(|y| {
+                x_range
+                    .clone()
+                    .any(|x| image.get_pixel(x, y).data == [255, 255, 255, 255])
+            })
+        };
+        assert!(drawn(width / 2..width, 0..height / 2));
+        assert!(!drawn(0..width / 2, 0..height));
+        image.save("test_output/test_render_template.png")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use image_webp::{ColorType, EncodingError, WebPEncoder};
+
+use crate::error::MemegenError;
+use crate::layout::RgbaImage;
+
+/// The largest width and height of a WebP image
+const WEBP_MAX_DIMENSION: u32 = 1 << 14;
+
+/**
+    Encodes the image as a lossless WebP image.
+    Opaque images are stored without an alpha channel, the EXIF data is stored in the extended format.
+*/
+pub fn encode_webp(image: &RgbaImage, exif: Option<&[u8]>) -> Result<Vec<u8>, MemegenError> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(MemegenError::EmptyImage);
+    }
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        return Err(MemegenError::Export(format!(
+            "WebP images can be at most {} pixels wide, not {}x{}",
+            WEBP_MAX_DIMENSION, width, height
+        )));
+    }
+    let opaque = image.pixels().all(|pixel| pixel.data[3] == 255);
+    let rgb: Vec<u8>;
+    let (pixels, colour): (&[u8], _) = if opaque {
+        rgb = image.pixels().flat_map(|pixel| pixel.data[..3].to_vec()).collect();
+        (&rgb, ColorType::Rgb8)
+    } else {
+        (image, ColorType::Rgba8)
+    };
+
+    let mut data = Vec::new();
+    let mut encoder = WebPEncoder::new(&mut data);
+    if let Some(exif) = exif {
+        encoder.set_exif_metadata(exif.to_vec());
+    }
+    encoder
+        .encode(pixels, width, height, colour)
+        .map_err(|err| match err {
+            EncodingError::IoError(err) => MemegenError::Io(err),
+            err => MemegenError::Export(err.to_string()),
+        })?;
+    Ok(data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::Rgba;
+    use image_webp::WebPDecoder;
+    use std::io::Cursor;
+
+    /**
+        Decodes the WebP image with the decoder of `image-webp`, with its EXIF data if it has any
+    */
+    pub(crate) fn decode_webp(data: &[u8]) -> (RgbaImage, Option<Vec<u8>>) {
+        let mut decoder = WebPDecoder::new(Cursor::new(data)).unwrap();
+        let (width, height) = decoder.dimensions();
+        let channels = if decoder.has_alpha() { 4 } else { 3 };
+        let mut pixels = vec![0; decoder.output_buffer_size().unwrap()];
+        decoder.read_image(&mut pixels).unwrap();
+        let exif = decoder.exif_metadata().unwrap();
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let idx = (y * width + x) as usize * channels;
+            let alpha = if channels == 4 { pixels[idx + 3] } else { 255 };
+            Rgba([pixels[idx], pixels[idx + 1], pixels[idx + 2], alpha])
+        });
+        (image, exif)
+    }
+
+    /**
+        Pseudo random pixels, from a linear congruential generator
+    */
+    fn noise(width: u32, height: u32, seed: u32) -> RgbaImage {
+        let mut state = seed;
+        RgbaImage::from_fn(width, height, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [r, g, b, a] = state.to_be_bytes();
+            Rgba([r, g, b, a])
+        })
+    }
+
+    fn assert_round_trip(image: &RgbaImage) -> usize {
+        let data = encode_webp(image, None).unwrap();
+        let (decoded, _) = decode_webp(&data);
+        assert_eq!(image.dimensions(), decoded.dimensions());
+        assert!(image.pixels().zip(decoded.pixels()).all(|(a, b)| a == b));
+        data.len()
+    }
+
+    #[test]
+    fn test_webp_round_trip() {
+        assert_round_trip(&noise(1, 1, 1));
+        assert_round_trip(&noise(1, 37, 2));
+        assert_round_trip(&noise(45, 33, 4));
+        let solid = RgbaImage::from_pixel(300, 200, Rgba([40, 80, 120, 255]));
+        assert!(assert_round_trip(&solid) < 1000);
+        let gradient = RgbaImage::from_fn(200, 150, |x, y| {
+            let alpha = if (x / 10) % 2 == 0 { 255 } else { 0 };
+            Rgba([x as u8, y as u8, (x + y) as u8, alpha])
+        });
+        assert_round_trip(&gradient);
+    }
+
+    #[test]
+    fn test_webp_compresses_photos() {
+        let photo = image::open("res/images/puffin.jpg").unwrap().to_rgba();
+        let (width, height) = photo.dimensions();
+        let size = assert_round_trip(&photo);
+        // uncompressed pixels take three bytes each without alpha
+        let raw = (width * height * 3) as usize;
+        assert!(size < raw * 3 / 4, "{} of {} bytes", size, raw);
+    }
+
+    #[test]
+    fn test_webp_exif() {
+        let image = RgbaImage::from_pixel(20, 10, Rgba([0, 0, 0, 255]));
+        let exif = b"MM\0\x2a\0\0\0\x08\0\0".to_vec();
+        let data = encode_webp(&image, Some(&exif)).unwrap();
+        assert_eq!(b"VP8X", &data[12..16]);
+        assert_eq!((image.into_raw(), Some(exif)), {
+            let (decoded, exif) = decode_webp(&data);
+            (decoded.into_raw(), exif)
+        });
+        assert!(encode_webp(&RgbaImage::new(WEBP_MAX_DIMENSION + 1, 1), None).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+
+use image::{ImageDecoder, ImageFormat};
+use memegen_lib::{
+    percent_decode, Background, ExportFormat, ExportOptions, FontError, Meme, MemegenError,
+    Placement, Visibility,
+};
+use serde::Serialize;
+
+/**
+    The limits requests are checked against before anything is rendered
+*/
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// The largest width and height of a background in pixels
+    pub max_dimension: u32,
+    /// The largest request body in bytes
+    pub max_body_size: usize,
+    /// The largest number of text layers of a meme
+    pub max_layers: usize,
+    /// The largest number of characters of a single text layer
+    pub max_text_length: usize,
+    /// The largest font size in pixels of the background
+    pub max_font_size: f32,
+    /// The largest outline width in pixels of the background
+    pub max_outline_width: f32,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_dimension: 4096,
+            max_body_size: 16 * 1024 * 1024,
+            max_layers: 32,
+            max_text_length: 1000,
+            max_font_size: 512.0,
+            max_outline_width: 32.0,
+        }
+    }
+}
+
+/**
+    An error that is sent to the client as JSON, with the HTTP status code it is sent with
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn bad_request<S: Into<String>>(message: S) -> ApiError {
+        ApiError {
+            status: 400,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> ApiError {
+        ApiError {
+            status: 404,
+            message: message.into(),
+        }
+    }
+
+    /**
+        The body of the error response, e.g. `{"status":404,"error":"unknown template foo"}`
+    */
+    pub fn to_json(&self) -> String {
+        #[derive(Serialize)]
+        struct ErrorBody<'a> {
+            status: u16,
+            error: &'a str,
+        }
+        serde_json::to_string(&ErrorBody {
+            status: self.status,
+            error: &self.message,
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+/**
+    Rendering errors caused by the request are client errors, everything else is a server error
+*/
+impl From<MemegenError> for ApiError {
+    fn from(err: MemegenError) -> ApiError {
+        let status = match &err {
+            MemegenError::Image(_)
+            | MemegenError::EmptyImage
+            | MemegenError::InvalidScale(_)
+            | MemegenError::InvalidOutline(_)
+            | MemegenError::UnknownSlot(_)
+            | MemegenError::Link(_)
+            | MemegenError::Export(_)
+            | MemegenError::Font(FontError::NotFound(_)) => 400,
+            MemegenError::UnknownTemplate(_) => 404,
+            _ => 500,
+        };
+        ApiError {
+            status,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// The query parameters that choose the export options, they are not captions of a template
+pub const EXPORT_PARAMETERS: [&str; 3] = ["format", "quality", "compression"];
+
+/**
+    Picks the export options by the `format`, `quality` and `compression` query parameters,
+    or the format by the `Accept` header. PNG is the default.
+    EXIF data is never kept, the metadata of uploaded images stays private.
+*/
+pub fn negotiate_export(
+    query: &HashMap<String, String>,
+    accept: Option<&str>,
+) -> Result<ExportOptions, ApiError> {
+    let format = match query.get("format") {
+        Some(format) => format.parse().map_err(ApiError::bad_request)?,
+        None => {
+            let accepts_jpeg = accept
+                .map(|accept| accept.contains("image/jpeg") && !accept.contains("image/png"))
+                .unwrap_or(false);
+            if accepts_jpeg {
+                ExportFormat::Jpeg
+            } else {
+                ExportFormat::Png
+            }
+        }
+    };
+    let mut options = ExportOptions::new(format);
+    if let Some(quality) = query.get("quality") {
+        options.jpeg_quality = quality
+            .parse()
+            .ok()
+            .filter(|quality| (1..=100).contains(quality))
+            .ok_or_else(|| {
+                ApiError::bad_request(format!("invalid quality {}, expected 1 to 100", quality))
+            })?;
+    }
+    if let Some(compression) = query.get("compression") {
+        options.png_compression = compression.parse().map_err(ApiError::bad_request)?;
+    }
+    Ok(options)
+}
+
+/**
+    The export options of a meme link with the extension
+*/
+pub fn export_from_extension(extension: &str) -> Result<ExportOptions, ApiError> {
+    ExportFormat::from_extension(extension)
+        .map(ExportOptions::new)
+        .ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "memes cannot be rendered as {}, use png, jpg or gif",
+                extension
+            ))
+        })
+}
+
+/**
+    Parses the query of a URL, e.g. `top=hello%20world&format=png`.
+    `+` is decoded as a space, like in HTML forms.
+*/
+pub fn parse_query(query: &str) -> Result<HashMap<String, String>, ApiError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = decode_component(parts.next().unwrap_or_default())?;
+            let value = decode_component(parts.next().unwrap_or_default())?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/**
+    Decodes a percent-encoded component of a URL, a `+` is a space like in form data
+*/
+pub fn decode_component(component: &str) -> Result<String, ApiError> {
+    percent_decode(&component.replace('+', " "))
+        .ok_or_else(|| ApiError::bad_request(format!("invalid URL encoding: {}", component)))
+}
+
+/**
+    Checks the meme against the limits before it is rendered.
+    Backgrounds must be sent along, the server does not read files the client names.
+*/
+pub fn validate_meme(meme: &Meme, limits: &Limits) -> Result<(), ApiError> {
+    let (width, height) =
+        match &meme.background {
+            Background::File { .. } => return Err(ApiError::bad_request(
+                "file backgrounds are not allowed, send the image encoded or a solid background",
+            )),
+            Background::Encoded { data } => encoded_dimensions(data)?,
+            Background::Solid { width, height, .. } => (*width, *height),
+        };
+    if width == 0 || height == 0 {
+        return Err(ApiError::bad_request("the background has no pixels"));
+    }
+    if width > limits.max_dimension || height > limits.max_dimension {
+        return Err(ApiError::bad_request(format!(
+            "the background is {}x{} pixels, the largest allowed size is {}x{}",
+            width, height, limits.max_dimension, limits.max_dimension
+        )));
+    }
+
+    if meme.layers.len() > limits.max_layers {
+        return Err(ApiError::bad_request(format!(
+            "there are {} text layers, at most {} are allowed",
+            meme.layers.len(),
+            limits.max_layers
+        )));
+    }
+    for (idx, layer) in meme.layers.iter().enumerate() {
+        let invalid = |what: &str| Err(ApiError::bad_request(format!("layer {}: {}", idx, what)));
+        if layer.text.chars().count() > limits.max_text_length {
+            return invalid(&format!(
+                "the text is longer than {} characters",
+                limits.max_text_length
+            ));
+        }
+        if !(layer.style.size > 0.0 && layer.style.size <= 1.0) {
+            return invalid("the size must be a fraction of the image height");
+        }
+        if layer.style.size * height as f32 > limits.max_font_size {
+            return invalid(&format!(
+                "the font is larger than {} pixels",
+                limits.max_font_size
+            ));
+        }
+        if let Some(outline_width) = layer.style.outline_width {
+            if !(0.0..=1.0).contains(&outline_width) {
+                return invalid("the outline width must be a fraction of the image height");
+            }
+            if outline_width * height as f32 > limits.max_outline_width {
+                return invalid(&format!(
+                    "the outline is wider than {} pixels",
+                    limits.max_outline_width
+                ));
+            }
+        }
+        let fractions = match &layer.placement {
+            Placement::Anchor { .. } => vec![],
+            Placement::Point { x, y } => vec![*x, *y],
+            Placement::Box {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => vec![*x, *y, *width, *height],
+        };
+        if fractions.iter().any(|value| !value.is_finite()) {
+            return invalid("the placement must be finite fractions of the image size");
+        }
+        if let Some(Visibility::Time { start, end }) = &layer.visible {
+            if !start.is_finite() || end.map(|end| !end.is_finite()).unwrap_or(false) {
+                return invalid("the visibility must be finite times in seconds");
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+    Reads the dimensions of an encoded image without decoding its pixels
+*/
+fn encoded_dimensions(data: &[u8]) -> Result<(u32, u32), ApiError> {
+    let invalid = |err: image::ImageError| ApiError::bad_request(format!("invalid image: {}", err));
+    let format = image::guess_format(data).map_err(invalid)?;
+    let (width, height) = match format {
+        ImageFormat::PNG => image::png::PNGDecoder::new(Cursor::new(data))
+            .map_err(invalid)?
+            .dimensions(),
+        ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(Cursor::new(data))
+            .map_err(invalid)?
+            .dimensions(),
+        ImageFormat::GIF => image::gif::Decoder::new(Cursor::new(data))
+            .map_err(invalid)?
+            .dimensions(),
+        ImageFormat::BMP => image::bmp::BMPDecoder::new(Cursor::new(data))
+            .map_err(invalid)?
+            .dimensions(),
+        _ => {
+            return Err(ApiError::bad_request(
+                "unsupported image format, expected PNG, JPEG, GIF or BMP",
+            ))
+        }
+    };
+    // larger values than u32 are clamped, they are far beyond any limit
+    Ok((
+        width.min(u64::from(u32::MAX)) as u32,
+        height.min(u64::from(u32::MAX)) as u32,
+    ))
+}
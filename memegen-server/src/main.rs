@@ -0,0 +1,119 @@
+extern crate memegen_lib;
+
+use std::path::PathBuf;
+use std::process;
+
+use memegen_lib::{FontRegistry, RenderCache, TemplateLibrary};
+use structopt::StructOpt;
+
+mod api;
+mod server;
+
+use crate::api::Limits;
+use crate::server::{MemeServer, ServerState};
+
+/**
+    Renders memes over HTTP
+*/
+#[derive(StructOpt)]
+#[structopt(name = "memegen-server")]
+struct Options {
+    /// The address to listen on
+    #[structopt(short, long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// The directory of the templates that can be rendered
+    #[structopt(long, parse(from_os_str))]
+    templates: Option<PathBuf>,
+
+    /// A directory of additional fonts
+    #[structopt(long, parse(from_os_str))]
+    fonts: Option<PathBuf>,
+
+    /// Makes the fonts installed on the system available
+    #[structopt(long)]
+    system_fonts: bool,
+
+    /// The largest width and height of a background in pixels
+    #[structopt(long, default_value = "4096")]
+    max_dimension: u32,
+
+    /// The largest request body in bytes
+    #[structopt(long, default_value = "16777216")]
+    max_body_size: usize,
+
+    /// The size of the in-memory cache of rendered memes in megabytes, 0 disables the cache
+    #[structopt(long, default_value = "64")]
+    cache_size: usize,
+
+    /// Also stores rendered memes in the directory, so they are kept across restarts
+    #[structopt(long, parse(from_os_str))]
+    cache_dir: Option<PathBuf>,
+
+    /// The largest size of the cache directory in megabytes
+    #[structopt(long, default_value = "1024")]
+    cache_dir_size: u64,
+
+    /// The number of requests that are handled at the same time
+    #[structopt(long, default_value = "4")]
+    threads: usize,
+}
+
+fn main() {
+    let options = Options::from_args();
+
+    let mut fonts = FontRegistry::with_baked_fonts();
+    if let Some(dir) = &options.fonts {
+        if fonts.load_dir(dir) == 0 {
+            eprintln!("error: there are no fonts in {}", dir.display());
+            process::exit(1);
+        }
+    }
+    if options.system_fonts {
+        fonts.load_system_fonts();
+    }
+    let mut templates = TemplateLibrary::new();
+    if let Some(dir) = &options.templates {
+        if templates.load_dir(dir) == 0 {
+            eprintln!("error: there are no templates in {}", dir.display());
+            process::exit(1);
+        }
+    }
+
+    let mut cache = None;
+    if options.cache_size > 0 || options.cache_dir.is_some() {
+        let memory = RenderCache::new(&fonts, options.cache_size * 1024 * 1024);
+        cache = Some(match &options.cache_dir {
+            Some(dir) => match memory.with_disk(dir, options.cache_dir_size * 1024 * 1024) {
+                Ok(cache) => cache,
+                Err(err) => {
+                    eprintln!("error: could not use {} as cache: {}", dir.display(), err);
+                    process::exit(1);
+                }
+            },
+            None => memory,
+        });
+    }
+
+    let state = ServerState {
+        fonts,
+        templates,
+        limits: Limits {
+            max_dimension: options.max_dimension,
+            max_body_size: options.max_body_size,
+            ..Limits::default()
+        },
+        cache,
+    };
+    let server = match MemeServer::bind(&options.address, state) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("error: could not listen on {}: {}", options.address, err);
+            process::exit(1);
+        }
+    };
+    if let Some(address) = server.address() {
+        println!("listening on http://{}", address);
+    }
+    server.run(options.threads);
+}
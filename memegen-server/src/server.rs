@@ -0,0 +1,672 @@
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use image::RgbaImage;
+use memegen_lib::{
+    render_with_fonts, ExportOptions, FontRegistry, Meme, MemeLink, MemegenError, RenderCache,
+    RenderKey, TemplateLibrary,
+};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::api::{
+    decode_component, export_from_extension, negotiate_export, parse_query, validate_meme,
+    ApiError, Limits, EXPORT_PARAMETERS,
+};
+
+/**
+    Everything the requests are handled with, shared by all worker threads
+*/
+pub struct ServerState {
+    pub fonts: FontRegistry,
+    pub templates: TemplateLibrary,
+    pub limits: Limits,
+    /// Rendered memes by their input, repeated requests are not rendered again
+    pub cache: Option<RenderCache>,
+}
+
+/**
+    A response body with its content type
+*/
+struct Reply {
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// Whether the image came from the render cache, if there is one
+    cached: Option<bool>,
+}
+
+/**
+    The HTTP interface of the renderer:
+
+    - `POST /memes` renders a meme document like the ones in project files,
+      the background has to be an encoded image or a solid colour
+    - `GET /templates` lists the templates
+    - `GET /templates/<id>?<slot>=<text>` renders a template with the captions of the query
+    - `GET /<id>/<top>/<bottom>.png` renders a template with classic top and bottom text,
+      in the URL scheme of memegen.link, also with an `/images` prefix
+
+    Memes are sent as PNG, or in the format of the `format` query parameter, e.g. `format=webp`,
+    or as JPEG if JPEG is the accepted type. The `quality` parameter sets the JPEG quality
+    and the `compression` parameter the PNG compression, `fast`, `default` or `best`. Errors are sent as JSON objects with the status and a message.
+    With a render cache, the `X-Cache` header of a meme is `hit` if it was not rendered again, otherwise `miss`.
+*/
+pub struct MemeServer {
+    http: Arc<Server>,
+    state: Arc<ServerState>,
+}
+
+impl MemeServer {
+    /**
+        Listens on the address, e.g. `127.0.0.1:8080`. Port 0 picks a free port.
+    */
+    pub fn bind(address: &str, state: ServerState) -> Result<MemeServer, String> {
+        let http = Server::http(address).map_err(|err| err.to_string())?;
+        Ok(MemeServer {
+            http: Arc::new(http),
+            state: Arc::new(state),
+        })
+    }
+
+    /// The address the server listens on
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /**
+        Handles requests on the number of threads, this blocks for as long as the server runs
+    */
+    pub fn run(self, threads: usize) {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                let http = self.http.clone();
+                let state = self.state.clone();
+                thread::spawn(move || {
+                    for request in http.incoming_requests() {
+                        serve(request, &state);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn serve(mut request: Request, state: &ServerState) {
+    let response = match handle(&mut request, state) {
+        Ok(reply) => {
+            let mut response =
+                Response::from_data(reply.body).with_header(content_type(reply.content_type));
+            if let Some(cached) = reply.cached {
+                let value = if cached { "hit" } else { "miss" };
+                response.add_header(
+                    Header::from_bytes("X-Cache", value).expect("hit and miss are header values"),
+                );
+            }
+            response
+        }
+        Err(err) => {
+            if err.status >= 500 {
+                eprintln!("{} {}: {}", request.method(), request.url(), err);
+            }
+            Response::from_string(err.to_json())
+                .with_status_code(err.status)
+                .with_header(content_type("application/json"))
+        }
+    };
+    // the client may be gone already, there is nobody to report the error to
+    let _ = request.respond(response);
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).expect("Content types are valid header values")
+}
+
+fn handle(request: &mut Request, state: &ServerState) -> Result<Reply, ApiError> {
+    let url = request.url().to_string();
+    let (path, query) = match url.find('?') {
+        Some(idx) => (&url[..idx], &url[idx + 1..]),
+        None => (url.as_str(), ""),
+    };
+    let query = parse_query(query)?;
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let accept = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Accept"))
+        .map(|header| header.value.to_string());
+
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["memes"]) => {
+            let options = negotiate_export(&query, accept.as_deref())?;
+            let body = read_body(request, &state.limits)?;
+            let meme: Meme = serde_json::from_slice(&body)
+                .map_err(|err| ApiError::bad_request(format!("invalid meme: {}", err)))?;
+            validate_meme(&meme, &state.limits)?;
+            render_reply(
+                state,
+                &options,
+                |cache, format| cache.meme_key(&meme, format),
+                || render_with_fonts(&meme, &state.fonts),
+            )
+        }
+        (Method::Get, ["templates"]) => list_templates(&state.templates),
+        (Method::Get, ["templates", id]) => {
+            let options = negotiate_export(&query, accept.as_deref())?;
+            let id = decode_component(id)?;
+            let template = state
+                .templates
+                .get(&id)
+                .ok_or_else(|| ApiError::not_found(format!("unknown template {}", id)))?;
+            let mut captions = query;
+            for parameter in EXPORT_PARAMETERS.iter() {
+                captions.remove(*parameter);
+            }
+            for (slot, text) in captions.iter() {
+                if text.chars().count() > state.limits.max_text_length {
+                    return Err(ApiError::bad_request(format!(
+                        "the text of slot {} is longer than {} characters",
+                        slot, state.limits.max_text_length
+                    )));
+                }
+            }
+            let meme = template.fill(&captions)?;
+            render_reply(
+                state,
+                &options,
+                |cache, format| cache.meme_key(&meme, format),
+                || render_with_fonts(&meme, &state.fonts),
+            )
+        }
+        (_, ["memes"]) | (_, ["templates"]) | (_, ["templates", _]) => Err(ApiError {
+            status: 405,
+            message: format!("{} is not allowed on {}", request.method(), path),
+        }),
+        (Method::Get, [_, ..]) => {
+            // links may start with an images segment, like the ones of memegen.link
+            let link_path = match segments[0] {
+                "images" => &path.trim_start_matches('/')["images".len()..],
+                _ => path,
+            };
+            let link = MemeLink::parse(link_path)?;
+            for line in link.lines.iter() {
+                if line.chars().count() > state.limits.max_text_length {
+                    return Err(ApiError::bad_request(format!(
+                        "a line of the link is longer than {} characters",
+                        state.limits.max_text_length
+                    )));
+                }
+            }
+            match &link.extension {
+                Some(extension) => {
+                    let options = export_from_extension(extension)?;
+                    render_reply(
+                        state,
+                        &options,
+                        |cache, format| cache.link_key(&link, &state.templates, format),
+                        || link.render(&state.templates),
+                    )
+                }
+                None => Err(ApiError::not_found(format!("there is nothing at {}", path))),
+            }
+        }
+        _ => Err(ApiError::not_found(format!("there is nothing at {}", path))),
+    }
+}
+
+/**
+    Reads the body of the request, bodies larger than the limit are rejected without reading them
+*/
+fn read_body(request: &mut Request, limits: &Limits) -> Result<Vec<u8>, ApiError> {
+    let too_large = || ApiError {
+        status: 413,
+        message: format!("the body is larger than {} bytes", limits.max_body_size),
+    };
+    if request.body_length().unwrap_or(0) > limits.max_body_size {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(limits.max_body_size as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| ApiError::bad_request(format!("could not read the body: {}", err)))?;
+    if body.len() > limits.max_body_size {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+/**
+    Renders and encodes the image, or takes it from the render cache by the key of its input
+*/
+fn render_reply<K, R>(
+    state: &ServerState,
+    options: &ExportOptions,
+    key: K,
+    render: R,
+) -> Result<Reply, ApiError>
+where
+    K: FnOnce(&RenderCache, &str) -> Result<RenderKey, MemegenError>,
+    R: FnOnce() -> Result<RgbaImage, MemegenError>,
+{
+    let encode = || render().and_then(|image| options.encode(&image, None));
+    let (body, cached) = match &state.cache {
+        Some(cache) => {
+            let key = key(cache, &options.cache_name())?;
+            let (body, cached) = cache.get_or_render(key, encode)?;
+            (body.to_vec(), Some(cached))
+        }
+        None => (encode()?, None),
+    };
+    Ok(Reply {
+        content_type: options.format.content_type(),
+        body,
+        cached,
+    })
+}
+
+fn list_templates(templates: &TemplateLibrary) -> Result<Reply, ApiError> {
+    #[derive(Serialize)]
+    struct TemplateInfo<'a> {
+        id: &'a str,
+        name: &'a str,
+        slots: Vec<&'a str>,
+    }
+    let infos: Vec<TemplateInfo> = templates
+        .templates()
+        .iter()
+        .map(|template| TemplateInfo {
+            id: &template.id,
+            name: &template.name,
+            slots: template
+                .slots
+                .iter()
+                .map(|slot| slot.name.as_str())
+                .collect(),
+        })
+        .collect();
+    let body = serde_json::to_vec(&infos).map_err(|err| ApiError {
+        status: 500,
+        message: err.to_string(),
+    })?;
+    Ok(Reply {
+        content_type: "application/json",
+        body,
+        cached: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+    use serde_json::{json, Value};
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    const TEMPLATE_DIR: &str = "../memegen-lib/res/test/templates";
+
+    struct TestResponse {
+        status: u16,
+        content_type: String,
+        cache: Option<String>,
+        body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        fn json(&self) -> Value {
+            assert_eq!("application/json", self.content_type);
+            serde_json::from_slice(&self.body).unwrap()
+        }
+
+        fn image(&self) -> DynamicImage {
+            image::load_from_memory(&self.body).unwrap()
+        }
+    }
+
+    fn start_server(limits: Limits) -> SocketAddr {
+        let mut templates = TemplateLibrary::new();
+        templates.load_dir(TEMPLATE_DIR);
+        let fonts = FontRegistry::with_baked_fonts();
+        let cache = RenderCache::new(&fonts, memegen_lib::DEFAULT_RENDER_CACHE_SIZE);
+        let state = ServerState {
+            fonts,
+            templates,
+            limits,
+            cache: Some(cache),
+        };
+        let server = MemeServer::bind("127.0.0.1:0", state).unwrap();
+        let address = server.address().unwrap();
+        thread::spawn(move || server.run(2));
+        address
+    }
+
+    /**
+        Sends a request and reads the response until the server closes the connection
+    */
+    fn send(
+        address: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> TestResponse {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let header = |name: &str| {
+            head.lines()
+                .find(|line| line.to_lowercase().starts_with(name))
+                .map(|line| line[name.len()..].trim().to_string())
+        };
+        TestResponse {
+            status,
+            content_type: header("content-type:").unwrap_or_default(),
+            cache: header("x-cache:"),
+            body: response[split + 4..].to_vec(),
+        }
+    }
+
+    fn background_png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut data, ImageOutputFormat::PNG)
+            .unwrap();
+        data
+    }
+
+    fn meme_json(background: Value) -> Vec<u8> {
+        json!({
+            "background": background,
+            "layers": [
+                {"text": "top text"},
+                {"text": "bottom text", "placement": {"type": "anchor", "orientation": "bottom"}}
+            ]
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn encoded(data: &[u8]) -> Value {
+        // the base64 alphabet of the project files
+        let meme = Meme::new(memegen_lib::Background::Encoded {
+            data: data.to_vec(),
+        });
+        serde_json::to_value(&meme).unwrap()["background"].clone()
+    }
+
+    #[test]
+    fn test_post_meme() {
+        let address = start_server(Limits::default());
+        let body = meme_json(encoded(&background_png(320, 200)));
+        let response = send(address, "POST", "/memes", &[], &body);
+        assert_eq!(200, response.status);
+        assert_eq!("image/png", response.content_type);
+        let image = response.image();
+        assert_eq!((320, 200), (image.width(), image.height()));
+        // the captions are drawn onto the black background
+        assert!(image.to_rgba().pixels().any(|pixel| pixel.data[0] == 255));
+
+        let response = send(address, "POST", "/memes?format=jpeg", &[], &body);
+        assert_eq!("image/jpeg", response.content_type);
+        assert_eq!(320, response.image().width());
+        let response = send(
+            address,
+            "POST",
+            "/memes",
+            &[("Accept", "image/jpeg")],
+            &body,
+        );
+        assert_eq!("image/jpeg", response.content_type);
+
+        // large bodies are sent in chunks, the other formats are checked on a small image
+        let small =
+            meme_json(json!({"type": "solid", "width": 64, "height": 48, "colour": [1, 2, 3]}));
+        let response = send(address, "POST", "/memes?format=webp", &[], &small);
+        assert_eq!("image/webp", response.content_type);
+        assert_eq!(b"RIFF", &response.body[0..4]);
+        let low = send(
+            address,
+            "POST",
+            "/memes?format=jpeg&quality=10",
+            &[],
+            &small,
+        );
+        let high = send(
+            address,
+            "POST",
+            "/memes?format=jpeg&quality=95",
+            &[],
+            &small,
+        );
+        assert!(low.body.len() < high.body.len());
+        let response = send(address, "POST", "/memes?format=jpeg&quality=0", &[], &small);
+        assert_eq!(400, response.status);
+        let response = send(address, "POST", "/memes?format=tiff", &[], &small);
+        assert_eq!(400, response.status);
+    }
+
+    #[test]
+    fn test_post_invalid_memes() {
+        let address = start_server(Limits {
+            max_dimension: 500,
+            max_body_size: 64 * 1024,
+            max_font_size: 200.0,
+            ..Limits::default()
+        });
+        let post = |body: &[u8]| send(address, "POST", "/memes", &[], body);
+
+        let response = post(b"{\"layers\": ");
+        assert_eq!(400, response.status);
+        assert!(response.json()["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid meme"));
+
+        // the server does not read files named by the client
+        let file = meme_json(json!({"type": "file", "path": "/etc/passwd"}));
+        assert_eq!(400, post(&file).status);
+
+        let too_large =
+            meme_json(json!({"type": "solid", "width": 501, "height": 10, "colour": [0, 0, 0]}));
+        let response = post(&too_large);
+        assert_eq!(400, response.status);
+        assert_eq!(400, response.json()["status"]);
+        let too_large = meme_json(encoded(&background_png(10, 800)));
+        assert_eq!(400, post(&too_large).status);
+
+        let not_an_image = meme_json(encoded(b"not an image"));
+        assert_eq!(400, post(&not_an_image).status);
+
+        let unknown_font = json!({
+            "background": {"type": "solid", "width": 100, "height": 100, "colour": [0, 0, 0]},
+            "layers": [{"text": "hi", "style": {"font_family": "Does Not Exist"}}]
+        });
+        let response = post(unknown_font.to_string().as_bytes());
+        assert_eq!(400, response.status);
+        assert!(response.json()["error"]
+            .as_str()
+            .unwrap()
+            .contains("Does Not Exist"));
+
+        let endless = json!({
+            "background": {"type": "solid", "width": 100, "height": 100, "colour": [0, 0, 0]},
+            "layers": [{"text": "hi", "visible": {"type": "time", "end": 1e39}}]
+        });
+        let response = post(endless.to_string().as_bytes());
+        assert_eq!(400, response.status);
+        assert!(response.json()["error"]
+            .as_str()
+            .unwrap()
+            .contains("visibility"));
+
+        let huge_font = json!({
+            "background": {"type": "solid", "width": 500, "height": 500, "colour": [0, 0, 0]},
+            "layers": [{"text": "hi", "style": {"size": 1.0}}]
+        });
+        let response = post(huge_font.to_string().as_bytes());
+        assert_eq!(400, response.status);
+        assert!(response.json()["error"].as_str().unwrap().contains("font"));
+
+        let wide_outline = json!({
+            "background": {"type": "solid", "width": 500, "height": 500, "colour": [0, 0, 0]},
+            "layers": [{"text": "hi", "style": {"outline_width": 0.5}}]
+        });
+        let response = post(wide_outline.to_string().as_bytes());
+        assert_eq!(400, response.status);
+        assert!(response.json()["error"]
+            .as_str()
+            .unwrap()
+            .contains("outline"));
+
+        let response = post(&vec![b' '; 65 * 1024]);
+        assert_eq!(413, response.status);
+        assert_eq!(413, response.json()["status"]);
+    }
+
+    #[test]
+    fn test_get_template() {
+        let address = start_server(Limits::default());
+        let response = send(
+            address,
+            "GET",
+            "/templates/two_panels?top=served+over&bottom=HTTP%21",
+            &[],
+            b"",
+        );
+        assert_eq!(200, response.status);
+        assert_eq!("image/png", response.content_type);
+        let image = response.image();
+        assert_eq!((400, 400), (image.width(), image.height()));
+
+        let response = send(address, "GET", "/templates/nope", &[], b"");
+        assert_eq!(404, response.status);
+        assert_eq!("unknown template nope", response.json()["error"]);
+
+        let response = send(address, "GET", "/templates/two_panels?middle=x", &[], b"");
+        assert_eq!(400, response.status);
+
+        let response = send(address, "GET", "/templates/two_panels?top=%zz", &[], b"");
+        assert_eq!(400, response.status);
+
+        let response = send(address, "GET", "/templates", &[], b"");
+        assert_eq!(200, response.status);
+        let templates = response.json();
+        assert_eq!("two_panels", templates[0]["id"]);
+        assert_eq!(json!(["top", "bottom"]), templates[0]["slots"]);
+    }
+
+    #[test]
+    fn test_get_link() {
+        let address = start_server(Limits::default());
+        let path = MemeLink::new("two_panels", &["is this", "a link?"]).to_path();
+        assert_eq!("/two_panels/is_this/a_link~q.png", path);
+        let response = send(address, "GET", &path, &[], b"");
+        assert_eq!(200, response.status);
+        assert_eq!("image/png", response.content_type);
+        assert_eq!(400, response.image().width());
+
+        let response = send(
+            address,
+            "GET",
+            "/images/two_panels/top/bottom.jpg",
+            &[],
+            b"",
+        );
+        assert_eq!(200, response.status);
+        assert_eq!("image/jpeg", response.content_type);
+
+        let response = send(address, "GET", "/nope/top/bottom.png", &[], b"");
+        assert_eq!(404, response.status);
+        assert_eq!("unknown template nope", response.json()["error"]);
+        let response = send(address, "GET", "/two_panels/top/bottom.gif", &[], b"");
+        assert_eq!(200, response.status);
+        assert_eq!("image/gif", response.content_type);
+        let response = send(address, "GET", "/two_panels/%C3.png", &[], b"");
+        assert_eq!(400, response.status);
+
+        // only a whole images segment is skipped
+        let response = send(address, "GET", "/imagestwo_panels/top.png", &[], b"");
+        assert_eq!(404, response.status);
+        assert_eq!("unknown template imagestwo_panels", response.json()["error"]);
+
+        let long_line = format!("/two_panels/{}.png", "a".repeat(1001));
+        let response = send(address, "GET", &long_line, &[], b"");
+        assert_eq!(400, response.status);
+    }
+
+    #[test]
+    fn test_cached_renders() {
+        let address = start_server(Limits::default());
+        let body =
+            meme_json(json!({"type": "solid", "width": 64, "height": 48, "colour": [1, 2, 3]}));
+        let first = send(address, "POST", "/memes", &[], &body);
+        assert_eq!(Some("miss".to_string()), first.cache);
+        let second = send(address, "POST", "/memes", &[], &body);
+        assert_eq!(Some("hit".to_string()), second.cache);
+        assert_eq!(first.body, second.body);
+        // another format is another image
+        let jpeg = send(address, "POST", "/memes?format=jpeg", &[], &body);
+        assert_eq!(Some("miss".to_string()), jpeg.cache);
+        assert_eq!("image/jpeg", jpeg.content_type);
+
+        let link = "/two_panels/cached/link.png";
+        assert_eq!(
+            Some("miss".to_string()),
+            send(address, "GET", link, &[], b"").cache
+        );
+        assert_eq!(
+            Some("hit".to_string()),
+            send(address, "GET", link, &[], b"").cache
+        );
+        let template = "/templates/two_panels?top=cached";
+        assert_eq!(
+            Some("miss".to_string()),
+            send(address, "GET", template, &[], b"").cache
+        );
+        assert_eq!(
+            Some("hit".to_string()),
+            send(address, "GET", template, &[], b"").cache
+        );
+
+        // only images are cached
+        let response = send(address, "GET", "/templates", &[], b"");
+        assert_eq!(None, response.cache);
+        let response = send(address, "GET", "/nope/top.png", &[], b"");
+        assert_eq!((404, None), (response.status, response.cache));
+    }
+
+    #[test]
+    fn test_unknown_routes() {
+        let address = start_server(Limits::default());
+        let response = send(address, "GET", "/memes", &[], b"");
+        assert_eq!(405, response.status);
+        assert_eq!(405, response.json()["status"]);
+        let response = send(address, "GET", "/favicon.ico", &[], b"");
+        assert_eq!(404, response.status);
+        assert_eq!(404, response.json()["status"]);
+    }
+}